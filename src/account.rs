@@ -1,9 +1,13 @@
 use crate::misc::Hash;
-use crate::storage::{MAX_ACCOUNT_ID_LENGTH, REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE};
+use crate::storage::{
+    MAX_ACCOUNT_ID_LENGTH, REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE,
+    REGISTERED_MAX_ID_LENGTH_ACCOUNT_MAX_STORAGE, U128_STORAGE, U32_STORAGE,
+};
 use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::{env, AccountId, Balance, StorageUsage};
+use std::collections::HashMap;
 
 /// Current account data struct
 ///
@@ -14,6 +18,37 @@ pub(crate) struct Account {
     pub(crate) storage_balance: u128,
     /// Total used by account storage amount in bytes
     pub(crate) storage_usage: StorageUsage,
+    /// Pool LP shares owned by account
+    pub(crate) lp_shares: u128,
+    /// Fungible token balances held by the pool for account keyed by token account id
+    pub(crate) balances: HashMap<AccountId, Balance>,
+    /// Value of the liquidity provided by account in Token2 units at the pool price it was added at,
+    /// reduced pro-rata as LP shares are burned
+    pub(crate) lp_entry_value: u128,
+}
+
+/// Legacy account data struct without LP shares
+#[derive(BorshSerialize, BorshDeserialize)]
+pub(crate) struct AccountV0 {
+    pub(crate) storage_balance: u128,
+    pub(crate) storage_usage: StorageUsage,
+}
+
+/// Legacy account data struct without token balances
+#[derive(BorshSerialize, BorshDeserialize)]
+pub(crate) struct AccountV1 {
+    pub(crate) storage_balance: u128,
+    pub(crate) storage_usage: StorageUsage,
+    pub(crate) lp_shares: u128,
+}
+
+/// Legacy account data struct without LP entry value
+#[derive(BorshSerialize, BorshDeserialize)]
+pub(crate) struct AccountV2 {
+    pub(crate) storage_balance: u128,
+    pub(crate) storage_usage: StorageUsage,
+    pub(crate) lp_shares: u128,
+    pub(crate) balances: HashMap<AccountId, Balance>,
 }
 
 /// Versioned account data struct
@@ -22,6 +57,9 @@ pub(crate) struct Account {
 /// which would be upgraded to current version upon next write access
 #[derive(BorshDeserialize, BorshSerialize)]
 pub(crate) enum VAccount {
+    V0(AccountV0),
+    V1(AccountV1),
+    V2(AccountV2),
     Current(Account),
 }
 
@@ -31,14 +69,17 @@ impl Account {
         Self {
             storage_balance: storage_balance.unwrap_or_default(),
             storage_usage: Self::initial_storage_usage(Some(account_id)),
+            lp_shares: 0,
+            balances: HashMap::new(),
+            lp_entry_value: 0,
         }
     }
 
     /// Returns storage usage by optionally provided `account_id`, otherwise use maximum account id length
     pub(crate) fn initial_storage_usage(account_id: Option<&AccountId>) -> u64 {
-        // compute storage usage diff for specific `account id` length, which is used when saving account data in lookup map
+        // compute storage usage diff for specific `account id` length, which is stored once as lookup map key
         let storage_diff = account_id
-            .map(|account_id| 2 * (MAX_ACCOUNT_ID_LENGTH - account_id.as_bytes().len() as u64))
+            .map(|account_id| MAX_ACCOUNT_ID_LENGTH - account_id.as_bytes().len() as u64)
             .unwrap_or_default();
 
         REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE - storage_diff
@@ -49,14 +90,74 @@ impl Account {
         (Self::initial_storage_usage(account_id) as Balance * env::storage_byte_cost()).into()
     }
 
-    /// Returns storage balance
-    pub(crate) fn storage_balance(&self) -> StorageBalance {
+    /// Returns deposit covering storage of account with maximum id length holding balances of both pool tokens
+    pub(crate) fn max_deposit() -> U128 {
+        (REGISTERED_MAX_ID_LENGTH_ACCOUNT_MAX_STORAGE as Balance * env::storage_byte_cost()).into()
+    }
+
+    /// Returns storage balance of the account registered by `account_id`
+    ///
+    /// Registration minimum for `account_id` is never available, so withdrawing all available balance
+    /// keeps the account registered
+    pub(crate) fn storage_balance(&self, account_id: &AccountId) -> StorageBalance {
+        let locked_balance = (self.storage_usage as Balance * env::storage_byte_cost())
+            .max(Self::required_deposit(Some(account_id)).into());
+
         StorageBalance {
             total: self.storage_balance.into(),
-            available: self
-                .storage_balance
-                .saturating_sub(self.storage_usage as Balance * env::storage_byte_cost())
-                .into(),
+            available: self.storage_balance.saturating_sub(locked_balance).into(),
+        }
+    }
+
+    /// Returns balance of `token_id` held for account
+    pub(crate) fn balance(&self, token_id: &AccountId) -> Balance {
+        self.balances.get(token_id).copied().unwrap_or_default()
+    }
+
+    /// Increases balance of `token_id`
+    pub(crate) fn deposit(
+        &mut self,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Result<(), &'static str> {
+        let balance = self
+            .balance(token_id)
+            .checked_add(amount)
+            .ok_or("Balance overflow")?;
+        self.balances.insert(token_id.clone(), balance);
+
+        Ok(())
+    }
+
+    /// Decreases balance of `token_id`, emptied balance entry is removed releasing its storage
+    pub(crate) fn withdraw(
+        &mut self,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Result<(), &'static str> {
+        let balance = self
+            .balance(token_id)
+            .checked_sub(amount)
+            .ok_or("Insufficient balance")?;
+
+        if balance == 0 {
+            self.balances.remove(token_id);
+        } else {
+            self.balances.insert(token_id.clone(), balance);
+        }
+
+        Ok(())
+    }
+}
+
+impl VAccount {
+    /// Returns storage usage recorded by the stored account version
+    pub(crate) fn storage_usage(&self) -> StorageUsage {
+        match self {
+            VAccount::V0(account) => account.storage_usage,
+            VAccount::V1(account) => account.storage_usage,
+            VAccount::V2(account) => account.storage_usage,
+            VAccount::Current(account) => account.storage_usage,
         }
     }
 }
@@ -70,6 +171,30 @@ impl From<Account> for VAccount {
 impl From<VAccount> for Account {
     fn from(v_acc: VAccount) -> Self {
         match v_acc {
+            // Upgraded account additionally stores LP shares, empty token balances & LP entry value
+            VAccount::V0(account) => Account {
+                storage_balance: account.storage_balance,
+                storage_usage: account.storage_usage + 2 * U128_STORAGE + U32_STORAGE,
+                lp_shares: 0,
+                balances: HashMap::new(),
+                lp_entry_value: 0,
+            },
+            // Upgraded account additionally stores empty token balances & LP entry value
+            VAccount::V1(account) => Account {
+                storage_balance: account.storage_balance,
+                storage_usage: account.storage_usage + U32_STORAGE + U128_STORAGE,
+                lp_shares: account.lp_shares,
+                balances: HashMap::new(),
+                lp_entry_value: 0,
+            },
+            // Upgraded account additionally stores LP entry value, unknown for the LP shares it already owns
+            VAccount::V2(account) => Account {
+                storage_balance: account.storage_balance,
+                storage_usage: account.storage_usage + U128_STORAGE,
+                lp_shares: account.lp_shares,
+                balances: account.balances,
+                lp_entry_value: 0,
+            },
             VAccount::Current(account) => account,
         }
     }
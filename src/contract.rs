@@ -1,10 +1,23 @@
 use crate::account::{Account, VAccount};
-use crate::misc::RunningState;
+use crate::escrow::{CommittedSwap, FailedSwap};
+use crate::history::SwapRecord;
+use crate::misc::{now_ns, FeeMode, Hash, RunningState, DEFAULT_FEE_BPS, MAX_BPS};
 use crate::storage::StorageKey;
 use crate::tokens::*;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::{env, near_bindgen, require, AccountId, PanicOnDefault, Promise, ONE_NEAR};
+use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, require, AccountId, Balance, PanicOnDefault, Promise,
+    StorageUsage, ONE_NEAR,
+};
+
+/// Version of the contract state & API schema, increased on every breaking change
+pub const CONTRACT_SCHEMA_VERSION: u32 = 2;
+
+/// Maximum length of the pause reason in bytes
+pub(crate) const MAX_PAUSE_REASON_LENGTH: usize = 256;
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
@@ -19,33 +32,169 @@ pub struct Contract {
     pub(crate) token1_wallet: Option<TokenWallet>,
     /// Token2 wallet entry containing information about deposit & liquidity in the pool
     pub(crate) token2_wallet: Option<TokenWallet>,
+    /// Number of successful swaps keyed by AccountId, kept for registered accounts only, which pay for its storage
+    pub(crate) swap_count: LookupMap<AccountId, u64>,
+    /// Pool creation & last activity timestamps
+    pub(crate) activity: Activity,
+    /// Pause the contract if a swap output transfer fails, e.g. due to accounting drift
+    pub(crate) auto_pause_on_transfer_failure: bool,
+    /// Maximum slippage of swaps from the marginal price in basis points, 0 disables the protection
+    pub(crate) default_max_slippage_bps: u32,
+    /// Accounts not allowed to swap
+    pub(crate) blacklist: UnorderedSet<AccountId>,
+    /// Swaps with escrowed input tokens keyed by AccountId, storage is paid by the registered account
+    pub(crate) committed_swaps: LookupMap<AccountId, CommittedSwap>,
+    /// Token1 & Token2 liquidity floors, swaps are refunded until both reserves exceed them
+    pub(crate) min_liquidity_for_swaps: [Option<U128>; 2],
+    /// Swap fee in basis points, retained in the pool liquidity
+    pub(crate) fee_bps: u16,
+    /// Swap token the fee is deducted from, fixed at init
+    pub(crate) fee_mode: FeeMode,
+    /// Last successful swap keyed by AccountId, lets contracts read the swap output after `ft_transfer_call`,
+    /// kept for registered accounts only, which pay for its storage
+    pub(crate) last_swaps: LookupMap<AccountId, LastSwap>,
+    /// Maximum deviation of `add_liquidity` amounts ratio from the reserves ratio in basis points,
+    /// [MAX_BPS] allows any amounts
+    pub(crate) max_add_deviation_bps: u32,
+    /// Account proposed by the owner to take over the ownership
+    pub(crate) pending_owner: Option<AccountId>,
+    /// Balances of the contract at both token contracts were verified to cover tracked liquidity & deposit,
+    /// swaps are allowed only once the backing is verified
+    pub(crate) backing_verified: bool,
+    /// Total LP shares owned by liquidity providers
+    pub(crate) total_lp_shares: u128,
+    /// LP shares of the liquidity provided from the owner's deposit, they pass to a new owner along with it
+    pub(crate) owner_lp_shares: u128,
+    /// Value of the liquidity provided from the owner's deposit in Token2 units at the pool price it was added at,
+    /// passes to a new owner along with the LP shares
+    pub(crate) owner_lp_entry_value: u128,
+    /// Operator's explanation of the pause, cleared on resume
+    pub(crate) pause_reason: Option<String>,
+    /// Little-endian limbs of U256 sum of Token1 spot prices weighted by nanoseconds they were held for
+    pub(crate) price_cumulative_last: [u64; 4],
+    /// Timestamp of the last price accumulation in nanoseconds
+    pub(crate) block_timestamp_last: u64,
+    /// Register users at the output token using the pool's $NEAR, if the swap output transfer fails
+    pub(crate) sponsor_user_registration: bool,
+    /// $NEAR attached to register users at pool tokens keyed by AccountId
+    pub(crate) sponsored_registrations: LookupMap<AccountId, Balance>,
+    /// Latest swaps keyed by swap index modulo [crate::history::SWAP_HISTORY_SIZE]
+    pub(crate) swap_history: LookupMap<u64, SwapRecord>,
+    /// Number of swaps recorded since the pool creation
+    pub(crate) swap_history_len: u64,
+    /// Share of the swap fee taken by the protocol in basis points, the rest is retained in the pool liquidity
+    pub(crate) protocol_fee_bps: u16,
+    /// Token1 & Token2 protocol fees accrued on swaps and not withdrawn yet
+    pub(crate) protocol_fees: [u128; 2],
+    /// Fold accrued protocol fees into the liquidity on swaps, minting LP shares to the owner
+    pub(crate) auto_compound: bool,
+    /// Token1 & Token2 protocol fees both must reach to be folded into the liquidity
+    pub(crate) auto_compound_threshold: [u128; 2],
+    /// Maximum swap input as a share of the input token liquidity in basis points, `None` disables the cap
+    pub(crate) max_swap_bps: Option<u16>,
+    /// Token contracts the pool may be created against, `None` allows any token
+    pub(crate) allowed_tokens: Option<UnorderedSet<AccountId>>,
+    /// Committed swaps, whose escrowed input couldn't be refunded, keyed by swap id
+    pub(crate) failed_swaps: LookupMap<u64, FailedSwap>,
+    /// Id of the next recorded failed swap
+    pub(crate) next_failed_swap_id: u64,
 }
 
 #[near_bindgen]
 impl Contract {
     /// Initializes contract
+    ///
+    /// `allowed_tokens` restricts token contracts the pool may be created against, any token is allowed if `None`,
+    /// `fee_mode` defaults to [FeeMode::OnInput]
     #[init]
-    pub fn init(owner_id: Option<AccountId>, tokens: Option<(AccountId, AccountId)>) -> Self {
+    pub fn init(
+        owner_id: Option<AccountId>,
+        tokens: Option<(AccountId, AccountId)>,
+        min_liquidity_for_swaps: Option<[Option<U128>; 2]>,
+        fee_bps: Option<u16>,
+        allowed_tokens: Option<Vec<AccountId>>,
+        fee_mode: Option<FeeMode>,
+    ) -> Self {
+        let fee_bps = fee_bps.unwrap_or(DEFAULT_FEE_BPS);
+        require!(u32::from(fee_bps) <= MAX_BPS, "Fee exceeds 100%");
+
         let mut contract = Self {
             owner_id: owner_id.unwrap_or_else(env::predecessor_account_id),
             running_state: RunningState::Running,
             accounts: LookupMap::new(StorageKey::Accounts),
             token1_wallet: None,
             token2_wallet: None,
+            swap_count: LookupMap::new(StorageKey::SwapCount),
+            activity: Activity {
+                created_ts: now_ns().into(),
+                last_swap_ts: None,
+                last_liquidity_ts: None,
+            },
+            auto_pause_on_transfer_failure: false,
+            default_max_slippage_bps: 0,
+            blacklist: UnorderedSet::new(StorageKey::Blacklist),
+            committed_swaps: LookupMap::new(StorageKey::CommittedSwaps),
+            min_liquidity_for_swaps: min_liquidity_for_swaps.unwrap_or_default(),
+            fee_bps,
+            fee_mode: fee_mode.unwrap_or(FeeMode::OnInput),
+            last_swaps: LookupMap::new(StorageKey::LastSwaps),
+            max_add_deviation_bps: DEFAULT_MAX_ADD_DEVIATION_BPS,
+            pending_owner: None,
+            backing_verified: false,
+            total_lp_shares: 0,
+            owner_lp_shares: 0,
+            owner_lp_entry_value: 0,
+            pause_reason: None,
+            price_cumulative_last: [0; 4],
+            block_timestamp_last: now_ns(),
+            sponsor_user_registration: false,
+            sponsored_registrations: LookupMap::new(StorageKey::SponsoredRegistrations),
+            swap_history: LookupMap::new(StorageKey::SwapHistory),
+            swap_history_len: 0,
+            protocol_fee_bps: 0,
+            protocol_fees: [0; 2],
+            auto_compound: false,
+            auto_compound_threshold: [0; 2],
+            max_swap_bps: None,
+            allowed_tokens: allowed_tokens.map(|allowed_tokens| {
+                let mut set = UnorderedSet::new(StorageKey::AllowedTokens);
+                set.extend(allowed_tokens);
+                set
+            }),
+            failed_swaps: LookupMap::new(StorageKey::FailedSwaps),
+            next_failed_swap_id: 0,
         };
 
         if let Some((token1, token2)) = tokens {
-            contract.create_wallets(token1, token2);
+            contract.create_wallets(token1, token2, 0);
         }
 
         contract
     }
 
     /// Requests a wallet registration in fungible token contract
-    pub(crate) fn create_wallets(&mut self, token1: AccountId, token2: AccountId) -> Promise {
+    ///
+    /// `deposit` is the amount of $NEAR paid by the owner, which is partially refunded if creation fails
+    pub(crate) fn create_wallets(
+        &mut self,
+        token1: AccountId,
+        token2: AccountId,
+        deposit: Balance,
+    ) -> Promise {
+        require!(
+            self.is_token_allowed(&token1) && self.is_token_allowed(&token2),
+            "Token is not allowed"
+        );
+
         self.create_token_wallet(token1.clone())
             .and(self.create_token_wallet(token2.clone()))
-            .then(Self::ext(env::current_account_id()).on_created_tokens_wallets(token1, token2))
+            .then(
+                Self::ext(env::current_account_id()).on_created_tokens_wallets(
+                    token1,
+                    token2,
+                    deposit.into(),
+                ),
+            )
     }
 
     /// Owner's function to register wallets
@@ -59,17 +208,548 @@ impl Contract {
 
         self.assert_owner();
 
-        self.create_wallets(token1, token2)
+        self.create_wallets(token1, token2, env::attached_deposit())
+    }
+
+    /// Owner's function to update several configurable parameters at once
+    ///
+    /// All provided values are validated before any of them is applied
+    #[payable]
+    #[handle_result]
+    pub fn update_config(&mut self, patch: ContractConfigPatch) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        // Validate all provided values before applying any of them
+        if (patch.max_deposit_per_account.is_some()
+            || patch.transfer_memo.is_some()
+            || patch.reserve_buffer.is_some()
+            || patch.rounding_buffer.is_some()
+            || patch.min_meaningful_output.is_some()
+            || patch.verify_deposits.is_some())
+            && (self.token1_wallet.is_none() || self.token2_wallet.is_none())
+        {
+            return Err("Token wallets are not created");
+        }
+
+        for memo in patch.transfer_memo.iter().flatten().flatten() {
+            validate_transfer_memo(memo)?;
+        }
+
+        if patch
+            .fee_bps
+            .is_some_and(|fee_bps| u32::from(fee_bps) > MAX_BPS)
+        {
+            return Err("Fee exceeds 100%");
+        }
+
+        if patch
+            .protocol_fee_bps
+            .is_some_and(|protocol_fee_bps| u32::from(protocol_fee_bps) > MAX_BPS)
+        {
+            return Err("Protocol fee exceeds 100%");
+        }
+
+        if patch
+            .max_swap_bps
+            .flatten()
+            .is_some_and(|max_swap_bps| u32::from(max_swap_bps) > MAX_BPS)
+        {
+            return Err("Max swap exceeds 100%");
+        }
+
+        if patch
+            .default_max_slippage_bps
+            .is_some_and(|slippage_bps| slippage_bps > MAX_BPS)
+        {
+            return Err("Slippage exceeds 100%");
+        }
+
+        if patch
+            .max_add_deviation_bps
+            .is_some_and(|deviation_bps| deviation_bps > MAX_BPS)
+        {
+            return Err("Deviation exceeds 100%");
+        }
+
+        if let (Some(token1_wallet), Some(token2_wallet)) =
+            (self.token1_wallet.as_mut(), self.token2_wallet.as_mut())
+        {
+            if let Some([token1_max, token2_max]) = patch.max_deposit_per_account {
+                token1_wallet.max_deposit_per_account = token1_max;
+                token2_wallet.max_deposit_per_account = token2_max;
+            }
+
+            if let Some([token1_memo, token2_memo]) = patch.transfer_memo {
+                token1_wallet.transfer_memo = token1_memo;
+                token2_wallet.transfer_memo = token2_memo;
+            }
+
+            if let Some([token1_buffer, token2_buffer]) = patch.reserve_buffer {
+                token1_wallet.reserve_buffer = token1_buffer;
+                token2_wallet.reserve_buffer = token2_buffer;
+            }
+
+            if let Some([token1_buffer, token2_buffer]) = patch.rounding_buffer {
+                token1_wallet.rounding_buffer = token1_buffer;
+                token2_wallet.rounding_buffer = token2_buffer;
+            }
+
+            if let Some([token1_min, token2_min]) = patch.min_meaningful_output {
+                token1_wallet.min_meaningful_output = token1_min;
+                token2_wallet.min_meaningful_output = token2_min;
+            }
+
+            if let Some([token1_verify, token2_verify]) = patch.verify_deposits {
+                token1_wallet.verify_deposits = token1_verify;
+                token2_wallet.verify_deposits = token2_verify;
+            }
+        }
+
+        if let Some(fee_bps) = patch.fee_bps {
+            self.fee_bps = fee_bps;
+        }
+
+        if let Some(protocol_fee_bps) = patch.protocol_fee_bps {
+            self.protocol_fee_bps = protocol_fee_bps;
+        }
+
+        if let Some(max_swap_bps) = patch.max_swap_bps {
+            self.max_swap_bps = max_swap_bps;
+        }
+
+        if let Some(auto_pause_on_transfer_failure) = patch.auto_pause_on_transfer_failure {
+            self.auto_pause_on_transfer_failure = auto_pause_on_transfer_failure;
+        }
+
+        if let Some(sponsor_user_registration) = patch.sponsor_user_registration {
+            self.sponsor_user_registration = sponsor_user_registration;
+        }
+
+        if let Some(default_max_slippage_bps) = patch.default_max_slippage_bps {
+            self.default_max_slippage_bps = default_max_slippage_bps;
+        }
+
+        if let Some(min_liquidity_for_swaps) = patch.min_liquidity_for_swaps {
+            self.min_liquidity_for_swaps = min_liquidity_for_swaps;
+        }
+
+        if let Some(max_add_deviation_bps) = patch.max_add_deviation_bps {
+            self.max_add_deviation_bps = max_add_deviation_bps;
+        }
+
+        if let Some(auto_compound) = patch.auto_compound {
+            self.auto_compound = auto_compound;
+        }
+
+        if let Some(auto_compound_threshold) = patch.auto_compound_threshold {
+            self.auto_compound_threshold = auto_compound_threshold.map(u128::from);
+        }
+
+        Ok(())
+    }
+
+    /// Owner's function to propose `new_owner`, who takes over the ownership by [Contract::accept_ownership]
+    ///
+    /// A new proposal replaces the pending one
+    #[payable]
+    pub fn propose_new_owner(&mut self, new_owner: AccountId) {
+        self.assert_owner_action();
+
+        env::log_str(&format!("Ownership is proposed to {:?}", new_owner));
+
+        self.pending_owner = Some(new_owner);
+    }
+
+    /// Pending owner's function to finalize the ownership transfer
+    #[payable]
+    #[handle_result]
+    pub fn accept_ownership(&mut self) -> Result<(), &'static str> {
+        assert_one_yocto();
+
+        let account_id = env::predecessor_account_id();
+        if self.pending_owner.as_ref() != Some(&account_id) {
+            return Err("Caller is not the pending owner");
+        }
+
+        env::log_str(&format!(
+            "Ownership is transferred from {:?} to {:?}",
+            self.owner_id, account_id
+        ));
+
+        self.owner_id = account_id;
+        self.pending_owner = None;
+
+        Ok(())
+    }
+
+    /// Returns the contract's owner account id
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Returns account proposed to take over the ownership
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    /// Owner's function to pause swaps, liquidity changes & storage management
+    #[payable]
+    pub fn pause(&mut self) {
+        self.set_running_state(RunningState::Paused, None);
+    }
+
+    /// Owner's function to resume the paused contract
+    #[payable]
+    pub fn resume(&mut self) {
+        self.set_running_state(RunningState::Running, None);
+    }
+
+    /// Owner's function to pause or resume the contract, `reason` of the pause is shown to users
+    /// and is cleared on resume
+    #[payable]
+    pub fn set_running_state(&mut self, running_state: RunningState, reason: Option<String>) {
+        self.assert_owner_action();
+
+        match running_state {
+            RunningState::Paused => {
+                if let Some(reason) = reason.as_ref() {
+                    require!(
+                        reason.len() <= MAX_PAUSE_REASON_LENGTH,
+                        "Pause reason is too long"
+                    );
+                    env::log_str(&format!("Contract paused by owner: {}", reason));
+                } else {
+                    env::log_str("Contract paused by owner");
+                }
+
+                self.pause_reason = reason;
+            }
+            RunningState::Running => {
+                self.pause_reason = None;
+                env::log_str("Contract resumed by owner");
+            }
+        }
+
+        self.running_state = running_state;
+    }
+
+    /// Returns contract's running state along with the pause reason
+    pub fn get_status(&self) -> ContractStatus {
+        ContractStatus {
+            running_state: self.running_state.clone(),
+            pause_reason: self.pause_reason.clone(),
+        }
+    }
+
+    /// Owner's function to forbid swaps by provided `account_id`
+    #[payable]
+    pub fn add_to_blacklist(&mut self, account_id: AccountId) {
+        self.assert_owner_action();
+
+        self.blacklist.insert(&account_id);
+    }
+
+    /// Owner's function to allow swaps by provided `account_id` again
+    #[payable]
+    pub fn remove_from_blacklist(&mut self, account_id: AccountId) {
+        self.assert_owner_action();
+
+        self.blacklist.remove(&account_id);
+    }
+
+    /// Returns `true` if provided `account_id` is not allowed to swap
+    pub fn is_blacklisted(&self, account_id: AccountId) -> bool {
+        self.blacklist.contains(&account_id)
+    }
+
+    /// Returns the contract crate version with the state & API schema version as its build metadata,
+    /// e.g. `0.1.0+schema.2`
+    pub fn get_version(&self) -> String {
+        format!(
+            "{}+schema.{}",
+            env!("CARGO_PKG_VERSION"),
+            CONTRACT_SCHEMA_VERSION
+        )
+    }
+
+    /// Returns LP shares owned by `account_id`, zero for unregistered accounts,
+    /// the owner additionally owns LP shares of the liquidity provided from the owner's deposit
+    pub fn get_lp_balance(&self, account_id: AccountId) -> U128 {
+        let owner_lp_shares = if self.is_owner(&account_id) {
+            self.owner_lp_shares
+        } else {
+            0
+        };
+
+        self.get_account(&account_id)
+            .map(|account| account.lp_shares)
+            .unwrap_or_default()
+            .saturating_add(owner_lp_shares)
+            .into()
+    }
+
+    /// Returns balance of `token_id` held by the pool for `account_id`, zero for unregistered accounts
+    pub fn get_account_balance(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.get_account(&account_id)
+            .map(|account| account.balance(&token_id))
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Returns total LP shares owned by liquidity providers
+    pub fn get_lp_total_supply(&self) -> U128 {
+        self.total_lp_shares.into()
+    }
+
+    /// Returns pool creation & last activity timestamps in nanoseconds
+    pub fn get_activity(&self) -> Activity {
+        self.activity.clone()
+    }
+
+    /// Returns contract configuration, token fields are `null` until token wallets are created
+    pub fn get_config(&self) -> ContractConfig {
+        self.config()
+    }
+
+    /// Returns hex encoded hash of the contract configuration, changes whenever any of them is updated,
+    /// so clients can poll it and refetch the full configuration only on change
+    pub fn get_config_hash(&self) -> String {
+        self.config()
+            .hash()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Returns owner's actions available at the current contract state
+    pub fn get_owner_capabilities(&self) -> OwnerCapabilities {
+        let token_wallets = self.token1_wallet.iter().chain(self.token2_wallet.iter());
+        let wallets_created = self.token1_wallet.is_some() && self.token2_wallet.is_some();
+        let is_running = self.running_state == RunningState::Running;
+
+        OwnerCapabilities {
+            create_wallets: !wallets_created,
+            configure_tokens: wallets_created,
+            add_liquidity: is_running
+                && wallets_created
+                && token_wallets.clone().any(|wallet| wallet.deposit.0 > 0),
+            remove_liquidity: is_running
+                && wallets_created
+                && token_wallets.clone().any(|wallet| wallet.liquidity.0 > 0),
+            pause: is_running,
+            resume: !is_running,
+        }
+    }
+}
+
+/// Pool liveness information, timestamps are in nanoseconds
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Activity {
+    /// Contract initialization timestamp
+    pub created_ts: U64,
+    /// Last successful swap timestamp
+    pub last_swap_ts: Option<U64>,
+    /// Last liquidity change timestamp
+    pub last_liquidity_ts: Option<U64>,
+}
+
+/// Contract's running state
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractStatus {
+    /// Contract's state, e.g. running, paused
+    pub running_state: RunningState,
+    /// Operator's explanation of the pause, `null` while running or if none was given
+    pub pause_reason: Option<String>,
+}
+
+/// Owner's actions available at the current contract state
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OwnerCapabilities {
+    /// Token wallets are not created yet
+    pub create_wallets: bool,
+    /// Token wallets are created, so per-token parameters can be configured
+    pub configure_tokens: bool,
+    /// Some deposit can be moved to liquidity
+    pub add_liquidity: bool,
+    /// Some liquidity can be moved back to deposit
+    pub remove_liquidity: bool,
+    /// Contract is running, so it can be paused
+    pub pause: bool,
+    /// Contract is paused, so it can be resumed
+    pub resume: bool,
+}
+
+/// Contract configuration & owner-configurable parameters
+#[derive(BorshSerialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    /// The contract's owner account id
+    pub owner_id: AccountId,
+    /// Contract's state, e.g. running, paused
+    pub running_state: RunningState,
+    /// Token1 account id, `null` until token wallets are created
+    pub token1_id: Option<AccountId>,
+    /// Token2 account id, `null` until token wallets are created
+    pub token2_id: Option<AccountId>,
+    /// Swap fee in basis points
+    pub fee_bps: u16,
+    /// Swap token the fee is deducted from
+    pub fee_mode: FeeMode,
+    /// Share of the swap fee taken by the protocol in basis points
+    pub protocol_fee_bps: u16,
+    /// Maximum swap input as a share of the input token liquidity in basis points
+    pub max_swap_bps: Option<u16>,
+    /// Pause the contract if a swap output transfer fails
+    pub auto_pause_on_transfer_failure: bool,
+    /// Register users at the output token using the pool's $NEAR, if the swap output transfer fails
+    pub sponsor_user_registration: bool,
+    /// Maximum slippage of swaps from the marginal price in basis points, 0 disables the protection
+    pub default_max_slippage_bps: u32,
+    /// Token1 & Token2 liquidity floors for swaps
+    pub min_liquidity_for_swaps: [Option<U128>; 2],
+    /// Maximum deviation of added liquidity amounts from the reserves ratio in basis points
+    pub max_add_deviation_bps: u32,
+    /// Fold accrued protocol fees into the liquidity on swaps, minting LP shares to the owner
+    pub auto_compound: bool,
+    /// Token1 & Token2 protocol fees both must reach to be folded into the liquidity
+    pub auto_compound_threshold: [U128; 2],
+    /// Token1 & Token2 parameters, `null` until token wallets are created
+    pub tokens: Option<[TokenConfig; 2]>,
+}
+
+/// Owner-configurable parameters of a token wallet
+#[derive(BorshSerialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenConfig {
+    /// Maximum deposit per account
+    pub max_deposit_per_account: Option<U128>,
+    /// Memo attached to outgoing transfers
+    pub transfer_memo: Option<String>,
+    /// Amount of liquidity a swap never takes out of the pool
+    pub reserve_buffer: U128,
+    /// Amount of swap input retained by the pool without being priced
+    pub rounding_buffer: U128,
+    /// Minimum swap output, smaller outputs are refunded
+    pub min_meaningful_output: U128,
+    /// Deposits are verified against the actual balance, for fee-on-transfer tokens
+    pub verify_deposits: bool,
+}
+
+impl From<&TokenWallet> for TokenConfig {
+    fn from(wallet: &TokenWallet) -> Self {
+        Self {
+            max_deposit_per_account: wallet.max_deposit_per_account,
+            transfer_memo: wallet.transfer_memo.clone(),
+            reserve_buffer: wallet.reserve_buffer,
+            rounding_buffer: wallet.rounding_buffer,
+            min_meaningful_output: wallet.min_meaningful_output,
+            verify_deposits: wallet.verify_deposits,
+        }
+    }
+}
+
+impl Hash for ContractConfig {
+    fn hash(&self) -> Vec<u8> {
+        env::sha256(
+            &self
+                .try_to_vec()
+                .unwrap_or_else(|_| env::panic_str("Failed to serialize config")),
+        )
     }
 }
 
+/// Partial update of owner-configurable parameters, only provided fields are applied
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfigPatch {
+    /// Swap fee in basis points
+    pub fee_bps: Option<u16>,
+    /// Share of the swap fee taken by the protocol in basis points
+    pub protocol_fee_bps: Option<u16>,
+    /// Maximum swap input as a share of the input token liquidity in basis points, `null` removes the cap
+    #[serde(
+        default,
+        deserialize_with = "crate::misc::deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_swap_bps: Option<Option<u16>>,
+    /// Maximum deposit per account for Token1 & Token2, `null` entry removes the cap
+    pub max_deposit_per_account: Option<[Option<U128>; 2]>,
+    /// Memo attached to outgoing Token1 & Token2 transfers, `null` entry removes the memo
+    pub transfer_memo: Option<[Option<String>; 2]>,
+    /// Amount of Token1 & Token2 liquidity a swap never takes out of the pool
+    pub reserve_buffer: Option<[U128; 2]>,
+    /// Amount of Token1 & Token2 swap input retained by the pool without being priced
+    pub rounding_buffer: Option<[U128; 2]>,
+    /// Minimum Token1 & Token2 swap output, smaller outputs are refunded
+    pub min_meaningful_output: Option<[U128; 2]>,
+    /// Verify Token1 & Token2 deposits against the actual balance, for fee-on-transfer tokens
+    pub verify_deposits: Option<[bool; 2]>,
+    /// Pause the contract if a swap output transfer fails
+    pub auto_pause_on_transfer_failure: Option<bool>,
+    /// Register users at the output token using the pool's $NEAR, if the swap output transfer fails
+    pub sponsor_user_registration: Option<bool>,
+    /// Maximum slippage of swaps from the marginal price in basis points, 0 disables the protection
+    pub default_max_slippage_bps: Option<u32>,
+    /// Token1 & Token2 liquidity floors for swaps, `null` entry removes the floor
+    pub min_liquidity_for_swaps: Option<[Option<U128>; 2]>,
+    /// Maximum deviation of added liquidity amounts from the reserves ratio in basis points
+    pub max_add_deviation_bps: Option<u32>,
+    /// Fold accrued protocol fees into the liquidity on swaps, minting LP shares to the owner
+    pub auto_compound: Option<bool>,
+    /// Token1 & Token2 protocol fees both must reach to be folded into the liquidity
+    pub auto_compound_threshold: Option<[U128; 2]>,
+}
+
 impl Contract {
+    /// Collects contract configuration & owner-configurable parameters
+    pub(crate) fn config(&self) -> ContractConfig {
+        ContractConfig {
+            owner_id: self.owner_id.clone(),
+            running_state: self.running_state.clone(),
+            token1_id: self
+                .token1_wallet
+                .as_ref()
+                .map(|wallet| wallet.token_id.clone()),
+            token2_id: self
+                .token2_wallet
+                .as_ref()
+                .map(|wallet| wallet.token_id.clone()),
+            fee_bps: self.fee_bps,
+            fee_mode: self.fee_mode,
+            protocol_fee_bps: self.protocol_fee_bps,
+            max_swap_bps: self.max_swap_bps,
+            auto_pause_on_transfer_failure: self.auto_pause_on_transfer_failure,
+            sponsor_user_registration: self.sponsor_user_registration,
+            default_max_slippage_bps: self.default_max_slippage_bps,
+            min_liquidity_for_swaps: self.min_liquidity_for_swaps,
+            max_add_deviation_bps: self.max_add_deviation_bps,
+            auto_compound: self.auto_compound,
+            auto_compound_threshold: self.auto_compound_threshold.map(U128),
+            tokens: self
+                .token1_wallet
+                .as_ref()
+                .zip(self.token2_wallet.as_ref())
+                .map(|(token1_wallet, token2_wallet)| [token1_wallet.into(), token2_wallet.into()]),
+        }
+    }
+
     /// Checks if contract is at running state
     pub(crate) fn assert_contract_running(&self) {
-        require!(
-            self.running_state == RunningState::Running,
-            "Contract paused"
-        );
+        if let Err(e) = self.check_contract_running() {
+            env::panic_str(e);
+        }
+    }
+
+    /// Returns an error if contract is not at running state
+    pub(crate) fn check_contract_running(&self) -> Result<(), &'static str> {
+        match self.running_state {
+            RunningState::Running => Ok(()),
+            RunningState::Paused => Err("Contract paused"),
+        }
     }
 
     /// Asserts if the caller is not an owner of the contract
@@ -77,11 +757,25 @@ impl Contract {
         require!(self.is_owner(&env::predecessor_account_id()), "Not allowed");
     }
 
+    /// Asserts if the caller is not an owner of the contract or didn't attach exactly 1 yoctoNEAR,
+    /// which requires full access key confirmation of admin actions
+    pub(crate) fn assert_owner_action(&self) {
+        assert_one_yocto();
+        self.assert_owner();
+    }
+
     /// Checks ifn the caller is an owner of the contract
     pub(crate) fn is_owner(&self, account_id: &AccountId) -> bool {
         account_id == &self.owner_id
     }
 
+    /// Checks if the pool may be created against `token_id`
+    pub(crate) fn is_token_allowed(&self, token_id: &AccountId) -> bool {
+        self.allowed_tokens
+            .as_ref()
+            .is_none_or(|allowed_tokens| allowed_tokens.contains(token_id))
+    }
+
     /// Returns account by provided `account_id`
     pub(crate) fn get_account(&self, account_id: &AccountId) -> Result<Account, &'static str> {
         self.accounts
@@ -89,4 +783,101 @@ impl Contract {
             .map(Account::from)
             .ok_or("Account is not registered")
     }
+
+    /// Credits `amount` of `token_id` to the balance of registered `account_id`
+    pub(crate) fn internal_deposit(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Result<(), &'static str> {
+        self.update_account(account_id, |account| account.deposit(token_id, amount))
+    }
+
+    /// Debits `amount` of `token_id` from the balance of registered `account_id`
+    pub(crate) fn internal_withdraw(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Result<(), &'static str> {
+        self.update_account(account_id, |account| account.withdraw(token_id, amount))
+    }
+
+    /// Applies `update` to registered `account_id` and stores it, see [Contract::store_account]
+    pub(crate) fn update_account(
+        &mut self,
+        account_id: &AccountId,
+        update: impl FnOnce(&mut Account) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let mut account = self.get_account(account_id)?;
+        update(&mut account)?;
+
+        self.store_account(account_id, account)
+    }
+
+    /// Stores `account` of `account_id`, bytes added by the write are charged from the account's
+    /// storage balance, bytes released by the write are returned to it
+    ///
+    /// The previous state is kept if the storage balance doesn't cover the bytes used
+    pub(crate) fn store_account(
+        &mut self,
+        account_id: &AccountId,
+        account: Account,
+    ) -> Result<(), &'static str> {
+        let storage_usage_before = env::storage_usage();
+        let prev_account = self.accounts.insert(account_id, &account.into());
+        let storage_usage_after = env::storage_usage();
+
+        // New account is charged for the whole entry
+        let prev_storage_usage = prev_account.as_ref().map_or(0, VAccount::storage_usage);
+        let mut account = self.get_account(account_id)?;
+        account.storage_usage = (prev_storage_usage + storage_usage_after)
+            .checked_sub(storage_usage_before)
+            .ok_or("Storage usage underflow")?;
+
+        // Account must be able to pay for all its bytes, otherwise the write is reverted
+        if Balance::from(account.storage_usage) * env::storage_byte_cost() > account.storage_balance
+        {
+            match prev_account {
+                Some(prev_account) => self.accounts.insert(account_id, &prev_account),
+                None => self.accounts.remove(account_id),
+            };
+            return Err("Insufficient storage balance");
+        }
+
+        self.accounts.insert(account_id, &account.into());
+
+        Ok(())
+    }
+
+    /// Charges bytes added to the state since `storage_usage_before` from the storage balance
+    /// of registered `account_id`, bytes released are returned to it
+    ///
+    /// Covers the account's state kept outside its account entry, the caller reverts the change on error
+    pub(crate) fn charge_account_storage(
+        &mut self,
+        account_id: &AccountId,
+        storage_usage_before: StorageUsage,
+    ) -> Result<(), &'static str> {
+        let storage_usage_after = env::storage_usage();
+
+        // Legacy account is upgraded first, so its entry keeps the size once the usage is updated
+        let account = self.get_account(account_id)?;
+        self.store_account(account_id, account)?;
+
+        let mut account = self.get_account(account_id)?;
+        account.storage_usage = (account.storage_usage + storage_usage_after)
+            .checked_sub(storage_usage_before)
+            .ok_or("Storage usage underflow")?;
+
+        if Balance::from(account.storage_usage) * env::storage_byte_cost() > account.storage_balance
+        {
+            return Err("Insufficient storage balance");
+        }
+
+        self.accounts.insert(account_id, &account.into());
+
+        Ok(())
+    }
 }
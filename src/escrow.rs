@@ -0,0 +1,397 @@
+use crate::misc::MAX_BPS;
+use crate::tokens::SwapProvider;
+use crate::{Contract, ContractExt};
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, near_bindgen, AccountId, Promise, PromiseError, PromiseOrValue,
+    ONE_YOCTO,
+};
+use primitive_types::U256;
+
+/// Committed swap, whose output transfer failed and whose escrowed input couldn't be refunded either
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FailedSwap {
+    pub account_id: AccountId,
+    /// Escrowed input token
+    pub token_id_in: AccountId,
+    /// Escrowed input amount owed to the account, the most that can be owed for an unresolved swap
+    pub amount_in: U128,
+    /// Block height of the failed refund
+    pub block_height: U64,
+    /// Outcome of the executed swap is unknown, so nothing is refunded until the owner settles it
+    pub unresolved: bool,
+}
+
+/// Tolerance of the committed swap output decrease till execution in basis points
+pub(crate) const COMMITTED_SWAP_TOLERANCE_BPS: u32 = 100;
+
+/// Swap with escrowed input tokens, which is executed by the same user in a later block
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CommittedSwap {
+    /// Escrowed input token
+    pub token_id_in: AccountId,
+    /// Escrowed input amount
+    pub amount_in: U128,
+    /// Output amount quoted at commit time
+    pub amount_out: U128,
+    /// Block height of the commit
+    pub block_height: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Executes caller's committed swap, if the output didn't decrease beyond tolerance since commit
+    #[payable]
+    #[handle_result]
+    pub fn execute_swap(&mut self) -> Result<Promise, &'static str> {
+        assert_one_yocto();
+
+        self.check_contract_running()?;
+
+        self.assert_min_liquidity_for_swaps()?;
+
+        let account_id = env::predecessor_account_id();
+        let committed_swap = self.remove_committed_swap(&account_id)?;
+
+        if env::block_height() <= committed_swap.block_height.0 {
+            return Err("Committed swap can be executed only in a later block");
+        }
+
+        let amount_out =
+            self.get_amount_out(committed_swap.token_id_in.clone(), committed_swap.amount_in)?;
+        if U256::from(amount_out.0) * U256::from(MAX_BPS)
+            < U256::from(committed_swap.amount_out.0)
+                * U256::from(MAX_BPS - COMMITTED_SWAP_TOLERANCE_BPS)
+        {
+            return Err("Swap output decreased beyond committed swap tolerance");
+        }
+
+        let token_id_in = committed_swap.token_id_in;
+        let amount_in = committed_swap.amount_in;
+        match self.swap_tokens(
+            account_id.clone(),
+            token_id_in.clone(),
+            amount_in.into(),
+            None,
+            None,
+            None,
+        )? {
+            PromiseOrValue::Promise(promise) => Ok(promise.then(
+                Self::ext(env::current_account_id()).on_committed_swap_complete(
+                    account_id,
+                    token_id_in,
+                    amount_in,
+                ),
+            )),
+            PromiseOrValue::Value(amount_unused) => {
+                Ok(self.refund_escrow(account_id, token_id_in, amount_unused))
+            }
+        }
+    }
+
+    /// Cancels caller's committed swap and refunds escrowed tokens
+    #[payable]
+    #[handle_result]
+    pub fn cancel_swap(&mut self) -> Result<Promise, &'static str> {
+        assert_one_yocto();
+
+        let account_id = env::predecessor_account_id();
+        let committed_swap = self.remove_committed_swap(&account_id)?;
+
+        Ok(self
+            .refund_escrow(
+                account_id.clone(),
+                committed_swap.token_id_in.clone(),
+                committed_swap.amount_in,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .on_committed_swap_cancelled(account_id, committed_swap),
+            ))
+    }
+
+    /// Returns committed swap of provided `account_id`
+    pub fn get_committed_swap(&self, account_id: AccountId) -> Option<CommittedSwap> {
+        self.committed_swaps.get(&account_id)
+    }
+
+    /// Returns recorded failed swap by `swap_id`
+    pub fn get_failed_swap(&self, swap_id: u64) -> Option<FailedSwap> {
+        self.failed_swaps.get(&swap_id)
+    }
+
+    /// Owner's function to return the escrowed input of a recorded failed swap to its account,
+    /// the record is restored if the refund fails again
+    ///
+    /// Only the escrowed input not used by a swap is recorded as owed, while an unresolved swap
+    /// is settled with [Contract::settle_unresolved_swap] first
+    #[payable]
+    #[handle_result]
+    pub fn resolve_stuck_swap(&mut self, swap_id: u64) -> Result<Promise, &'static str> {
+        self.assert_owner_action();
+
+        let failed_swap = self
+            .failed_swaps
+            .get(&swap_id)
+            .ok_or("Failed swap is not found")?;
+        if failed_swap.unresolved {
+            return Err("Failed swap is unresolved");
+        }
+        self.failed_swaps.remove(&swap_id);
+
+        Ok(self
+            .refund_escrow(
+                failed_swap.account_id.clone(),
+                failed_swap.token_id_in.clone(),
+                failed_swap.amount_in,
+            )
+            .then(
+                Self::ext(env::current_account_id()).on_stuck_swap_resolved(swap_id, failed_swap),
+            ))
+    }
+
+    /// Owner's function to settle an unresolved swap once its outcome is checked,
+    /// `amount_owed` of the escrowed input becomes refundable by [Contract::resolve_stuck_swap]
+    /// and the record is dropped if nothing is owed
+    #[payable]
+    #[handle_result]
+    pub fn settle_unresolved_swap(
+        &mut self,
+        swap_id: u64,
+        amount_owed: U128,
+    ) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        let mut failed_swap = self
+            .failed_swaps
+            .get(&swap_id)
+            .ok_or("Failed swap is not found")?;
+        if !failed_swap.unresolved {
+            return Err("Failed swap is already resolved");
+        }
+        if amount_owed.0 > failed_swap.amount_in.0 {
+            return Err("Owed amount exceeds escrowed input");
+        }
+
+        env::log_str(&format!(
+            "Unresolved swap {} of {:?} is settled, {} `{}` is owed",
+            swap_id, failed_swap.account_id, amount_owed.0, failed_swap.token_id_in
+        ));
+
+        if amount_owed.0 == 0 {
+            self.failed_swaps.remove(&swap_id);
+        } else {
+            failed_swap.amount_in = amount_owed;
+            failed_swap.unresolved = false;
+            self.failed_swaps.insert(&swap_id, &failed_swap);
+        }
+
+        Ok(())
+    }
+
+    /// Refunds input left unused by the executed committed swap, the swap is held as unresolved
+    /// for the owner to check if its outcome is unknown
+    #[private]
+    pub fn on_committed_swap_complete(
+        &mut self,
+        account_id: AccountId,
+        token_id_in: AccountId,
+        amount_in: U128,
+        #[callback_result] amount_unused: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()> {
+        match amount_unused {
+            Ok(amount_unused) if amount_unused.0 > 0 => self
+                .refund_escrow(account_id.clone(), token_id_in.clone(), amount_unused)
+                .then(Self::ext(env::current_account_id()).on_escrow_refunded(
+                    account_id,
+                    token_id_in,
+                    amount_unused,
+                ))
+                .into(),
+
+            Ok(_) => PromiseOrValue::Value(()),
+
+            // Output may have been transferred already, so the input isn't recorded as owed
+            Err(_) => {
+                let swap_id =
+                    self.record_failed_swap(account_id.clone(), token_id_in, amount_in, true);
+                env::log_str(&format!(
+                    "Committed swap of {:?} completed with unknown outcome, held as unresolved swap {}",
+                    account_id, swap_id
+                ));
+
+                PromiseOrValue::Value(())
+            }
+        }
+    }
+
+    /// Records failed swap if the escrow refund of the executed committed swap failed
+    #[private]
+    pub fn on_escrow_refunded(
+        &mut self,
+        account_id: AccountId,
+        token_id_in: AccountId,
+        amount: U128,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) {
+        if transfer_result.is_ok() {
+            return;
+        }
+
+        let swap_id = self.record_failed_swap(account_id.clone(), token_id_in, amount, false);
+        env::log_str(&format!(
+            "Refund of committed swap to {:?} failed, recorded as failed swap {}",
+            account_id, swap_id
+        ));
+    }
+
+    /// Restores failed swap if its refund by the owner failed again
+    #[private]
+    pub fn on_stuck_swap_resolved(
+        &mut self,
+        swap_id: u64,
+        failed_swap: FailedSwap,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> bool {
+        if transfer_result.is_err() {
+            env::log_str(&format!(
+                "Refund of failed swap {} to {:?} failed, swap is restored",
+                swap_id, failed_swap.account_id
+            ));
+
+            self.failed_swaps.insert(&swap_id, &failed_swap);
+
+            return false;
+        }
+
+        true
+    }
+
+    /// Restores committed swap if its escrow refund failed
+    #[private]
+    pub fn on_committed_swap_cancelled(
+        &mut self,
+        account_id: AccountId,
+        committed_swap: CommittedSwap,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) {
+        if transfer_result.is_err() {
+            env::log_str(&format!(
+                "Refund of committed swap to {:?} failed, swap is restored",
+                account_id
+            ));
+
+            // Swap is restored even if the account can't pay for it anymore, as its escrow is still owed
+            let storage_usage_before = env::storage_usage();
+            self.committed_swaps.insert(&account_id, &committed_swap);
+            self.charge_account_storage(&account_id, storage_usage_before)
+                .ok();
+        }
+    }
+}
+
+impl Contract {
+    /// Escrows transferred tokens and locks current swap quote for the sender
+    pub(crate) fn on_transfer_commit_swap(
+        &mut self,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        amount_in: U128,
+        min_amount_out: Option<U128>,
+    ) -> Result<PromiseOrValue<U128>, &'static str> {
+        self.check_contract_running()?;
+
+        if self.committed_swaps.contains_key(&sender_id) {
+            return Err("Swap is already committed");
+        }
+
+        let committed_swap = CommittedSwap {
+            amount_out: self.get_amount_out(token_id_in.clone(), amount_in)?,
+            token_id_in,
+            amount_in,
+            block_height: env::block_height().into(),
+        };
+
+        if committed_swap.amount_out.0 < min_amount_out.map_or(0, u128::from) {
+            return Err("Swap output is below minimum");
+        }
+
+        env::log_str(&format!(
+            "User {:?} commits swap of {} `{}` token(s) for {}",
+            sender_id,
+            committed_swap.amount_in.0,
+            committed_swap.token_id_in,
+            committed_swap.amount_out.0
+        ));
+
+        // Sender pays for the committed swap storage
+        let storage_usage_before = env::storage_usage();
+        self.committed_swaps.insert(&sender_id, &committed_swap);
+        if let Err(e) = self.charge_account_storage(&sender_id, storage_usage_before) {
+            self.committed_swaps.remove(&sender_id);
+            return Err(e);
+        }
+
+        Ok(PromiseOrValue::Value(0.into()))
+    }
+
+    /// Removes committed swap of `account_id`, its storage is returned to the account
+    fn remove_committed_swap(
+        &mut self,
+        account_id: &AccountId,
+    ) -> Result<CommittedSwap, &'static str> {
+        let storage_usage_before = env::storage_usage();
+        let committed_swap = self
+            .committed_swaps
+            .remove(account_id)
+            .ok_or("Swap is not committed")?;
+        // Swap committed before its storage was charged to the account has nothing to return
+        self.charge_account_storage(account_id, storage_usage_before)
+            .ok();
+
+        Ok(committed_swap)
+    }
+
+    /// Records escrowed `amount_in` of `token_id_in` owed to `account_id` as failed swap,
+    /// or held for the owner to check if `unresolved`, returns its id
+    fn record_failed_swap(
+        &mut self,
+        account_id: AccountId,
+        token_id_in: AccountId,
+        amount_in: U128,
+        unresolved: bool,
+    ) -> u64 {
+        let swap_id = self.next_failed_swap_id;
+        self.next_failed_swap_id += 1;
+
+        self.failed_swaps.insert(
+            &swap_id,
+            &FailedSwap {
+                account_id,
+                token_id_in,
+                amount_in,
+                block_height: env::block_height().into(),
+                unresolved,
+            },
+        );
+
+        swap_id
+    }
+
+    /// Transfers escrowed `amount` of `token_id` back to `account_id`
+    fn refund_escrow(&self, account_id: AccountId, token_id: AccountId, amount: U128) -> Promise {
+        let memo = self
+            .get_token_wallet(&token_id)
+            .ok()
+            .and_then(|token_wallet| token_wallet.transfer_memo.clone());
+
+        ext_ft_core::ext(token_id)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(account_id, amount, memo)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::misc::now_ns;
+use crate::tokens::SwapAmounts;
+use crate::{Contract, ContractExt};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+
+/// Number of the latest swaps kept in the history, older ones are overwritten
+pub(crate) const SWAP_HISTORY_SIZE: u64 = 100;
+
+/// Completed swap kept in the history
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapRecord {
+    /// Index of the swap, counted from the first swap of the pool
+    pub index: U64,
+    pub sender_id: AccountId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    /// Input amount consumed by the swap, including the fee
+    pub amount_in: U128,
+    pub amount_out: U128,
+    /// Input fee, including the protocol share
+    pub fee: U128,
+    /// Swap completion timestamp in nanoseconds
+    pub timestamp: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns up to `limit` swaps starting from the swap with `from_index`, only the latest
+    /// [SWAP_HISTORY_SIZE] swaps are kept, so the result starts from the oldest kept one if it's later
+    pub fn get_swap_history(&self, from_index: U64, limit: u64) -> Vec<SwapRecord> {
+        let oldest_index = self.swap_history_len.saturating_sub(SWAP_HISTORY_SIZE);
+        let from_index = from_index.0.max(oldest_index);
+        let to_index = from_index.saturating_add(limit).min(self.swap_history_len);
+
+        (from_index..to_index)
+            .filter_map(|index| self.swap_history.get(&(index % SWAP_HISTORY_SIZE)))
+            .collect()
+    }
+
+    /// Returns number of swaps recorded since the pool creation, including ones dropped from the history
+    pub fn get_swap_history_len(&self) -> U64 {
+        self.swap_history_len.into()
+    }
+}
+
+impl Contract {
+    /// Records completed swap, overwriting the oldest one once the history is full
+    pub(crate) fn record_swap(
+        &mut self,
+        sender_id: AccountId,
+        token_in: AccountId,
+        token_out: AccountId,
+        amounts: &SwapAmounts,
+    ) {
+        let index = self.swap_history_len;
+        self.swap_history.insert(
+            &(index % SWAP_HISTORY_SIZE),
+            &SwapRecord {
+                index: index.into(),
+                sender_id,
+                token_in,
+                token_out,
+                amount_in: amounts.amount_in,
+                amount_out: amounts.amount_out,
+                fee: amounts.fee,
+                timestamp: now_ns().into(),
+            },
+        );
+        self.swap_history_len += 1;
+    }
+}
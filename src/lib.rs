@@ -1,6 +1,9 @@
 mod account;
 mod contract;
+mod escrow;
+mod history;
 mod misc;
+mod oracle;
 mod storage;
 mod storage_management;
 #[cfg(test)]
@@ -1,23 +1,595 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::env;
 use near_sdk::serde::{Deserialize, Serialize};
-use primitive_types::U256;
+use near_sdk::serde_json;
+use primitive_types::{U256, U512};
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub enum RunningState {
     Running,
     Paused,
 }
 
+/// Swap token the fee is deducted from
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FeeMode {
+    /// Fee is deducted from the input before pricing it
+    OnInput,
+    /// Whole input is priced and the fee is deducted from the output
+    OnOutput,
+}
+
+impl FeeMode {
+    /// Returns part of `fee_bps` deducted from the input
+    pub(crate) fn input_fee_bps(self, fee_bps: u16) -> u16 {
+        match self {
+            FeeMode::OnInput => fee_bps,
+            FeeMode::OnOutput => 0,
+        }
+    }
+
+    /// Returns part of `fee_bps` deducted from the output
+    pub(crate) fn output_fee_bps(self, fee_bps: u16) -> u16 {
+        match self {
+            FeeMode::OnInput => 0,
+            FeeMode::OnOutput => fee_bps,
+        }
+    }
+}
+
+/// Returns current block timestamp in nanoseconds
+pub(crate) fn now_ns() -> u64 {
+    env::block_timestamp()
+}
+
+/// Returns nanoseconds elapsed since `since_ns` till `now_ns`
+///
+/// Saturates to zero if `since_ns` is ahead of `now_ns`, e.g. for a malformed or zero timestamp
+pub(crate) fn elapsed_ns(since_ns: u64, now_ns: u64) -> u64 {
+    now_ns.saturating_sub(since_ns)
+}
+
+/// Standard name of the pool events
+pub(crate) const EVENT_STANDARD: &str = "swap_pool";
+
+/// Version of the pool events standard
+pub(crate) const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// NEP-297 event logged with `EVENT_JSON:` prefix
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Event<T> {
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    pub data: Vec<T>,
+}
+
+/// Logs `event` with provided `data` as NEP-297 event
+pub(crate) fn emit_event<T: Serialize>(event: &str, data: T) {
+    let event = Event {
+        standard: EVENT_STANDARD.to_string(),
+        version: EVENT_STANDARD_VERSION.to_string(),
+        event: event.to_string(),
+        data: vec![data],
+    };
+
+    if let Ok(event_json) = serde_json::to_string(&event) {
+        env::log_str(&format!("EVENT_JSON:{}", event_json));
+    }
+}
+
 pub(crate) trait Hash {
     fn hash(&self) -> Vec<u8>;
 }
 
+/// Returns multipliers normalizing amounts of tokens with `decimals1` & `decimals2` to the larger of them,
+/// so both amounts are expressed in units of the same scale
+pub(crate) fn compute_normalization_scales(
+    decimals1: u8,
+    decimals2: u8,
+) -> Result<[U256; 2], &'static str> {
+    let decimals = decimals1.max(decimals2);
+    let scale1 = U256::from(10).checked_pow((decimals - decimals1).into());
+    let scale2 = U256::from(10).checked_pow((decimals - decimals2).into());
+
+    scale1
+        .zip(scale2)
+        .map(|(scale1, scale2)| [scale1, scale2])
+        .ok_or("Computation overflow")
+}
+
+/// Normalizes `amount` by `scale` of [compute_normalization_scales]
+fn normalize_amount(amount: u128, scale: U256) -> Result<U256, &'static str> {
+    U256::from(amount)
+        .checked_mul(scale)
+        .ok_or("Computation overflow")
+}
+
+/// Computes tokens ratio `token1_amount * token2_amount` with amounts normalized to the common decimals
+/// scale, so the ratio isn't distorted by mismatched tokens decimals
+///
+/// Extremely large reserves of tokens with very different decimals may overflow `U256` once normalized,
+/// so [crate::Contract::add_liquidity] rejects liquidity whose resulting ratio would overflow
 pub(crate) fn compute_tokens_ratio(
     token1_amount: u128,
+    token1_decimals: u8,
     token2_amount: u128,
+    token2_decimals: u8,
+) -> Result<U256, &'static str> {
+    let [scale1, scale2] = compute_normalization_scales(token1_decimals, token2_decimals)?;
+
+    normalize_amount(token1_amount, scale1)?
+        .checked_mul(normalize_amount(token2_amount, scale2)?)
+        .ok_or("Computation overflow")
+}
+
+/// Computes swap output amount for `amount_in` against provided reserves using tokens ratio of normalized
+/// amounts, the output is scaled back to the output token decimals & rounded in favor of the pool,
+/// so the ratio never decreases
+pub(crate) fn compute_amount_out(
+    reserve_in: u128,
+    decimals_in: u8,
+    reserve_out: u128,
+    decimals_out: u8,
+    amount_in: u128,
+) -> Result<u128, &'static str> {
+    let [scale_in, scale_out] = compute_normalization_scales(decimals_in, decimals_out)?;
+    let ratio = compute_tokens_ratio(reserve_in, decimals_in, reserve_out, decimals_out)?;
+    let reserve_out = normalize_amount(reserve_out, scale_out)?;
+
+    let reserve_in_after = reserve_in
+        .checked_add(amount_in)
+        .ok_or("Computation overflow")
+        .and_then(|reserve_in_after| normalize_amount(reserve_in_after, scale_in))?;
+
+    // amount_out = (reserve_out - ceil(ratio / (reserve_in + amount_in))) / scale_out
+    ceil_div(ratio, reserve_in_after)
+        .and_then(|res| reserve_out.checked_sub(res))
+        .map(|amount_out| amount_out / scale_out)
+        .and_then(|amount_out| u128::try_from(amount_out).ok())
+        .ok_or("Computation overflow")
+}
+
+/// Computes swap input amount required to receive `amount_out` against provided reserves,
+/// the inverse of [compute_amount_out] rounded in favor of the pool
+pub(crate) fn compute_amount_in(
+    reserve_in: u128,
+    decimals_in: u8,
+    reserve_out: u128,
+    decimals_out: u8,
+    amount_out: u128,
+) -> Result<u128, &'static str> {
+    let [scale_in, scale_out] = compute_normalization_scales(decimals_in, decimals_out)?;
+    let ratio = compute_tokens_ratio(reserve_in, decimals_in, reserve_out, decimals_out)?;
+    let reserve_in = normalize_amount(reserve_in, scale_in)?;
+
+    let reserve_out_after = reserve_out
+        .checked_sub(amount_out)
+        .ok_or("Computation overflow")
+        .and_then(|reserve_out_after| normalize_amount(reserve_out_after, scale_out))?;
+
+    // amount_in = ceil((ceil(ratio / (reserve_out - amount_out)) - reserve_in) / scale_in)
+    ceil_div(ratio, reserve_out_after)
+        .and_then(|res| res.checked_sub(reserve_in))
+        .and_then(|amount_in| ceil_div(amount_in, scale_in))
+        .and_then(|amount_in| u128::try_from(amount_in).ok())
+        .ok_or("Computation overflow")
+}
+
+/// Computes liquidity value of `reserves` as the geometric mean `sqrt(reserve1 * reserve2)` rounded down,
+/// or rounded up if `round_up` is set
+fn compute_liquidity_value(reserves: [u128; 2], round_up: bool) -> U256 {
+    // u128 * u128 always fits into U256
+    let ratio = U256::from(reserves[0]) * U256::from(reserves[1]);
+    let value = ratio.integer_sqrt();
+
+    if round_up && value * value < ratio {
+        value + 1
+    } else {
+        value
+    }
+}
+
+/// Computes the largest amounts within `max_amounts`, which match the `reserves` ratio, the amount derived
+/// from the other one is rounded down. Amounts of an empty pool set its ratio, so they are used as is
+pub(crate) fn compute_balanced_amounts(
+    reserves: [u128; 2],
+    max_amounts: [u128; 2],
+) -> Result<[u128; 2], &'static str> {
+    if reserves == [0, 0] {
+        return Ok(max_amounts);
+    }
+    if reserves[0] == 0 || reserves[1] == 0 {
+        return Err("Pool ratio is undefined");
+    }
+
+    // u128 * u128 always fits into U256, the quotient never exceeds the other max amount
+    let amount2 = U256::from(max_amounts[0]) * U256::from(reserves[1]) / U256::from(reserves[0]);
+    if amount2 <= U256::from(max_amounts[1]) {
+        return Ok([max_amounts[0], amount2.as_u128()]);
+    }
+
+    let amount1 = U256::from(max_amounts[1]) * U256::from(reserves[0]) / U256::from(reserves[1]);
+    Ok([amount1.as_u128(), max_amounts[1]])
+}
+
+/// Computes LP shares minted for adding `amounts` to `reserves`, while `total_shares` are in circulation
+///
+/// Shares are proportional to the growth of the liquidity value, so the first provider mints the geometric
+/// mean of the reserves after the addition, while liquidity added before LP shares existed is attributed
+/// to them. Shares are rounded down in favor of the pool
+pub(crate) fn compute_shares_to_mint(
+    reserves: [u128; 2],
+    amounts: [u128; 2],
+    total_shares: u128,
+) -> Result<u128, &'static str> {
+    let reserves_after = [
+        reserves[0].checked_add(amounts[0]),
+        reserves[1].checked_add(amounts[1]),
+    ];
+    let [Some(reserve1_after), Some(reserve2_after)] = reserves_after else {
+        return Err("Computation overflow");
+    };
+    let value_after = compute_liquidity_value([reserve1_after, reserve2_after], false);
+
+    let shares = if total_shares == 0 {
+        value_after
+    } else {
+        let value_before = compute_liquidity_value(reserves, true);
+        if value_before.is_zero() {
+            return Err("Pool is empty");
+        }
+
+        // u128 * sqrt(u128 * u128) always fits into U256
+        U256::from(total_shares) * value_after.saturating_sub(value_before) / value_before
+    };
+
+    match u128::try_from(shares) {
+        Ok(0) => Err("Liquidity amounts are too small to mint LP shares"),
+        Ok(shares) => Ok(shares),
+        Err(_) => Err("Computation overflow"),
+    }
+}
+
+/// Computes LP shares burned for removing `amounts` from `reserves`, while `total_shares` are in circulation,
+/// proportionally to the decrease of the liquidity value & rounded up in favor of the pool
+pub(crate) fn compute_shares_to_burn(
+    reserves: [u128; 2],
+    amounts: [u128; 2],
+    total_shares: u128,
+) -> Result<u128, &'static str> {
+    let value_before = compute_liquidity_value(reserves, true);
+    let value_after = compute_liquidity_value(
+        [
+            reserves[0].saturating_sub(amounts[0]),
+            reserves[1].saturating_sub(amounts[1]),
+        ],
+        false,
+    );
+
+    // u128 * sqrt(u128 * u128) always fits into U256
+    ceil_div(
+        U256::from(total_shares) * (value_before - value_after),
+        value_before,
+    )
+    .map_or(Ok(0), |shares| {
+        u128::try_from(shares).map_err(|_| "Computation overflow")
+    })
+}
+
+/// Computes pro-rata amounts of `reserves` for `shares` out of `total_shares`, rounded down in favor of
+/// the remaining LP shares
+pub(crate) fn compute_amounts_for_shares(
+    reserves: [u128; 2],
+    shares: u128,
+    total_shares: u128,
+) -> Result<[u128; 2], &'static str> {
+    if shares == 0 {
+        return Err("Shares must be positive");
+    }
+
+    if shares > total_shares {
+        return Err("Not enough LP shares");
+    }
+
+    // Amount never exceeds the reserve, as `shares <= total_shares`
+    Ok(reserves.map(|reserve| {
+        (U256::from(reserve) * U256::from(shares) / U256::from(total_shares)).as_u128()
+    }))
+}
+
+/// Computes value of liquidity `amounts` in Token2 units at the price of `reserves`
+pub(crate) fn compute_entry_value(
+    reserves: [u128; 2],
+    amounts: [u128; 2],
+) -> Result<u128, &'static str> {
+    if reserves[0] == 0 {
+        return Ok(amounts[1]);
+    }
+
+    // u128 * u128 always fits into U256
+    (U256::from(amounts[0]) * U256::from(reserves[1]) / U256::from(reserves[0]))
+        .checked_add(U256::from(amounts[1]))
+        .and_then(|value| u128::try_from(value).ok())
+        .ok_or("Computation overflow")
+}
+
+/// Computes part of the LP entry `value` of `lp_shares` burned along with `shares` out of them
+pub(crate) fn compute_entry_value_burned(value: u128, shares: u128, lp_shares: u128) -> u128 {
+    if shares >= lp_shares {
+        return value;
+    }
+
+    // u128 * u128 always fits into U256, the result doesn't exceed `value` as `shares < lp_shares`
+    (U256::from(value) * U256::from(shares) / U256::from(lp_shares)).as_u128()
+}
+
+/// Computes Token1 price in Token2, adjusted by tokens decimals and scaled by `10^PRICE_DECIMALS`, at which
+/// the liquidity of `lp_reserves` is worth `value` in Token2 units
+///
+/// Liquidity `x * y = k` is worth `2 * sqrt(k * price)` in Token2 at the price, so it's `value^2 / (4 * k)`
+pub(crate) fn compute_breakeven_price(
+    value: u128,
+    lp_reserves: [u128; 2],
+    decimals1: u8,
+    decimals2: u8,
 ) -> Result<U256, &'static str> {
-    U256::from(token1_amount)
-        .checked_mul(U256::from(token2_amount))
+    if lp_reserves[0] == 0 || lp_reserves[1] == 0 {
+        return Err("Pool is empty");
+    }
+
+    // u128 * u128 always fits into U512, scaled by decimals it may not
+    let ten = U512::from(10);
+    let numerator = ten
+        .checked_pow((u32::from(decimals1) + PRICE_DECIMALS).into())
+        .and_then(|scale| (U512::from(value) * U512::from(value)).checked_mul(scale))
+        .ok_or("Computation overflow")?;
+    let denominator = ten
+        .checked_pow(decimals2.into())
+        .and_then(|scale| {
+            (U512::from(4) * U512::from(lp_reserves[0]) * U512::from(lp_reserves[1]))
+                .checked_mul(scale)
+        })
+        .ok_or("Computation overflow")?;
+
+    U256::try_from(numerator / denominator).map_err(|_| "Computation overflow")
+}
+
+/// Checks if the tokens ratio `reserve1 * reserve2` of `reserves_after` didn't decrease
+pub(crate) fn is_ratio_preserved(reserves_before: [u128; 2], reserves_after: [u128; 2]) -> bool {
+    let [reserve1_before, reserve2_before] = reserves_before.map(U256::from);
+    let [reserve1_after, reserve2_after] = reserves_after.map(U256::from);
+
+    // u128 * u128 always fits into U256
+    reserve1_after * reserve2_after >= reserve1_before * reserve2_before
+}
+
+/// Basis points in 100%
+pub(crate) const MAX_BPS: u32 = 10_000;
+
+/// Default swap fee in basis points
+pub(crate) const DEFAULT_FEE_BPS: u16 = 30;
+
+/// Deducts `fee_bps` fee from `amount`, the fee is rounded up in favor of the pool
+pub(crate) fn deduct_fee(amount: u128, fee_bps: u16) -> Result<u128, &'static str> {
+    // u128 * u16 always fits into U256
+    let amount = U256::from(amount);
+    let fee = ceil_div(amount * U256::from(fee_bps), U256::from(MAX_BPS))
+        .ok_or("Computation overflow")?;
+
+    u128::try_from(amount.saturating_sub(fee)).map_err(|_| "Computation overflow")
+}
+
+/// Computes the smallest amount, which is at least `amount` after [deduct_fee]
+pub(crate) fn add_fee(amount: u128, fee_bps: u16) -> Result<u128, &'static str> {
+    ceil_div(
+        U256::from(amount) * U256::from(MAX_BPS),
+        U256::from(MAX_BPS.saturating_sub(fee_bps.into())),
+    )
+    .and_then(|amount| u128::try_from(amount).ok())
+    .ok_or("Computation overflow")
+}
+
+/// Number of fractional digits of prices returned by views
+pub(crate) const PRICE_DECIMALS: u32 = 12;
+
+/// Computes price `reserve_out / reserve_in` adjusted by tokens decimals, scaled by `10^PRICE_DECIMALS`
+pub(crate) fn compute_price(
+    reserve_in: u128,
+    decimals_in: u8,
+    reserve_out: u128,
+    decimals_out: u8,
+) -> Result<U256, &'static str> {
+    if reserve_in == 0 {
+        return Err("Pool is empty");
+    }
+
+    let ten = U256::from(10);
+    let numerator = ten
+        .checked_pow((u32::from(decimals_in) + PRICE_DECIMALS).into())
+        .and_then(|scale| U256::from(reserve_out).checked_mul(scale))
+        .ok_or("Computation overflow")?;
+    let denominator = ten
+        .checked_pow(decimals_out.into())
+        .and_then(|scale| U256::from(reserve_in).checked_mul(scale))
+        .ok_or("Computation overflow")?;
+
+    Ok(numerator / denominator)
+}
+
+/// Computes input amount moving the price `reserve_out / reserve_in` adjusted by tokens decimals
+/// down to `target_price` scaled by `10^PRICE_DECIMALS`
+///
+/// As the tokens ratio of normalized amounts is preserved and the decimals adjusted price equals
+/// the price of normalized amounts, `(reserve_in + amount_in)^2 = ratio / target_price`
+pub(crate) fn compute_amount_to_price(
+    reserve_in: u128,
+    decimals_in: u8,
+    reserve_out: u128,
+    decimals_out: u8,
+    target_price: U256,
+) -> Result<u128, &'static str> {
+    if target_price >= compute_price(reserve_in, decimals_in, reserve_out, decimals_out)? {
+        return Err("Target price must be below the current price");
+    }
+
+    let [scale_in, _] = compute_normalization_scales(decimals_in, decimals_out)?;
+    let numerator = compute_tokens_ratio(reserve_in, decimals_in, reserve_out, decimals_out)?
+        .checked_mul(U256::from(10).pow(PRICE_DECIMALS.into()))
+        .ok_or("Computation overflow")?;
+
+    (numerator / target_price)
+        .integer_sqrt()
+        .checked_sub(normalize_amount(reserve_in, scale_in)?)
+        .map(|amount_in| amount_in / scale_in)
+        .and_then(|amount_in| u128::try_from(amount_in).ok())
         .ok_or("Computation overflow")
 }
+
+/// Inverts price scaled by `10^PRICE_DECIMALS`, i.e. converts price of the input token in units of
+/// the output token into price of the output token in units of the input token
+pub(crate) fn invert_price(price: U256) -> Result<U256, &'static str> {
+    if price.is_zero() {
+        return Err("Invalid price");
+    }
+
+    Ok(U256::from(10).pow((2 * PRICE_DECIMALS).into()) / price)
+}
+
+/// Computes value of `amount_out` in units of the input token, given `price` of the input token
+/// in units of the output token adjusted by tokens decimals and scaled by `10^PRICE_DECIMALS`
+pub(crate) fn compute_value_in(
+    amount_out: u128,
+    decimals_in: u8,
+    decimals_out: u8,
+    price: U256,
+) -> Result<u128, &'static str> {
+    if price.is_zero() {
+        return Err("Invalid price");
+    }
+
+    let ten = U256::from(10);
+    let numerator = ten
+        .checked_pow((u32::from(decimals_in) + PRICE_DECIMALS).into())
+        .and_then(|scale| U256::from(amount_out).checked_mul(scale))
+        .ok_or("Computation overflow")?;
+    let denominator = ten
+        .checked_pow(decimals_out.into())
+        .and_then(|scale| price.checked_mul(scale))
+        .ok_or("Computation overflow")?;
+
+    u128::try_from(numerator / denominator).map_err(|_| "Computation overflow")
+}
+
+/// Formats price scaled by `10^PRICE_DECIMALS` as a decimal string
+pub(crate) fn format_price(price: U256) -> String {
+    let (integer, fraction) = price.div_mod(U256::from(10).pow(PRICE_DECIMALS.into()));
+
+    format!(
+        "{}.{:0width$}",
+        integer,
+        fraction.as_u64(),
+        width = PRICE_DECIMALS as usize
+    )
+}
+
+/// Parses a decimal string with at most [PRICE_DECIMALS] fractional digits into a price
+/// scaled by `10^PRICE_DECIMALS`
+pub(crate) fn parse_price(price: &str) -> Result<U256, &'static str> {
+    let (integer, fraction) = price.split_once('.').unwrap_or((price, ""));
+
+    if integer.is_empty()
+        || fraction.len() > PRICE_DECIMALS as usize
+        || !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err("Invalid price");
+    }
+
+    let digits = format!(
+        "{}{:0<width$}",
+        integer,
+        fraction,
+        width = PRICE_DECIMALS as usize
+    );
+
+    U256::from_dec_str(&digits).map_err(|_| "Invalid price")
+}
+
+/// Divides rounding up, returns `None` on division by zero
+pub(crate) fn ceil_div(numerator: U256, denominator: U256) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+
+    let (quotient, remainder) = numerator.div_mod(denominator);
+
+    Some(if remainder.is_zero() {
+        quotient
+    } else {
+        quotient + 1
+    })
+}
+
+/// Logs a warning if the spot price of `reserves_after` moved beyond rounding from `reserves_before`
+#[cfg(feature = "debug")]
+pub(crate) fn check_price_preserved(reserves_before: [u128; 2], reserves_after: [u128; 2]) {
+    if !is_price_preserved(reserves_before, reserves_after) {
+        env::log_str(&format!(
+            "Warning: spot price moved from {}/{} to {}/{}",
+            reserves_before[1], reserves_before[0], reserves_after[1], reserves_after[0]
+        ));
+    }
+}
+
+/// Checks if the spot price `reserve2 / reserve1` is preserved within a single unit rounding
+/// of either reserve change. Empty pool has no price, so any change preserves it.
+#[cfg(any(test, feature = "debug"))]
+pub(crate) fn is_price_preserved(reserves_before: [u128; 2], reserves_after: [u128; 2]) -> bool {
+    let [reserve1_before, reserve2_before] = reserves_before.map(U256::from);
+    let [reserve1_after, reserve2_after] = reserves_after.map(U256::from);
+
+    if reserve1_before.is_zero() || reserve2_before.is_zero() {
+        return true;
+    }
+
+    // u128 * u128 always fits into U256
+    let lhs = reserve2_after * reserve1_before;
+    let rhs = reserve2_before * reserve1_after;
+    let deviation = if lhs > rhs { lhs - rhs } else { rhs - lhs };
+
+    deviation <= reserve1_before + reserve2_before
+}
+
+/// Serde helper for an optional patch field, which distinguishes `null` from the missing field
+///
+/// Must be combined with `#[serde(default)]`, so the missing field stays `None`, while `null` becomes `Some(None)`
+pub(crate) fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: near_sdk::serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Serde helper for `U256` as a decimal string, since JSON numbers overflow in JS beyond 2^53
+pub(crate) mod u256_dec_format {
+    use near_sdk::serde::{de, Deserialize, Deserializer, Serializer};
+    use primitive_types::U256;
+
+    pub fn serialize<S: Serializer>(num: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&num.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        let num = String::deserialize(deserializer)?;
+        U256::from_dec_str(&num).map_err(|_| de::Error::custom("Invalid U256 decimal string"))
+    }
+}
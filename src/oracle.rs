@@ -0,0 +1,67 @@
+use crate::misc::{compute_price, elapsed_ns, now_ns};
+use crate::{Contract, ContractExt};
+use near_sdk::json_types::U64;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+use primitive_types::U256;
+
+/// Observation of the cumulative Token1 price, TWAP between two observations is
+/// `(price_cumulative2 - price_cumulative1) / (block_timestamp2 - block_timestamp1)`
+///
+/// The accumulator wraps around on U256 overflow, so the difference must be computed with wrapping
+/// subtraction, which stays correct as long as observations are less than one wraparound apart
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceCumulativeView {
+    /// Sum of Token1 spot prices in the format of [Contract::get_marginal_price] scaled by `10^12`,
+    /// weighted by nanoseconds they were held for
+    #[serde(with = "crate::misc::u256_dec_format")]
+    pub price_cumulative: U256,
+    /// Timestamp of the last accumulation in nanoseconds
+    pub block_timestamp: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns the last cumulative Token1 price observation
+    pub fn get_price_cumulative(&self) -> PriceCumulativeView {
+        PriceCumulativeView {
+            price_cumulative: U256(self.price_cumulative_last),
+            block_timestamp: self.block_timestamp_last.into(),
+        }
+    }
+}
+
+impl Contract {
+    /// Accumulates Token1 spot price held since the last accumulation, must be called before reserves change
+    pub(crate) fn update_price_cumulative(&mut self) {
+        let now = now_ns();
+        let elapsed = elapsed_ns(self.block_timestamp_last, now);
+        if elapsed == 0 {
+            return;
+        }
+
+        let price = self
+            .token1_wallet
+            .as_ref()
+            .zip(self.token2_wallet.as_ref())
+            .and_then(|(token1_wallet, token2_wallet)| {
+                compute_price(
+                    token1_wallet.liquidity.into(),
+                    token1_wallet.price_decimals(),
+                    token2_wallet.liquidity.into(),
+                    token2_wallet.price_decimals(),
+                )
+                .ok()
+            });
+
+        // Empty pool has no price, so the period isn't accumulated. Overflow wraps, as consumers only use
+        // differences between observations
+        if let Some(price) = price {
+            let (price_cumulative, _) = U256(self.price_cumulative_last)
+                .overflowing_add(price.overflowing_mul(U256::from(elapsed)).0);
+            self.price_cumulative_last = price_cumulative.0;
+        }
+        self.block_timestamp_last = now;
+    }
+}
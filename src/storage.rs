@@ -3,6 +3,7 @@ use near_sdk::{BorshStorageKey, StorageUsage};
 
 pub(crate) const U128_STORAGE: StorageUsage = 16;
 pub(crate) const U64_STORAGE: StorageUsage = 8;
+pub(crate) const U32_STORAGE: StorageUsage = 4;
 pub(crate) const U8_STORAGE: StorageUsage = 1;
 
 /// Max length of account id [64 bytes]
@@ -27,7 +28,11 @@ const ENUM_STORAGE_KEY: StorageUsage = U8_STORAGE;
 ///
 /// - storage_balance: [U128_STORAGE]
 /// - storage_usage: [U64_STORAGE]
-pub(crate) const ACCOUNT_STORAGE: StorageUsage = U128_STORAGE + U64_STORAGE;
+/// - lp_shares: [U128_STORAGE]
+/// - balances: empty map length [U32_STORAGE]
+/// - lp_entry_value: [U128_STORAGE]
+pub(crate) const ACCOUNT_STORAGE: StorageUsage =
+    U128_STORAGE + U64_STORAGE + U128_STORAGE + U32_STORAGE + U128_STORAGE;
 
 /// Versioned user account size
 ///
@@ -41,7 +46,28 @@ pub(crate) const VACCOUNT_STORAGE: StorageUsage = U8_STORAGE + ACCOUNT_STORAGE;
 pub(crate) const REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE: StorageUsage =
     STORAGE_ENTRY + ENUM_STORAGE_KEY + ACCOUNT_ID_STORAGE + VACCOUNT_STORAGE;
 
+/// Token balance entry of the user account with maximum token id length
+///
+/// - key: token id [ACCOUNT_ID_STORAGE]
+/// - value: balance [U128_STORAGE]
+pub(crate) const BALANCE_ENTRY_STORAGE: StorageUsage = ACCOUNT_ID_STORAGE + U128_STORAGE;
+
+/// Maximum storage in bytes used by registered user account with maximum id length (64 symbols)
+///
+/// - initial storage [REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE]
+/// - balances of both pool tokens [BALANCE_ENTRY_STORAGE]
+pub(crate) const REGISTERED_MAX_ID_LENGTH_ACCOUNT_MAX_STORAGE: StorageUsage =
+    REGISTERED_MAX_ID_LENGTH_ACCOUNT_INITIAL_STORAGE + 2 * BALANCE_ENTRY_STORAGE;
+
 #[derive(BorshStorageKey, BorshSerialize)]
 pub(crate) enum StorageKey {
     Accounts,
+    SwapCount,
+    Blacklist,
+    CommittedSwaps,
+    LastSwaps,
+    SponsoredRegistrations,
+    SwapHistory,
+    AllowedTokens,
+    FailedSwaps,
 }
@@ -1,10 +1,10 @@
-use crate::account::{Account, VAccount};
+use crate::account::Account;
 use crate::{Contract, ContractExt};
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
 use near_sdk::json_types::U128;
-use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Promise};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance, Promise};
 
 #[near_bindgen]
 impl StorageManagement for Contract {
@@ -22,6 +22,8 @@ impl StorageManagement for Contract {
         }
 
         let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        // Registration sponsored by the pool is repaid first
+        let deposit_amount = self.repay_sponsored_registration(&account_id, deposit_amount);
         let registration_only = registration_only.unwrap_or(false);
 
         let account = match self.get_account(&account_id) {
@@ -31,18 +33,20 @@ impl StorageManagement for Contract {
                 account
             }
 
-            // if exists then update near_balance
+            // if exists then update near_balance up to maximum storage balance
             Ok(mut account) => {
-                account.storage_balance = account
+                let storage_balance = account
                     .storage_balance
                     .checked_add(deposit_amount)
                     .unwrap_or_else(|| env::panic_str("Storage balance overflow"));
+                account.storage_balance =
+                    self.refund_storage_excess(storage_balance, deposit_amount);
                 account
             }
 
             // if not exist and registration only then register and refund
             Err(_) if registration_only => {
-                let min_balance = self.storage_balance_bounds().min.into();
+                let min_balance = Account::required_deposit(Some(&account_id)).into();
 
                 let refund = deposit_amount.checked_sub(min_balance).unwrap_or_else(|| {
                     env::panic_str("Not enough minimum deposit to register account")
@@ -55,17 +59,19 @@ impl StorageManagement for Contract {
                 Account::new(&account_id, Some(min_balance))
             }
 
-            // else register account with all deposit
-            _ => Account::new(&account_id, Some(deposit_amount)),
+            // else register account with all deposit up to maximum storage balance
+            _ => Account::new(
+                &account_id,
+                Some(self.refund_storage_excess(deposit_amount, deposit_amount)),
+            ),
         };
 
-        let storage_balance = account.storage_balance();
-
-        self.accounts
-            .insert(&account_id, &VAccount::Current(account));
+        self.store_account(&account_id, account)
+            .unwrap_or_else(|e| env::panic_str(e));
 
         // return balance of account
-        storage_balance
+        self.storage_balance_of(account_id)
+            .unwrap_or_else(|| env::panic_str("Account is not registered"))
     }
 
     #[payable]
@@ -75,32 +81,28 @@ impl StorageManagement for Contract {
         self.assert_contract_running();
 
         let account_id = env::predecessor_account_id();
-        let mut account: Account = self
-            .get_account(&account_id)
-            .unwrap_or_else(|e| env::panic_str(e));
+        self.charge_sponsored_registration(&account_id);
 
-        let mut storage_balance = account.storage_balance();
-        // If amount not provided, use all available storage balance
-        let withdraw_amount = amount.unwrap_or(storage_balance.available).into();
+        let mut withdraw_amount = 0;
+        self.update_account(&account_id, |account| {
+            let available = account.storage_balance(&account_id).available;
+            // If amount not provided, use all available storage balance
+            withdraw_amount = amount.unwrap_or(available).into();
 
-        storage_balance.available = u128::from(storage_balance.available)
-            .checked_sub(withdraw_amount)
-            .unwrap_or_else(|| env::panic_str("Not enough available storage to withdraw"))
-            .into();
-        storage_balance.total = u128::from(storage_balance.total)
-            .checked_sub(withdraw_amount)
-            .unwrap_or_else(|| env::panic_str("Withdraw storage balance overflow"))
-            .into();
-
-        account.storage_balance = storage_balance.total.into();
+            if withdraw_amount > available.0 {
+                return Err("Not enough available storage to withdraw");
+            }
+            account.storage_balance -= withdraw_amount;
 
-        self.accounts
-            .insert(&account_id, &VAccount::Current(account));
+            Ok(())
+        })
+        .unwrap_or_else(|e| env::panic_str(e));
 
-        Promise::new(account_id).transfer(withdraw_amount);
+        Promise::new(account_id.clone()).transfer(withdraw_amount);
 
         // return balance of account
-        storage_balance
+        self.storage_balance_of(account_id)
+            .unwrap_or_else(|| env::panic_str("Account is not registered"))
     }
 
     #[payable]
@@ -121,23 +123,46 @@ impl StorageManagement for Contract {
                 "Unable to unregister a positive balance account without `force` set to `true`",
             ),
 
-            // Unregister account and transfer all funds
+            // LP shares can't be forfeited
+            Ok(account) if account.lp_shares > 0 => {
+                env::panic_str("Unable to unregister an account with LP shares")
+            }
+
+            // Token balances can't be forfeited
+            Ok(account) if !account.balances.is_empty() => {
+                env::panic_str("Unable to unregister an account with token balances")
+            }
+
+            // Escrowed input can't be forfeited
+            Ok(_) if self.committed_swaps.contains_key(&account_id) => {
+                env::panic_str("Unable to unregister an account with a committed swap")
+            }
+
+            // Unregister account with its swap records and transfer all funds
             Ok(account) => {
+                self.last_swaps.remove(&account_id);
+                self.swap_count.remove(&account_id);
                 self.accounts.remove(&account_id);
 
-                // Transfer storage amount
-                Promise::new(account_id).transfer(account.storage_balance);
+                // Transfer storage amount less the outstanding sponsored registration
+                let refund =
+                    self.repay_sponsored_registration(&account_id, account.storage_balance);
+                if refund > 0 {
+                    Promise::new(account_id).transfer(refund);
+                }
 
                 true
             }
         }
     }
 
-    /// Returns storage min/max bounds in $NEAR for account with maximum id length
+    /// Returns storage min/max bounds in $NEAR for account with maximum id length,
+    /// registration of a shorter id requires less, as of [Account::required_deposit]
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
             min: Account::required_deposit(None),
-            max: None,
+            // account storage grows with balances of both pool tokens at most
+            max: Some(Account::max_deposit()),
         }
     }
 
@@ -145,7 +170,117 @@ impl StorageManagement for Contract {
     fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
         self.assert_contract_running();
         self.get_account(&account_id)
-            .map(|account| account.storage_balance())
+            .map(|account| account.storage_balance(&account_id))
             .ok()
     }
 }
+
+#[near_bindgen]
+impl Contract {
+    /// Registers each of `account_ids` with its minimum storage balance in a single call,
+    /// already registered accounts are skipped and the remainder of the attached deposit is refunded
+    #[payable]
+    pub fn storage_deposit_batch(&mut self, account_ids: Vec<AccountId>) -> Vec<StorageBalance> {
+        self.assert_contract_running();
+
+        if account_ids.is_empty() {
+            env::panic_str("No accounts provided")
+        }
+
+        // Whatever isn't charged for the new accounts is refunded
+        let mut refund = env::attached_deposit();
+
+        let storage_balances = account_ids
+            .into_iter()
+            .map(|account_id| match self.get_account(&account_id) {
+                // Registered account keeps its balance
+                Ok(account) => account.storage_balance(&account_id),
+                Err(_) => {
+                    let min_balance: Balance = Account::required_deposit(Some(&account_id)).into();
+                    refund = refund.checked_sub(min_balance).unwrap_or_else(|| {
+                        env::panic_str("Not enough deposit to register all accounts")
+                    });
+
+                    self.store_account(&account_id, Account::new(&account_id, Some(min_balance)))
+                        .unwrap_or_else(|e| env::panic_str(e));
+                    self.get_account(&account_id)
+                        .unwrap_or_else(|e| env::panic_str(e))
+                        .storage_balance(&account_id)
+                }
+            })
+            .collect();
+
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+
+        storage_balances
+    }
+}
+
+impl Contract {
+    /// Charges the outstanding registration the pool sponsored for `account_id` to its available
+    /// storage balance, the remainder stays outstanding until the account repays it
+    pub(crate) fn charge_sponsored_registration(&mut self, account_id: &AccountId) {
+        let available = match self.get_account(account_id) {
+            Ok(account) => account.storage_balance(account_id).available.0,
+            Err(_) => return,
+        };
+
+        let charged = available - self.repay_sponsored_registration(account_id, available);
+        if charged > 0 {
+            self.update_account(account_id, |account| {
+                account.storage_balance -= charged;
+
+                Ok(())
+            })
+            .unwrap_or_else(|e| env::panic_str(e));
+        }
+    }
+
+    /// Repays the outstanding registration the pool sponsored for `account_id` out of `amount`,
+    /// returns the rest of `amount`
+    pub(crate) fn repay_sponsored_registration(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let sponsored = self
+            .sponsored_registrations
+            .get(account_id)
+            .unwrap_or_default();
+        let repaid = sponsored.min(amount);
+
+        match sponsored - repaid {
+            0 if sponsored > 0 => {
+                self.sponsored_registrations.remove(account_id);
+            }
+            0 => (),
+            outstanding => {
+                self.sponsored_registrations
+                    .insert(account_id, &outstanding);
+            }
+        }
+
+        amount - repaid
+    }
+
+    /// Caps `storage_balance` at maximum storage balance bound and refunds the excess
+    /// of the attached `deposit_amount` to the caller
+    fn refund_storage_excess(&self, storage_balance: Balance, deposit_amount: Balance) -> Balance {
+        let max_balance = match self.storage_balance_bounds().max {
+            Some(max_balance) => max_balance.0,
+            None => return storage_balance,
+        };
+
+        let refund = storage_balance
+            .saturating_sub(max_balance)
+            .min(deposit_amount);
+
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+
+        storage_balance - refund
+    }
+}
@@ -1,8 +1,29 @@
-use crate::tokens::{PoolView, TransferCommand, TransferType};
+use crate::account::Account as ContractAccount;
+use crate::contract::{
+    Activity, ContractConfig, ContractConfigPatch, ContractStatus, OwnerCapabilities,
+};
+use crate::escrow::FailedSwap;
+use crate::history::{SwapRecord, SWAP_HISTORY_SIZE};
+use crate::misc::{
+    add_fee, ceil_div, compute_amount_in, compute_amount_out, compute_amount_to_price,
+    compute_price, compute_tokens_ratio, deduct_fee, elapsed_ns, emit_event, format_price,
+    is_price_preserved, is_ratio_preserved, parse_price, Event, FeeMode, RunningState, MAX_BPS,
+};
+use crate::oracle::PriceCumulativeView;
+use crate::tokens::{
+    BalanceObservation, DepositEvent, LastSwap, LpState, PoolView, SwapAmounts, SwapEvent,
+    SwapProvider, SwapStatus, TokenWallet, TransferCommand, TransferType,
+};
 use assert_matches::assert_matches;
+use near_contract_standards::fungible_token::metadata::{FungibleTokenMetadata, FT_METADATA_SPEC};
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
+use near_sdk::mock::VmAction;
 use near_sdk::serde_json::{self, json};
-use near_sdk::{ONE_NEAR, ONE_YOCTO};
+use near_sdk::test_utils::{get_created_receipts, get_logs, VMContextBuilder};
+use near_sdk::{PromiseOrValue, ONE_NEAR, ONE_YOCTO};
 use near_units::parse_near;
 use primitive_types::U256;
 use std::str::FromStr;
@@ -68,6 +89,46 @@ async fn test_create_wallets() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_create_wallets_failure_refund() -> anyhow::Result<()> {
+    let worker_testnet = workspaces::testnet_archival().await?;
+    let worker = workspaces::sandbox().await?;
+
+    let usdn_token_id = "usdn.testnet".parse()?;
+    let _ = worker
+        .import_contract(&usdn_token_id, &worker_testnet)
+        .initial_balance(parse_near!("10000000 N"))
+        .with_data()
+        .block_height(82_000_000)
+        .transact()
+        .await?;
+
+    // Token2 is a plain account without fungible token contract, so its metadata fetch fails
+    let not_a_token = gen_user_account(&worker, "not_a_token.test.near").await?;
+
+    let owner = gen_user_account(&worker, "owner.test.near").await?;
+    let contract = build_contract(
+        &worker,
+        "./",
+        json!({
+          "owner_id": owner.id(),
+        }),
+    )
+    .await?;
+
+    let balance_before = owner.view_account().await?.balance;
+    create_token_wallets(&contract, &owner, &usdn_token_id, not_a_token.id()).await?;
+    let balance_after = owner.view_account().await?.balance;
+
+    // Only gas and Token1 wallet registration are paid, the rest of 2 NEAR is refunded
+    assert!(balance_before - balance_after < ONE_NEAR / 10);
+
+    // No partial state persists
+    assert!(get_pool_view(&contract).await.is_err());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_liquidity() -> anyhow::Result<()> {
     let worker_testnet = workspaces::testnet_archival().await?;
@@ -99,6 +160,7 @@ async fn test_liquidity() -> anyhow::Result<()> {
         json!({
           "owner_id": owner.id(),
           "tokens": (usdn_token_id, usdt_token_id),
+          "fee_bps": 0,
         }),
     )
     .await?;
@@ -166,9 +228,24 @@ async fn test_liquidity() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [(50_000 * ONE_USDT).into(), (50_000 * ONE_USDN).into()] && ratio == U256::from(2_500_000_000_000_000_000_000u128).to_string()
+        } if amounts == [(50_000 * ONE_USDT).into(), (50_000 * ONE_USDN).into()] && ratio == U256::from(2_500_000_000_000_000_000_000u128)
     );
 
+    // Disproportionate liquidity is rejected unless the owner loosens the ratio deviation
+    assert!(add_liquidity(
+        &contract,
+        &owner,
+        [(500 * ONE_USDT).into(), (100 * ONE_USDN).into()],
+    )
+    .await
+    .is_err());
+    update_config(
+        &contract,
+        &owner,
+        json!({ "max_add_deviation_bps": 10_000 }),
+    )
+    .await?;
+
     // Add some liquidity to the contract swap pool using an owner's deposit
     add_liquidity(
         &contract,
@@ -192,7 +269,7 @@ async fn test_liquidity() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [(50_350 * ONE_USDT).into(), (50_090 * ONE_USDN).into()] && ratio == U256::from(2_522_031_500_000_000_000_000u128).to_string()
+        } if amounts == [(50_350 * ONE_USDT).into(), (50_090 * ONE_USDN).into()] && ratio == U256::from(2_522_031_500_000_000_000_000u128)
     );
 
     Ok(())
@@ -327,7 +404,7 @@ async fn test_swap_usdn_usdt() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [(51_000 * ONE_USDN).into(), 49_019_607_843.into()] && ratio == U256::from(2_499_999_999_993_000_000_000u128).to_string()
+        } if amounts == [(51_000 * ONE_USDN).into(), 49_019_607_844.into()] && ratio == U256::from(2_500_000_000_044_000_000_000u128)
     );
 
     // Swap 1000 USDT for USDN
@@ -346,7 +423,7 @@ async fn test_swap_usdn_usdt() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [49_937_933_851.into(), 50_019_607_843.into()] && ratio == U256::from(2_497_875_867_716_694_793_393u128).to_string()
+        } if amounts == [49_980_399_844.into(), 50_019_607_844.into()] && ratio == U256::from(2_500_000_000_083_198_776_336u128)
     );
 
     Ok(())
@@ -386,6 +463,7 @@ async fn test_swap_eth_usdt() -> anyhow::Result<()> {
         json!({
           "owner_id": owner.id(),
           "tokens": (eth_token_id, usdt_token_id),
+          "fee_bps": 0,
         }),
     )
     .await?;
@@ -464,7 +542,7 @@ async fn test_swap_eth_usdt() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [(51 * ONE_ETH).into(), 98_039_215_686.into()] && ratio == U256::from(4_999_999_999_986_000_000_000_000_000_000u128).to_string()
+        } if amounts == [(51 * ONE_ETH).into(), 98_039_215_687.into()] && ratio == U256::from(5_000_000_000_037_000_000_000_000_000_000u128) * U256::exp10(12)
     );
 
     // Swap 2000 USDT for ETH
@@ -483,199 +561,6618 @@ async fn test_swap_eth_usdt() -> anyhow::Result<()> {
             amounts,
             ratio,
             ..
-        } if amounts == [49_937_933_850_548_209_693.into(), 100_039_215_686.into()] && ratio == U256::from(4_995_751_735_388_192_838_819_182_844_398u128).to_string()
+        } if amounts == [49_980_399_843_206_139_790.into(), 100_039_215_687.into()] && ratio == U256::from(5_000_000_000_037_000_000_054_482_885_730u128) * U256::exp10(12)
     );
 
     Ok(())
 }
 
-pub(crate) async fn gen_user_account<T>(
-    worker: &Worker<T>,
-    account_id: &str,
-) -> anyhow::Result<Account>
-where
-    T: DevNetwork + Send + Sync,
-{
-    let id = workspaces::AccountId::from_str(account_id)?;
-    let sk = SecretKey::from_random(KeyType::ED25519);
-
-    let account = worker.create_tla(id, sk).await?.into_result()?;
-
-    Ok(account)
-}
+#[tokio::test]
+async fn test_max_deposit_per_account() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
 
-pub(crate) async fn build_contract<T>(
-    worker: &Worker<T>,
-    project_path: &str,
-    args_json: serde_json::Value,
-) -> anyhow::Result<Contract>
-where
-    T: NetworkInfo + NetworkClient + DevNetwork + Send + Sync,
-{
-    let wasm = workspaces::compile_project(project_path).await?;
-    let (id, sk) = worker.dev_generate().await;
+    // Limit USDN deposit per account
+    set_max_deposit_per_account(
+        &pool.contract,
+        &pool.owner,
+        pool.usdn_contract.id(),
+        Some((1_000 * ONE_USDN).into()),
+    )
+    .await?;
 
-    let contract = worker
-        .create_tla_and_deploy(id, sk, &wasm)
+    // Register the user with the storage for token balances
+    pool.user
+        .call(pool.contract.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(ONE_NEAR)
+        .max_gas()
+        .transact()
         .await?
         .into_result()?;
 
-    // initialize contract
-    let res = contract
-        .call("init")
-        .args_json(args_json)
-        .max_gas()
-        .transact()
-        .await;
+    // Deposit by a registered non-owner exceeding the cap is refunded
+    let logs = deposit_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (2_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(
+        logs.contains(&"Transfer failed. Error: Deposit exceeds maximum per account".to_string())
+    );
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
 
-    match res {
-        Ok(_) => (),
-        Err(_) => {
-            return Err(anyhow::Error::msg(format!(
-                "Failed to build & init contract. Log {:?}",
-                res
-            )))
-        }
-    };
+    // Deposit by a registered non-owner within the cap is credited to its balance
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+    assert_eq!(
+        get_account_balance(&pool.contract, &pool.user, pool.usdn_contract.id()).await?,
+        (1_000 * ONE_USDN).into()
+    );
 
-    Ok(contract)
-}
+    // Deposit by an owner exceeding the cap is refunded
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (2_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.owner).await?,
+        (100_000 * ONE_USDN).into()
+    );
 
-async fn register_user(target_contract: &Contract, user: &Account) -> anyhow::Result<()> {
-    let res = user
-        .call(target_contract.id(), "storage_deposit")
-        .args_json(json!({
-            "registration_only": true,
-        }))
-        .deposit(ONE_NEAR)
-        .max_gas()
-        .transact()
-        .await?;
+    // Deposit within the cap is accepted
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.owner).await?,
+        (99_000 * ONE_USDN).into()
+    );
 
-    match res.clone().into_result() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg(format!(
-            "Registration of user `{:?}` failed on target contract `{:?}`. Log {:?}",
-            user.id(),
-            target_contract.id(),
-            res
-        ))),
-    }
+    // Deposit of a token without the cap is accepted
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (2_000 * ONE_USDT).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.owner).await?,
+        (198_000 * ONE_USDT).into()
+    );
+
+    Ok(())
 }
 
-async fn mint_tokens(
-    token_contract: &Contract,
-    user: &Account,
-    amount: U128,
-) -> anyhow::Result<()> {
-    let res = user
-        .call(token_contract.id(), "mint")
+#[tokio::test]
+async fn test_internal_methods_are_private() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    // Swap without transferring the input is rejected
+    let res = pool
+        .user
+        .call(pool.contract.id(), "swap_tokens")
         .args_json(json!({
-            "account_id": user.id(),
-            "amount": amount,
+            "sender_id": pool.user.id(),
+            "token_id_in": pool.usdn_contract.id(),
+            "amount_in": 1_000 * ONE_USDN,
+            "min_amount_out": null,
+            "exact_amount_out": null,
+            "max_price_impact_bps": null,
         }))
         .max_gas()
         .transact()
         .await?;
+    assert!(res.is_failure());
 
-    match res.clone().into_result() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg(format!(
-            "Mint token `{:?}` for user `{:?}` failed. Log {:?}",
-            token_contract.id(),
-            user.id(),
-            res
-        ))),
-    }
-}
-
-async fn exchange_usdt_for_usdn(
-    usdn_contract: &Contract,
-    user: &Account,
-    amount: U128,
-) -> anyhow::Result<()> {
-    let res = usdn_contract
-        .call("ft_transfer")
-        .args_json(json!({
-            "receiver_id": user.id(),
-            "amount": amount,
-            "msg": "",
-        }))
+    // Wallet creation spending the pool's $NEAR is rejected
+    let res = pool
+        .user
+        .call(pool.contract.id(), "create_token_wallet")
+        .args_json(json!({ "token": pool.usdn_contract.id() }))
         .max_gas()
-        .deposit(ONE_YOCTO)
         .transact()
         .await?;
+    assert!(res.is_failure());
 
-    match res.clone().into_result() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg(format!(
-            "Exchange USDT to USDN for user `{:?}` failed. Log {:?}",
-            user.id(),
-            res
-        ))),
-    }
+    Ok(())
 }
 
-async fn deposit_tokens(
-    token_contract: &Contract,
-    sender: &Account,
-    receiver: &Account,
-    amount: U128,
-) -> anyhow::Result<()> {
-    let res = sender
-        .call(token_contract.id(), "ft_transfer_call")
-        .args_json(json!({
-            "receiver_id": receiver.id(),
-            "amount": amount,
-            "msg": "",
-        }))
-        .max_gas()
-        .deposit(ONE_YOCTO)
-        .transact()
-        .await?;
+#[tokio::test]
+async fn test_swap_count() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+    // Swap count is stored at the account's expense
+    register_pool_account(&pool.contract, &pool.user).await?;
 
-    match res.clone().into_result() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg(format!(
-            "Deposit tokens `{:?}` by user `{:?}` to user `{:?}` failed. Log {:?}",
-            token_contract.id(),
-            sender.id(),
-            receiver.id(),
-            res
-        ))),
-    }
-}
+    // Swap against an empty pool fails and is refunded
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+    assert_eq!(get_swap_count(&pool.contract, &pool.user).await?, 0);
 
-async fn swap_tokens(
-    token_contract: &Contract,
-    sender: &Account,
-    receiver: &Account,
-    amount: U128,
-) -> anyhow::Result<()> {
-    let res = sender
-        .call(token_contract.id(), "ft_transfer_call")
-        .args_json(json!({
-            "receiver_id": receiver.id(),
-            "amount": amount,
-            "msg": serde_json::to_string(&TransferCommand { r#type: TransferType::Swap }).unwrap(),
-        }))
-        .max_gas()
-        .deposit(ONE_YOCTO)
-        .transact()
-        .await?;
+    // Provide liquidity
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
 
-    match res.clone().into_result() {
-        Ok(_) => Ok(()),
-        Err(_) => Err(anyhow::Error::msg(format!(
-            "Swap tokens `{:?}` by user `{:?}` at pool `{:?}` failed. Log {:?}",
-            token_contract.id(),
-            sender.id(),
-            receiver.id(),
-            res
-        ))),
-    }
-}
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(get_swap_count(&pool.contract, &pool.user).await?, 1);
+    assert_eq!(get_swap_count(&pool.contract, &pool.owner).await?, 0);
 
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_update_config() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    // Cap both tokens deposits in a single call
+    update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({
+            "max_deposit_per_account": [(1_000 * ONE_USDN).to_string(), (2_000 * ONE_USDT).to_string()],
+        }),
+    )
+    .await?;
+
+    // Deposits exceeding caps are refunded
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (1_500 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (2_500 * ONE_USDT).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.owner).await?,
+        (100_000 * ONE_USDN).into()
+    );
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.owner).await?,
+        (200_000 * ONE_USDT).into()
+    );
+
+    // Empty patch keeps caps untouched
+    update_config(&pool.contract, &pool.owner, json!({})).await?;
+
+    // Deposits within caps are accepted
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (2_000 * ONE_USDT).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.owner).await?,
+        (198_000 * ONE_USDT).into()
+    );
+
+    // Fees & swap cap are validated like by their setters
+    for patch in [
+        json!({ "fee_bps": MAX_BPS + 1 }),
+        json!({ "protocol_fee_bps": MAX_BPS + 1 }),
+        json!({ "max_swap_bps": MAX_BPS + 1 }),
+    ] {
+        assert!(update_config(&pool.contract, &pool.owner, patch)
+            .await
+            .is_err());
+    }
+
+    update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({ "fee_bps": 50, "protocol_fee_bps": 1_000, "max_swap_bps": 500 }),
+    )
+    .await?;
+    let config = pool
+        .contract
+        .view("get_config")
+        .args_json(json!({}))
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(config["fee_bps"], json!(50));
+    assert_eq!(config["protocol_fee_bps"], json!(1_000));
+    assert_eq!(config["max_swap_bps"], json!(500));
+
+    // `null` removes the swap cap, while a missing field keeps it
+    update_config(&pool.contract, &pool.owner, json!({ "fee_bps": 30 })).await?;
+    update_config(&pool.contract, &pool.owner, json!({ "max_swap_bps": null })).await?;
+    let config = pool
+        .contract
+        .view("get_config")
+        .args_json(json!({}))
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(config["fee_bps"], json!(30));
+    assert_eq!(config["max_swap_bps"], json!(null));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_pool_not_ready() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool_with(json!({ "tokens": null })).await?;
+
+    // Swap before wallets are created is refunded with a clear reason
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Pool not ready"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_decimals() -> anyhow::Result<()> {
+    let worker_testnet = workspaces::testnet_archival().await?;
+    let worker = workspaces::sandbox().await?;
+
+    let usdt_token_id = "usdt.fakes.testnet".parse()?;
+    let _ = worker
+        .import_contract(&usdt_token_id, &worker_testnet)
+        .initial_balance(parse_near!("10000000 N"))
+        .with_data()
+        .block_height(72_000_000)
+        .transact()
+        .await?;
+
+    let eth_token_id = "eth.fakes.testnet".parse()?;
+    let _ = worker
+        .import_contract(&eth_token_id, &worker_testnet)
+        .initial_balance(parse_near!("10000000 N"))
+        .with_data()
+        .block_height(82_000_000)
+        .transact()
+        .await?;
+
+    let owner = gen_user_account(&worker, "owner.test.near").await?;
+    let contract = build_contract(
+        &worker,
+        "./",
+        json!({
+          "owner_id": owner.id(),
+          "tokens": (&eth_token_id, &usdt_token_id),
+        }),
+    )
+    .await?;
+
+    assert_eq!(get_decimals(&contract, &eth_token_id).await?, 18);
+    assert_eq!(get_decimals(&contract, &usdt_token_id).await?, 6);
+    assert!(get_decimals(&contract, owner.id()).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_activity() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    let activity = get_activity(&pool.contract).await?;
+    assert!(activity.last_swap_ts.is_none() && activity.last_liquidity_ts.is_none());
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let activity = get_activity(&pool.contract).await?;
+    assert_matches!(
+        activity,
+        Activity {
+            created_ts,
+            last_swap_ts: None,
+            last_liquidity_ts: Some(last_liquidity_ts),
+        } if last_liquidity_ts.0 > created_ts.0
+    );
+
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+
+    let activity = get_activity(&pool.contract).await?;
+    assert_matches!(
+        activity,
+        Activity {
+            last_swap_ts: Some(last_swap_ts),
+            last_liquidity_ts: Some(last_liquidity_ts),
+            ..
+        } if last_swap_ts.0 > last_liquidity_ts.0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_is_price_preserved() {
+    // Proportional add and remove keep the price
+    assert!(is_price_preserved(
+        [50_000 * ONE_USDN, 25_000 * ONE_USDT],
+        [51_000 * ONE_USDN, 25_500 * ONE_USDT]
+    ));
+    assert!(is_price_preserved(
+        [50_000 * ONE_USDN, 25_000 * ONE_USDT],
+        [40_000 * ONE_USDN, 20_000 * ONE_USDT]
+    ));
+
+    // Single unit rounding is tolerated
+    assert!(is_price_preserved([3, 7], [6, 15]));
+    assert!(is_price_preserved([3, 7], [7, 14]));
+
+    // Disproportional add moves the price
+    assert!(!is_price_preserved(
+        [50_000 * ONE_USDN, 25_000 * ONE_USDT],
+        [51_000 * ONE_USDN, 25_000 * ONE_USDT]
+    ));
+
+    // Seeding an empty pool has no price to preserve
+    assert!(is_price_preserved([0, 0], [ONE_USDN, 2 * ONE_USDT]));
+}
+
+#[tokio::test]
+async fn test_get_amount_out_at() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (40_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (40_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let reserves = get_pool_view(&pool.contract).await?.amounts;
+
+    for (token_id, amount_in) in [
+        (pool.usdn_contract.id(), 1_000 * ONE_USDN),
+        (pool.usdt_contract.id(), 1_000 * ONE_USDT),
+    ] {
+        let amount_out = get_amount_out(&pool.contract, token_id, amount_in.into(), None).await?;
+        let amount_out_at =
+            get_amount_out(&pool.contract, token_id, amount_in.into(), Some(reserves)).await?;
+
+        assert_eq!(amount_out, amount_out_at);
+    }
+
+    // Hypothetical reserves produce a different quote
+    let amount_out_at = get_amount_out(
+        &pool.contract,
+        pool.usdn_contract.id(),
+        (1_000 * ONE_USDN).into(),
+        Some([(100_000 * ONE_USDN).into(), (100_000 * ONE_USDT).into()]),
+    )
+    .await?;
+    assert_eq!(amount_out_at, U128(990_099_009));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_memo() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let res = pool
+        .owner
+        .call(pool.contract.id(), "set_transfer_memo")
+        .args_json(json!({
+            "token_id": pool.usdt_contract.id(),
+            "memo": "compliance-ref-42",
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(res.is_success(), "Set transfer memo failed. Log {:?}", res);
+
+    // Output USDT transfer carries the memo, recorded by the token transfer event
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log.starts_with("EVENT_JSON:") && log.contains("compliance-ref-42")));
+
+    Ok(())
+}
+
+#[test]
+fn test_emit_event() {
+    near_sdk::testing_env!(VMContextBuilder::new().build());
+
+    emit_event("test", json!({ "amount": "1" }));
+    assert_eq!(
+        get_logs(),
+        vec![
+            r#"EVENT_JSON:{"standard":"swap_pool","version":"1.0.0","event":"test","data":[{"amount":"1"}]}"#
+        ]
+    );
+}
+
+#[test]
+fn test_elapsed_ns() {
+    assert_eq!(elapsed_ns(100, 250), 150);
+    assert_eq!(elapsed_ns(250, 250), 0);
+    assert_eq!(elapsed_ns(0, u64::MAX), u64::MAX);
+
+    // Timestamp ahead of now never underflows
+    assert_eq!(elapsed_ns(251, 250), 0);
+    assert_eq!(elapsed_ns(u64::MAX, 0), 0);
+}
+
+#[tokio::test]
+async fn test_utilization() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // All deposit is provided as liquidity
+    assert_eq!(
+        get_utilization(&pool.contract).await?,
+        ["100.00".to_string(), "100.00".to_string()]
+    );
+
+    // Idle deposit lowers utilization
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (25_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        get_utilization(&pool.contract).await?,
+        ["66.66".to_string(), "100.00".to_string()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_get_version() {
+    let contract = crate::Contract::init(None, None, None, None, None, None);
+
+    let version = contract.get_version();
+    let (crate_version, schema_version) = version.split_once("+schema.").unwrap();
+    assert_eq!(crate_version, env!("CARGO_PKG_VERSION"));
+    assert!(crate_version
+        .split('.')
+        .all(|part| part.parse::<u32>().is_ok()));
+    assert_eq!(crate_version.split('.').count(), 3);
+    assert_eq!(
+        schema_version.parse::<u32>(),
+        Ok(crate::CONTRACT_SCHEMA_VERSION)
+    );
+}
+
+#[test]
+fn test_get_swap_tokens_wallets_mut() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = ONE_USDN.into();
+    token2_wallet.liquidity = (2 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    let (token_wallet_in, token_wallet_out, token1_is_input) = contract
+        .get_swap_tokens_wallets_mut(&"usdt.fakes.testnet".parse().unwrap())
+        .unwrap();
+    assert_eq!(token_wallet_in.liquidity, (2 * ONE_USDT).into());
+    assert_eq!(token_wallet_out.liquidity, ONE_USDN.into());
+    assert!(!token1_is_input);
+
+    // Unrelated token is rejected rather than routed to Token2
+    for token_id in ["eth.fakes.testnet", "usdt.fakes.testnet.x", "usdn.testne"] {
+        assert_eq!(
+            contract
+                .get_swap_tokens_wallets_mut(&token_id.parse().unwrap())
+                .err(),
+            Some("Token is not supported")
+        );
+    }
+}
+
+#[test]
+fn test_arbitrage_opportunity() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (100_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (100_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    assert_eq!(
+        contract.arbitrage_opportunity("1".to_string()).err(),
+        Some("Pool is at the reference price")
+    );
+
+    // USDN is cheaper elsewhere, so it's sold to the pool
+    let arb_info = contract.arbitrage_opportunity("0.98".to_string()).unwrap();
+    assert_eq!(arb_info.token_in.as_str(), "usdn.testnet");
+    assert_eq!(arb_info.token_out.as_str(), "usdt.fakes.testnet");
+    assert!(arb_info.amount_in.0 > 0 && arb_info.amount_out.0 > 0);
+    assert!(arb_info.expected_profit.0 > 0);
+
+    // USDN is pricier elsewhere, so it's bought from the pool
+    let arb_info = contract.arbitrage_opportunity("1.02".to_string()).unwrap();
+    assert_eq!(arb_info.token_in.as_str(), "usdt.fakes.testnet");
+    assert_eq!(arb_info.token_out.as_str(), "usdn.testnet");
+    assert!(arb_info.amount_in.0 > 0 && arb_info.expected_profit.0 > 0);
+
+    // The arbitrage swap moves the pool to the reference price
+    let token2_wallet = contract.token2_wallet.as_mut().unwrap();
+    token2_wallet.liquidity = (token2_wallet.liquidity.0 + arb_info.amount_in.0).into();
+    let token1_wallet = contract.token1_wallet.as_mut().unwrap();
+    token1_wallet.liquidity = (token1_wallet.liquidity.0 - arb_info.amount_out.0).into();
+    let price = contract
+        .get_marginal_price("usdn.testnet".parse().unwrap())
+        .unwrap();
+    assert!(price.starts_with("1.0199") || price.starts_with("1.0200"));
+}
+
+#[test]
+fn test_get_spread_bps() {
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (100_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (100_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    assert_eq!(
+        contract.get_spread_bps(0.into()),
+        Err("Notional must be positive")
+    );
+
+    // Fee-free spread is slippage only, which grows with notional
+    let spreads = [ONE_USDN, 1_000 * ONE_USDN, 10_000 * ONE_USDN]
+        .map(|notional| contract.get_spread_bps(notional.into()).unwrap());
+    assert_eq!(spreads[0], 0);
+    assert!(spreads[0] < spreads[1] && spreads[1] < spreads[2]);
+
+    // Fee is paid on both legs of the round trip
+    contract.fee_bps = 30;
+    let spreads_with_fee = [ONE_USDN, 1_000 * ONE_USDN, 10_000 * ONE_USDN]
+        .map(|notional| contract.get_spread_bps(notional.into()).unwrap());
+    assert!((59..=60).contains(&spreads_with_fee[0]));
+    for (spread, spread_with_fee) in spreads.iter().zip(spreads_with_fee) {
+        assert!(spread_with_fee > *spread);
+    }
+}
+
+#[test]
+fn test_get_amount_out_net() {
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet(usdn.as_str(), 6));
+    contract.token2_wallet = Some(test_token_wallet("usdt.fakes.testnet", 6));
+    contract.token1_wallet.as_mut().unwrap().liquidity = (1_000 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (1_000 * ONE_USDT).into();
+
+    let amount_out = contract
+        .get_amount_out(usdn.clone(), (10 * ONE_USDN).into())
+        .unwrap();
+    assert_eq!(
+        contract.get_amount_out_net(usdn.clone(), (10 * ONE_USDN).into(), ONE_USDT.into()),
+        Ok((amount_out.0 - ONE_USDT).into())
+    );
+    assert_eq!(
+        contract.get_amount_out_net(usdn.clone(), (10 * ONE_USDN).into(), 0.into()),
+        Ok(amount_out)
+    );
+
+    // Gas cost above the output is clamped at zero
+    assert_eq!(
+        contract.get_amount_out_net(usdn, (10 * ONE_USDN).into(), (20 * ONE_USDT).into()),
+        Ok(0.into())
+    );
+    assert!(contract
+        .get_amount_out_net(
+            "unknown.testnet".parse().unwrap(),
+            ONE_USDN.into(),
+            0.into()
+        )
+        .is_err());
+}
+
+#[test]
+fn test_get_swap_estimate() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet("usdn.testnet", 6));
+    contract.token2_wallet = Some(test_token_wallet("usdt.fakes.testnet", 6));
+
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    assert_eq!(
+        contract.get_swap_estimate(usdn.clone(), ONE_USDN.into()),
+        Err("Pool is empty")
+    );
+    assert_eq!(
+        contract.get_swap_estimate("eth.fakes.testnet".parse().unwrap(), ONE_USDN.into()),
+        Err("Token is not supported")
+    );
+
+    contract.token1_wallet.as_mut().unwrap().liquidity = (1_000 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (1_000 * ONE_USDT).into();
+
+    // Fee is deducted from the input before pricing
+    assert_eq!(
+        contract.get_swap_estimate(usdn.clone(), (10 * ONE_USDN).into()),
+        Ok(compute_amount_out(
+            1_000 * ONE_USDN,
+            6,
+            1_000 * ONE_USDT,
+            6,
+            deduct_fee(10 * ONE_USDN, 30).unwrap()
+        )
+        .unwrap()
+        .into())
+    );
+
+    // Output is capped by the reserve buffer, unlike the bare tokens ratio quote
+    assert_eq!(
+        contract.get_swap_estimate(usdn, u128::MAX.into()),
+        Ok((1_000 * ONE_USDT - 1).into())
+    );
+}
+
+#[test]
+fn test_quote_swap_exact_out() {
+    let mut token_wallet_in = test_token_wallet("usdn.testnet", 6);
+    let mut token_wallet_out = test_token_wallet("usdt.fakes.testnet", 6);
+    token_wallet_in.liquidity = 1_000_003.into();
+    token_wallet_out.liquidity = 999_999.into();
+
+    for amount_out in [1, 7, 1_000, 333_333, 999_998] {
+        let quote = token_wallet_in
+            .quote_swap_exact_out(
+                &token_wallet_out,
+                u128::MAX,
+                amount_out,
+                30,
+                FeeMode::OnInput,
+            )
+            .unwrap();
+        assert_eq!(quote.amount_out, amount_out);
+        assert_eq!(quote.amount_unused, u128::MAX - quote.amount_in);
+
+        // Priced input buys at least the exact output, so the pool never loses value
+        assert!(
+            compute_amount_out(1_000_003, 6, 999_999, 6, quote.amount_in_priced).unwrap()
+                >= amount_out
+        );
+    }
+
+    assert!(token_wallet_in
+        .quote_swap_exact_out(&token_wallet_out, u128::MAX, 999_999, 30, FeeMode::OnInput)
+        .is_err());
+}
+
+#[test]
+fn test_swap_normalized_decimals() {
+    let mut eth_wallet = test_token_wallet("eth.fakes.testnet", 18);
+    let mut usdt_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    eth_wallet.liquidity = (50 * ONE_ETH).into();
+    usdt_wallet.liquidity = (100_000 * ONE_USDT).into();
+
+    // Both reserves are 18 decimals amounts once normalized
+    let eth_reserve = U256::from(50 * ONE_ETH);
+    let usdt_reserve = U256::from(100_000 * ONE_USDT) * U256::exp10(12);
+    let ratio = eth_reserve * usdt_reserve;
+    assert_eq!(
+        compute_tokens_ratio(50 * ONE_ETH, 18, 100_000 * ONE_USDT, 6),
+        Ok(ratio)
+    );
+
+    // Swap 1 ETH for USDT
+    let quote = eth_wallet
+        .quote_swap(&usdt_wallet, ONE_ETH, 0, FeeMode::OnInput)
+        .unwrap();
+    let usdt_reserve_after = ceil_div(ratio, eth_reserve + U256::from(ONE_ETH)).unwrap();
+    let expected_amount_out = (usdt_reserve - usdt_reserve_after) / U256::exp10(12);
+    assert_eq!(U256::from(quote.amount_out), expected_amount_out);
+    assert_eq!(quote.amount_out, 1_960_784_313);
+
+    // Swap 2000 USDT for ETH
+    let quote = usdt_wallet
+        .quote_swap(&eth_wallet, 2_000 * ONE_USDT, 0, FeeMode::OnInput)
+        .unwrap();
+    let eth_reserve_after = ceil_div(
+        ratio,
+        usdt_reserve + U256::from(2_000 * ONE_USDT) * U256::exp10(12),
+    )
+    .unwrap();
+    assert_eq!(
+        U256::from(quote.amount_out),
+        eth_reserve - eth_reserve_after
+    );
+
+    // Effective price of the swap is close to the 2000 USDT/ETH pool price
+    assert_eq!(quote.amount_out / (ONE_ETH / 1_000), 980);
+}
+
+#[test]
+fn test_compute_amount_in() {
+    assert_eq!(compute_amount_in(10, 0, 10, 0, 9), Ok(90));
+    assert_eq!(compute_amount_out(10, 0, 10, 0, 90), Ok(9));
+
+    // Required input always produces at least requested output
+    for (reserves, decimals, amount_out) in [
+        (
+            [50_000 * ONE_USDN, 50_000 * ONE_USDT],
+            [6, 6],
+            1_000 * ONE_USDT,
+        ),
+        ([51 * ONE_ETH, 98_039 * ONE_USDT], [18, 6], 3_333 * ONE_USDT),
+        ([98_039 * ONE_USDT, 51 * ONE_ETH], [6, 18], ONE_ETH / 3),
+        ([7, 1_000_003], [0, 0], 999_999),
+        ([7, 1_000_003], [0, 3], 999_999),
+    ] {
+        let amount_in = compute_amount_in(
+            reserves[0],
+            decimals[0],
+            reserves[1],
+            decimals[1],
+            amount_out,
+        )
+        .unwrap();
+        assert!(
+            compute_amount_out(
+                reserves[0],
+                decimals[0],
+                reserves[1],
+                decimals[1],
+                amount_in
+            )
+            .unwrap()
+                >= amount_out
+        );
+    }
+
+    // Whole output reserve can't be bought
+    assert!(compute_amount_in(10, 0, 10, 0, 10).is_err());
+}
+
+#[test]
+fn test_transfer_command_unknown_field_refund() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (50_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (50_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+
+    // Misspelled field is neither swapped nor deposited, but refunded in full
+    let res = contract.ft_on_transfer(
+        "user.near".parse().unwrap(),
+        (100 * ONE_USDN).into(),
+        r#"{"type":"swap","min_amount":"1"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (100 * ONE_USDN).into()));
+    assert!(get_created_receipts().is_empty());
+    assert!(get_logs()
+        .iter()
+        .any(|log| log.starts_with("Invalid transfer command: unknown field `min_amount`")));
+
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.liquidity, (50_000 * ONE_USDN).into());
+    assert_eq!(token1_wallet.deposit, 0.into());
+}
+
+#[test]
+fn test_swap_input_overflow_refund() {
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+
+    // Input doesn't fit into the input reserve and is refunded in full
+    let amount_in = u128::MAX - 1;
+    let res = contract.ft_on_transfer(
+        "user.near".parse().unwrap(),
+        amount_in.into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == amount_in.into()));
+    assert!(get_created_receipts().is_empty());
+    assert!(get_logs().contains(&"Transfer failed. Error: Swap input is too large".to_string()));
+    assert_eq!(
+        contract.get_swap_estimate("usdn.testnet".parse().unwrap(), amount_in.into()),
+        Err("Swap input is too large")
+    );
+
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.liquidity, (1_000 * ONE_USDN).into());
+    assert_eq!(token1_wallet.deposit, 0.into());
+}
+
+#[test]
+fn test_verify_backing() {
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token1_wallet.deposit = (100 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+    let swap = |contract: &mut crate::Contract| {
+        contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            (10 * ONE_USDN).into(),
+            r#"{"type":"swap"}"#.to_string(),
+        )
+    };
+
+    // Swaps are refunded until backing is verified
+    assert!(!contract.is_backing_verified());
+    let res = swap(&mut contract);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDN).into()));
+    assert!(
+        get_logs().contains(&"Transfer failed. Error: Pool backing is not verified".to_string())
+    );
+
+    // Token1 balance doesn't cover both liquidity & deposit
+    assert!(
+        !contract.on_backing_verified(Ok((1_099 * ONE_USDN).into()), Ok((1_000 * ONE_USDT).into()))
+    );
+    // Failed balance query doesn't verify backing
+    assert!(!contract.on_backing_verified(
+        Ok((1_100 * ONE_USDN).into()),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    let res = swap(&mut contract);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDN).into()));
+
+    assert!(
+        contract.on_backing_verified(Ok((1_100 * ONE_USDN).into()), Ok((1_000 * ONE_USDT).into()))
+    );
+    assert!(contract.is_backing_verified());
+    assert!(matches!(swap(&mut contract), PromiseOrValue::Promise(_)));
+
+    // Failed check, e.g. while a swap output transfer awaits its callback, doesn't un-verify the pool
+    assert!(
+        !contract.on_backing_verified(Ok((1_000 * ONE_USDN).into()), Ok((1_000 * ONE_USDT).into()))
+    );
+    assert!(contract.is_backing_verified());
+    assert!(matches!(swap(&mut contract), PromiseOrValue::Promise(_)));
+}
+
+#[test]
+fn test_min_output_usd() {
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+    let mut swap = |amount_in: u128, msg: &str| {
+        contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            amount_in.into(),
+            msg.to_string(),
+        )
+    };
+
+    // Large trade worth ~90.9 USD is refunded with 99 USD minimum
+    let res = swap(100 * ONE_USDN, r#"{"type":"swap","min_output_usd":"99"}"#);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (100 * ONE_USDN).into()));
+    assert!(
+        get_logs().contains(&"Transfer failed. Error: Swap output is below minimum".to_string())
+    );
+
+    // USD minimum applies on top of the amount minimum
+    let res = swap(
+        ONE_USDN,
+        r#"{"type":"swap","min_amount_out":"1","min_output_usd":"0.9991"}"#,
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == ONE_USDN.into()));
+
+    // Small trade worth ~0.999 USD passes
+    let res = swap(ONE_USDN, r#"{"type":"swap","min_output_usd":"0.99"}"#);
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+
+    let res = swap(ONE_USDN, r#"{"type":"swap","min_output_usd":"-1"}"#);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == ONE_USDN.into()));
+    assert!(get_logs().contains(&"Transfer failed. Error: Invalid USD amount".to_string()));
+}
+
+#[test]
+fn test_max_price_impact() {
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+    let mut swap = |msg: &str| {
+        contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            (100 * ONE_USDN).into(),
+            msg.to_string(),
+        )
+    };
+
+    // Swapping 10% of the reserve lowers USDN price from 1 to ~0.8264, i.e. by ~17.36%
+    let res = swap(r#"{"type":"swap","max_price_impact_bps":1000}"#);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (100 * ONE_USDN).into()));
+    assert!(get_logs()
+        .contains(&"Transfer failed. Error: Swap price impact exceeds maximum".to_string()));
+
+    let res =
+        swap(r#"{"type":"swap_exact_out","amount_out":"90000000","max_price_impact_bps":1000}"#);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (100 * ONE_USDN).into()));
+
+    let res = swap(r#"{"type":"swap","max_price_impact_bps":1800}"#);
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+}
+
+#[test]
+fn test_max_swap_bps() {
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("owner.near".parse().unwrap())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    assert_eq!(contract.get_config().max_swap_bps, None);
+    assert_eq!(
+        contract.set_max_swap_bps(Some(10_001)),
+        Err("Max swap exceeds 100%")
+    );
+    contract.set_max_swap_bps(Some(1_000)).unwrap();
+    assert_eq!(contract.get_config().max_swap_bps, Some(1_000));
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .build());
+
+    // Swap of 20% of the input token liquidity is refunded under the 10% cap
+    let res = contract.ft_on_transfer(
+        "user.near".parse().unwrap(),
+        (200 * ONE_USDN).into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (200 * ONE_USDN).into()));
+    assert!(get_logs().contains(
+        &"Transfer failed. Error: Swap input exceeds maximum share of liquidity".to_string()
+    ));
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().liquidity,
+        (1_000 * ONE_USDN).into()
+    );
+
+    let res = contract.ft_on_transfer(
+        "user.near".parse().unwrap(),
+        (100 * ONE_USDN).into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+}
+
+#[test]
+fn test_sponsor_user_registration() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("owner.near".parse().unwrap())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    register_account(&mut contract, &user);
+
+    let amounts = || SwapAmounts {
+        amount_in: (10 * ONE_USDT).into(),
+        amount_out: (9 * ONE_USDN).into(),
+        amount_unused: 0.into(),
+        fee: 0.into(),
+        protocol_fee: 0.into(),
+    };
+    // Output of the swap is reserved from the liquidity when the swap starts
+    let reserve_output = |contract: &mut crate::Contract| {
+        contract.token1_wallet.as_mut().unwrap().liquidity = (991 * ONE_USDN).into();
+    };
+    let swap_complete =
+        |contract: &mut crate::Contract, sponsored: bool, transfer_result| match sponsored {
+            false => contract.on_swap_complete(
+                user.clone(),
+                usdt.clone(),
+                usdn.clone(),
+                amounts(),
+                transfer_result,
+            ),
+            true => contract.on_sponsored_swap_complete(
+                user.clone(),
+                usdt.clone(),
+                usdn.clone(),
+                amounts(),
+                transfer_result,
+            ),
+        };
+
+    // Without sponsoring the failed output transfer is refunded & the reserved output is returned
+    reserve_output(&mut contract);
+    let res = swap_complete(&mut contract, false, Err(near_sdk::PromiseError::Failed));
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDT).into()));
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().liquidity,
+        (1_000 * ONE_USDN).into()
+    );
+
+    contract
+        .update_config(ContractConfigPatch {
+            sponsor_user_registration: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(contract.get_config().sponsor_user_registration);
+
+    // Unregistered user is registered at the output token & the output transfer is retried
+    reserve_output(&mut contract);
+    let res = swap_complete(&mut contract, false, Err(near_sdk::PromiseError::Failed));
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+    drop(res);
+    let calls: Vec<_> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver_id = receipt.receiver_id;
+            receipt.actions.into_iter().map(move |action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    deposit,
+                    ..
+                } => (receiver_id.to_string(), function_name, deposit),
+                _ => panic!("Unexpected action"),
+            })
+        })
+        .collect();
+    assert_eq!(
+        calls,
+        vec![
+            (
+                usdn.to_string(),
+                "storage_deposit".to_string(),
+                crate::tokens::SPONSORED_REGISTRATION_DEPOSIT
+            ),
+            (usdn.to_string(), "ft_transfer".to_string(), ONE_YOCTO),
+            (
+                near_sdk::env::current_account_id().to_string(),
+                "on_sponsored_swap_complete".to_string(),
+                0
+            ),
+        ]
+    );
+    // Registered user pays for the registration out of its available storage balance right away
+    let charged = ContractAccount::max_deposit().0
+        - contract.storage_balance_of(user.clone()).unwrap().total.0;
+    assert!(charged > 0 && charged < crate::tokens::SPONSORED_REGISTRATION_DEPOSIT);
+    assert_eq!(
+        contract.get_sponsored_registration(user.clone()),
+        (crate::tokens::SPONSORED_REGISTRATION_DEPOSIT - charged).into()
+    );
+    // Retry is pending, so the last swap is still the refunded one
+    assert_eq!(
+        contract.get_last_swap(user.clone()).map(|swap| swap.status),
+        Some(SwapStatus::Refunded)
+    );
+
+    // Retried output transfer succeeds, so the swap input is applied
+    let res = swap_complete(&mut contract, true, Ok(()));
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+    assert_eq!(
+        contract.get_last_swap(user.clone()).map(|swap| swap.status),
+        Some(SwapStatus::Filled)
+    );
+    assert_eq!(
+        contract.get_reserves(),
+        Ok([991 * ONE_USDN, 1_010 * ONE_USDT])
+    );
+
+    // Retried output transfer isn't retried again
+    let res = swap_complete(&mut contract, true, Err(near_sdk::PromiseError::Failed));
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDT).into()));
+
+    // Outstanding sponsored registration is repaid from the next storage deposit
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(
+            crate::tokens::SPONSORED_REGISTRATION_DEPOSIT + ContractAccount::max_deposit().0
+        )
+        .build());
+    let storage_balance = contract.storage_deposit(None, None);
+    assert_eq!(contract.get_sponsored_registration(user.clone()), 0.into());
+    assert_eq!(storage_balance.total, ContractAccount::max_deposit());
+
+    // Available storage balance is charged for the next sponsored registration
+    reserve_output(&mut contract);
+    let res = swap_complete(&mut contract, false, Err(near_sdk::PromiseError::Failed));
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+    let charged = storage_balance
+        .available
+        .0
+        .min(crate::tokens::SPONSORED_REGISTRATION_DEPOSIT);
+    assert!(charged > 0);
+    assert_eq!(
+        contract.get_sponsored_registration(user.clone()),
+        (crate::tokens::SPONSORED_REGISTRATION_DEPOSIT - charged).into()
+    );
+    assert_eq!(
+        contract.storage_balance_of(user.clone()).unwrap().total.0,
+        ContractAccount::max_deposit().0 - charged
+    );
+}
+
+#[test]
+fn test_swap_history() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    assert_eq!(contract.get_swap_history(0.into(), 10), vec![]);
+
+    let swaps = SWAP_HISTORY_SIZE + 3;
+    for index in 0..swaps {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .block_timestamp(1_000 + index)
+            .build());
+        let amount_in = u128::from(index + 1) * ONE_USDT;
+        let res = contract.on_swap_complete(
+            user.clone(),
+            usdt.clone(),
+            usdn.clone(),
+            SwapAmounts {
+                amount_in: amount_in.into(),
+                amount_out: (amount_in / 2).into(),
+                amount_unused: 0.into(),
+                fee: (amount_in / 100).into(),
+                protocol_fee: 0.into(),
+            },
+            Ok(()),
+        );
+        assert!(matches!(res, PromiseOrValue::Value(_)));
+    }
+    assert_eq!(contract.get_swap_history_len(), swaps.into());
+
+    // The oldest swaps were overwritten, so the history starts from the oldest kept one
+    let indexes = |history: Vec<SwapRecord>| -> Vec<u64> {
+        history.into_iter().map(|record| record.index.0).collect()
+    };
+    assert_eq!(indexes(contract.get_swap_history(0.into(), 2)), vec![3, 4]);
+    assert_eq!(
+        indexes(contract.get_swap_history(50.into(), 3)),
+        vec![50, 51, 52]
+    );
+    assert_eq!(
+        indexes(contract.get_swap_history((swaps - 2).into(), 10)),
+        vec![swaps - 2, swaps - 1]
+    );
+    assert_eq!(contract.get_swap_history(swaps.into(), 10), vec![]);
+    assert_eq!(
+        contract.get_swap_history(0.into(), u64::MAX).len() as u64,
+        SWAP_HISTORY_SIZE
+    );
+
+    assert_eq!(
+        contract.get_swap_history(50.into(), 1),
+        vec![SwapRecord {
+            index: 50.into(),
+            sender_id: user,
+            token_in: usdt,
+            token_out: usdn,
+            amount_in: (51 * ONE_USDT).into(),
+            amount_out: (51 * ONE_USDT / 2).into(),
+            fee: (51 * ONE_USDT / 100).into(),
+            timestamp: 1_050.into(),
+        }]
+    );
+}
+
+#[test]
+fn test_price_cumulative() {
+    const ONE_SECOND: u64 = 1_000_000_000;
+    let at = |timestamp: u64| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("usdn.testnet".parse().unwrap())
+            .block_timestamp(timestamp)
+            .build());
+    };
+
+    at(ONE_SECOND);
+    let mut contract = crate::Contract::init(None, None, None, Some(0), None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+    assert_eq!(
+        contract.get_price_cumulative(),
+        PriceCumulativeView {
+            price_cumulative: U256::zero(),
+            block_timestamp: ONE_SECOND.into(),
+        }
+    );
+
+    let swap = |contract: &mut crate::Contract| {
+        contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            ONE_USDN.into(),
+            r#"{"type":"swap"}"#.to_string(),
+        )
+    };
+    let one = U256::exp10(12);
+
+    // Price of 1 was held for 2 seconds
+    at(3 * ONE_SECOND);
+    swap(&mut contract);
+    let observation1 = contract.get_price_cumulative();
+    assert_eq!(observation1.price_cumulative, one * 2 * ONE_SECOND);
+    assert_eq!(observation1.block_timestamp, (3 * ONE_SECOND).into());
+
+    // Accumulator doesn't change within the same block
+    swap(&mut contract);
+    assert_eq!(contract.get_price_cumulative(), observation1);
+
+    // Price of 0.5 was held for 4 seconds, so TWAP between observations is 0.5
+    contract.token2_wallet.as_mut().unwrap().liquidity = (500 * ONE_USDT).into();
+    at(7 * ONE_SECOND);
+    swap(&mut contract);
+    let observation2 = contract.get_price_cumulative();
+    assert!(observation2.price_cumulative > observation1.price_cumulative);
+    assert_eq!(
+        (observation2.price_cumulative - observation1.price_cumulative)
+            / (observation2.block_timestamp.0 - observation1.block_timestamp.0),
+        one / 2
+    );
+
+    // Swap applied by a later callback accumulates the price held until the callback,
+    // reserves held meanwhile are set explicitly instead of the ones with the reserved swap outputs
+    contract.token1_wallet.as_mut().unwrap().liquidity = (1_000 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (500 * ONE_USDT).into();
+    at(9 * ONE_SECOND);
+    let res = contract.on_swap_complete(
+        "user.near".parse().unwrap(),
+        "usdn.testnet".parse().unwrap(),
+        "usdt.fakes.testnet".parse().unwrap(),
+        SwapAmounts {
+            amount_in: (1_000 * ONE_USDN).into(),
+            amount_out: (250 * ONE_USDT).into(),
+            amount_unused: 0.into(),
+            fee: 0.into(),
+            protocol_fee: 0.into(),
+        },
+        Ok(()),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(_)));
+    let observation3 = contract.get_price_cumulative();
+    assert_eq!(observation3.block_timestamp, (9 * ONE_SECOND).into());
+    assert_eq!(
+        observation3.price_cumulative - observation2.price_cumulative,
+        one / 2 * 2 * ONE_SECOND
+    );
+}
+
+#[test]
+fn test_price_cumulative_wraparound() {
+    const ONE_SECOND: u64 = 1_000_000_000;
+    let at = |timestamp: u64| {
+        near_sdk::testing_env!(VMContextBuilder::new().block_timestamp(timestamp).build());
+    };
+
+    at(ONE_SECOND);
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    // Accumulator is just below the wraparound
+    contract.price_cumulative_last = (U256::MAX - U256::from(1_000)).0;
+    let observation1 = contract.get_price_cumulative();
+
+    at(3 * ONE_SECOND);
+    contract.update_price_cumulative();
+    let observation2 = contract.get_price_cumulative();
+    assert!(observation2.price_cumulative < observation1.price_cumulative);
+
+    // Wrapping difference still yields price of 1 held for 2 seconds
+    let (delta, _) = observation2
+        .price_cumulative
+        .overflowing_sub(observation1.price_cumulative);
+    assert_eq!(delta, U256::exp10(12) * 2 * ONE_SECOND);
+    assert_eq!(
+        delta / (observation2.block_timestamp.0 - observation1.block_timestamp.0),
+        U256::exp10(12)
+    );
+}
+
+#[test]
+fn test_swap_deadline() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("usdn.testnet".parse().unwrap())
+        .block_timestamp(1_000)
+        .build());
+    let swap = |contract: &mut crate::Contract, deadline_ns: u64| {
+        contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            (10 * ONE_USDN).into(),
+            format!(r#"{{"type":"swap","deadline_ns":"{}"}}"#, deadline_ns),
+        )
+    };
+
+    // Past, current & zero deadlines are refunded without touching liquidity
+    for deadline_ns in [999, 1_000, 0] {
+        let res = swap(&mut contract, deadline_ns);
+        assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDN).into()));
+        assert!(
+            get_logs().contains(&"Transfer failed. Error: Swap deadline has passed".to_string())
+        );
+    }
+    assert!(get_created_receipts().is_empty());
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.liquidity, (1_000 * ONE_USDN).into());
+    assert_eq!(token1_wallet.deposit, 0.into());
+    assert_eq!(contract.get_swap_history_len(), 0.into());
+
+    let res = swap(&mut contract, 1_001);
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+}
+
+#[test]
+fn test_swap_complete_unused_input() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new().block_index(7).build());
+
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    register_account(&mut contract, &user);
+    // Output of the swap is reserved from the liquidity when the swap starts
+    let reserve_output = |contract: &mut crate::Contract| {
+        contract.token1_wallet.as_mut().unwrap().liquidity = (991 * ONE_USDN).into();
+    };
+
+    // Failed output transfer refunds the whole input, including the used part,
+    // and returns the reserved output to the liquidity
+    reserve_output(&mut contract);
+    let res = contract.on_swap_complete(
+        user.clone(),
+        usdt.clone(),
+        usdn.clone(),
+        SwapAmounts {
+            amount_in: (10 * ONE_USDT).into(),
+            amount_out: (9 * ONE_USDN).into(),
+            amount_unused: ONE_USDT.into(),
+            fee: 0.into(),
+            protocol_fee: 0.into(),
+        },
+        Err(near_sdk::PromiseError::Failed),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (11 * ONE_USDT).into()));
+    assert_eq!(
+        contract.get_last_swap(user.clone()),
+        Some(LastSwap {
+            token_in: usdt.clone(),
+            token_out: usdn.clone(),
+            status: SwapStatus::Refunded,
+            amount_in: 0.into(),
+            amount_out: 0.into(),
+            amount_refunded: (11 * ONE_USDT).into(),
+            block_height: 7.into(),
+        })
+    );
+    assert_eq!(
+        contract.get_reserves(),
+        Ok([1_000 * ONE_USDN, 1_000 * ONE_USDT])
+    );
+
+    // Successful swap returns only the unused input, while output is recorded separately
+    reserve_output(&mut contract);
+    let res = contract.on_swap_complete(
+        user.clone(),
+        usdt.clone(),
+        usdn.clone(),
+        SwapAmounts {
+            amount_in: (10 * ONE_USDT).into(),
+            amount_out: (9 * ONE_USDN).into(),
+            amount_unused: ONE_USDT.into(),
+            fee: 0.into(),
+            protocol_fee: 0.into(),
+        },
+        Ok(()),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == ONE_USDT.into()));
+    assert_eq!(
+        contract.get_last_swap(user),
+        Some(LastSwap {
+            token_in: usdt,
+            token_out: usdn,
+            status: SwapStatus::PartiallyFilled,
+            amount_in: (10 * ONE_USDT).into(),
+            amount_out: (9 * ONE_USDN).into(),
+            amount_refunded: ONE_USDT.into(),
+            block_height: 7.into(),
+        })
+    );
+    assert_eq!(
+        contract.get_reserves(),
+        Ok([991 * ONE_USDN, 1_010 * ONE_USDT])
+    );
+}
+
+#[test]
+fn test_swap_status() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet.clone());
+    contract.token2_wallet = Some(token2_wallet.clone());
+    register_account(&mut contract, &user);
+
+    let swap_event = || {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str::<Event<SwapEvent>>(event).unwrap())
+            .next_back()
+            .unwrap()
+            .data
+            .remove(0)
+    };
+    let mut swap_complete = |amount_unused: u128, transfer_result| {
+        contract.on_swap_complete(
+            user.clone(),
+            usdn.clone(),
+            "usdt.fakes.testnet".parse().unwrap(),
+            SwapAmounts {
+                amount_in: (10 * ONE_USDN).into(),
+                amount_out: (9 * ONE_USDT).into(),
+                amount_unused: amount_unused.into(),
+                fee: 0.into(),
+                protocol_fee: 0.into(),
+            },
+            transfer_result,
+        );
+        contract.get_last_swap(user.clone()).unwrap()
+    };
+
+    let last_swap = swap_complete(0, Ok(()));
+    assert_eq!(last_swap.status, SwapStatus::Filled);
+    assert_eq!(last_swap.amount_refunded, 0.into());
+    assert_matches!(
+        swap_event(),
+        SwapEvent {
+            status: SwapStatus::Filled,
+            amount_in,
+            amount_out,
+            ..
+        } if amount_in == (10 * ONE_USDN).into() && amount_out == (9 * ONE_USDT).into()
+    );
+
+    let last_swap = swap_complete(ONE_USDN, Ok(()));
+    assert_eq!(last_swap.status, SwapStatus::PartiallyFilled);
+    assert_eq!(last_swap.amount_refunded, ONE_USDN.into());
+    assert_eq!(swap_event().status, SwapStatus::PartiallyFilled);
+
+    let last_swap = swap_complete(ONE_USDN, Err(near_sdk::PromiseError::Failed));
+    assert_eq!(last_swap.status, SwapStatus::Refunded);
+    assert_eq!(last_swap.amount_out, 0.into());
+    assert_eq!(last_swap.amount_refunded, (11 * ONE_USDN).into());
+    assert_eq!(swap_event().status, SwapStatus::Refunded);
+    assert_eq!(
+        swap_event().marginal_price,
+        contract.get_marginal_price(usdn.clone()).ok()
+    );
+
+    // Swap rejected before execution is refunded as a whole
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(usdn.clone())
+        .build());
+    let res = contract.ft_on_transfer(
+        user.clone(),
+        (5 * ONE_USDN).into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (5 * ONE_USDN).into()));
+    assert_matches!(
+        contract.get_last_swap(user).unwrap(),
+        LastSwap {
+            status: SwapStatus::Refunded,
+            amount_in,
+            amount_refunded,
+            ..
+        } if amount_in == 0.into() && amount_refunded == (5 * ONE_USDN).into()
+    );
+    assert_eq!(swap_event().status, SwapStatus::Refunded);
+}
+
+#[test]
+fn test_swap_records_storage() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    let swap_complete = |contract: &mut crate::Contract| {
+        contract.on_swap_complete(
+            user.clone(),
+            usdn.clone(),
+            usdt.clone(),
+            SwapAmounts {
+                amount_in: (10 * ONE_USDN).into(),
+                amount_out: (9 * ONE_USDT).into(),
+                amount_unused: 0.into(),
+                fee: 0.into(),
+                protocol_fee: 0.into(),
+            },
+            Ok(()),
+        )
+    };
+
+    // Unregistered sender can't pay for its swap records, so they aren't kept
+    let storage_usage_before = near_sdk::env::storage_usage();
+    swap_complete(&mut contract);
+    assert_eq!(contract.get_last_swap(user.clone()), None);
+    assert_eq!(contract.get_swap_count(user.clone()), 0);
+    let history_storage_usage = near_sdk::env::storage_usage() - storage_usage_before;
+
+    // Registered sender pays for its swap records out of its storage balance
+    register_account(&mut contract, &user);
+    let account_storage_usage = contract.get_account(&user).unwrap().storage_usage;
+    let storage_usage_before = near_sdk::env::storage_usage();
+    swap_complete(&mut contract);
+    assert!(contract.get_last_swap(user.clone()).is_some());
+    assert_eq!(contract.get_swap_count(user.clone()), 1);
+    let records_storage_usage =
+        near_sdk::env::storage_usage() - storage_usage_before - history_storage_usage;
+    assert!(records_storage_usage > 0);
+    assert_eq!(
+        contract.get_account(&user).unwrap().storage_usage,
+        account_storage_usage + records_storage_usage
+    );
+
+    // Overwriting the records of the same size charges nothing more
+    swap_complete(&mut contract);
+    assert_eq!(contract.get_swap_count(user.clone()), 2);
+    assert_eq!(
+        contract.get_account(&user).unwrap().storage_usage,
+        account_storage_usage + records_storage_usage
+    );
+
+    // Committed swap requires the sender to pay for its storage
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    assert!(matches!(
+        contract.on_transfer_commit_swap(bob.clone(), usdn, ONE_USDN.into(), None),
+        Err("Account is not registered")
+    ));
+    assert!(contract.get_committed_swap(bob).is_none());
+}
+
+#[test]
+fn test_add_liquidity_ratio_bounds() {
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("owner.near".parse().unwrap())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let init = || {
+        let mut contract = crate::Contract::init(
+            Some("owner.near".parse().unwrap()),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+        let mut token2_wallet = test_token_wallet("eth.fakes.testnet", 18);
+        token1_wallet.deposit = u128::MAX.into();
+        token2_wallet.deposit = u128::MAX.into();
+        contract.token1_wallet = Some(token1_wallet);
+        contract.token2_wallet = Some(token2_wallet);
+        contract
+    };
+
+    // Largest USDN reserve, whose normalized tokens ratio with the ETH reserve fits U256
+    let eth = u128::MAX / 2;
+    let max_usdn = (U256::MAX / (U256::exp10(12) * U256::from(eth))).as_u128();
+
+    let mut contract = init();
+    assert_eq!(
+        contract.add_liquidity([(max_usdn + 1).into(), eth.into()], None),
+        Err("Liquidity exceeds tokens ratio bounds")
+    );
+    assert_eq!(contract.get_reserves(), Ok([0, 0]));
+
+    let mut contract = init();
+    contract
+        .add_liquidity([max_usdn.into(), eth.into()], None)
+        .unwrap();
+    assert_eq!(contract.get_reserves(), Ok([max_usdn, eth]));
+
+    // Pool at the bound still reports its normalized ratio & quotes swaps in both directions
+    let pool = contract.get_pool().unwrap();
+    assert_eq!(
+        pool.ratio,
+        U256::from(max_usdn) * U256::exp10(12) * U256::from(eth)
+    );
+    assert!(
+        contract
+            .get_amount_out("usdn.testnet".parse().unwrap(), ONE_USDN.into())
+            .unwrap()
+            .0
+            > 0
+    );
+    assert!(
+        contract
+            .get_amount_out("eth.fakes.testnet".parse().unwrap(), ONE_ETH.into())
+            .unwrap()
+            .0
+            > 0
+    );
+}
+
+#[test]
+fn test_add_liquidity_max_deviation() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (100_000 * ONE_USDN).into();
+    token1_wallet.deposit = (100_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (100_000 * ONE_USDT).into();
+    token2_wallet.deposit = (100_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    // Default tolerance is 10 bps
+    assert_eq!(
+        contract.add_liquidity(
+            [(10_000 * ONE_USDN).into(), (9_989 * ONE_USDT).into()],
+            None
+        ),
+        Err("Liquidity amounts deviate from the pool ratio")
+    );
+    assert_eq!(
+        contract.add_liquidity(
+            [(10_000 * ONE_USDN).into(), (9_990 * ONE_USDT).into()],
+            None
+        ),
+        Ok(())
+    );
+
+    // Loosened tolerance accepts one-sided additions at most
+    contract
+        .update_config(ContractConfigPatch {
+            max_add_deviation_bps: Some(MAX_BPS),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(
+        contract.add_liquidity([ONE_USDN.into(), 0.into()], None),
+        Ok(())
+    );
+    assert_eq!(
+        contract.update_config(ContractConfigPatch {
+            max_add_deviation_bps: Some(MAX_BPS + 1),
+            ..Default::default()
+        }),
+        Err("Deviation exceeds 100%")
+    );
+}
+
+#[test]
+fn test_add_liquidity_balanced() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.deposit = (10_000 * ONE_USDN).into();
+    token2_wallet.deposit = (10_000 * ONE_USDT).into();
+    token1_wallet.accounts_deposit = (100 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    // The first addition sets an arbitrary ratio
+    assert_eq!(
+        contract.add_liquidity_balanced([(1_000 * ONE_USDN).into(), (2_000 * ONE_USDT).into()]),
+        Ok([(1_000 * ONE_USDN).into(), (2_000 * ONE_USDT).into()])
+    );
+
+    // Unbalanced amounts are rejected by plain addition
+    assert_eq!(
+        contract.add_liquidity([(500 * ONE_USDN).into(), (500 * ONE_USDT).into()], None),
+        Err("Liquidity amounts deviate from the pool ratio")
+    );
+
+    // Only the balanced part is added, while the rest stays in the deposit
+    assert_eq!(
+        contract.add_liquidity_balanced([(500 * ONE_USDN).into(), (500 * ONE_USDT).into()]),
+        Ok([(250 * ONE_USDN).into(), (500 * ONE_USDT).into()])
+    );
+    assert_eq!(
+        contract.add_liquidity_balanced([(100 * ONE_USDN).into(), (5_000 * ONE_USDT).into()]),
+        Ok([(100 * ONE_USDN).into(), (200 * ONE_USDT).into()])
+    );
+    assert_eq!(
+        contract.get_pool().unwrap().amounts,
+        [(1_350 * ONE_USDN).into(), (2_700 * ONE_USDT).into()]
+    );
+    assert_eq!(
+        contract.get_deposits(),
+        Ok([(8_650 * ONE_USDN).into(), (7_300 * ONE_USDT).into()])
+    );
+
+    // Registered account adds the balanced part of its own balances
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    register_account(&mut contract, &bob);
+    contract
+        .internal_deposit(&bob, &usdn, 100 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&bob, &usdt, 1_000 * ONE_USDT)
+        .unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(bob.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    assert_eq!(
+        contract.add_liquidity_balanced([(100 * ONE_USDN).into(), (1_000 * ONE_USDT).into()]),
+        Ok([(100 * ONE_USDN).into(), (200 * ONE_USDT).into()])
+    );
+    assert_eq!(contract.get_account_balance(bob.clone(), usdn), 0.into());
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdt),
+        (800 * ONE_USDT).into()
+    );
+    assert!(contract.get_lp_balance(bob).0 > 0);
+    assert_eq!(
+        contract.get_deposits(),
+        Ok([(8_650 * ONE_USDN).into(), (7_300 * ONE_USDT).into()])
+    );
+}
+
+#[test]
+fn test_rebalance_and_add() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (100_000 * ONE_USDN).into();
+    token1_wallet.deposit = (20_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (100_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.protocol_fee_bps = 1_000;
+
+    // Swap leg is subject to the same checks as a regular swap
+    assert_eq!(
+        contract.rebalance_and_add(
+            usdn.clone(),
+            (5_000 * ONE_USDN).into(),
+            [0.into(), 0.into()]
+        ),
+        Err("Pool backing is not verified")
+    );
+    contract.backing_verified = true;
+    contract
+        .token2_wallet
+        .as_mut()
+        .unwrap()
+        .min_meaningful_output = (10 * ONE_USDT).into();
+    assert_eq!(
+        contract.rebalance_and_add(usdn.clone(), ONE_USDN.into(), [0.into(), 0.into()]),
+        Err("Swap output is too small, try a larger trade")
+    );
+    contract
+        .token2_wallet
+        .as_mut()
+        .unwrap()
+        .min_meaningful_output = 0.into();
+
+    // Skewed USDN-only deposit can't be added as is
+    assert_eq!(
+        contract.add_liquidity([(20_000 * ONE_USDN).into(), 0.into()], None),
+        Err("Liquidity amounts deviate from the pool ratio")
+    );
+
+    // Quarter of the deposit is swapped to USDT, then both are added at the post-swap ratio
+    let amount_out = contract
+        .get_swap_estimate(usdn.clone(), (5_000 * ONE_USDN).into())
+        .unwrap()
+        .0;
+    let protocol_fee = contract
+        .prepare_swap(&owner, &usdn, 5_000 * ONE_USDN, None, None, None)
+        .unwrap()
+        .amounts
+        .protocol_fee
+        .0;
+    assert!(protocol_fee > 0);
+    let amount_usdn =
+        amount_out * (105_000 * ONE_USDN - protocol_fee) / (100_000 * ONE_USDT - amount_out);
+    contract
+        .rebalance_and_add(
+            usdn,
+            (5_000 * ONE_USDN).into(),
+            [amount_usdn.into(), amount_out.into()],
+        )
+        .unwrap();
+
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    let token2_wallet = contract.token2_wallet.as_ref().unwrap();
+    assert_eq!(
+        token1_wallet.liquidity,
+        (105_000 * ONE_USDN - protocol_fee + amount_usdn).into()
+    );
+    assert_eq!(
+        token1_wallet.deposit,
+        (15_000 * ONE_USDN - amount_usdn).into()
+    );
+    assert_eq!(token2_wallet.liquidity, (100_000 * ONE_USDT).into());
+    assert_eq!(token2_wallet.deposit, 0.into());
+    assert_eq!(contract.protocol_fees, [protocol_fee, 0]);
+
+    // Swap beyond the deposit is rejected
+    assert_eq!(
+        contract.rebalance_and_add(
+            "usdt.fakes.testnet".parse().unwrap(),
+            ONE_USDT.into(),
+            [0.into(), 0.into()]
+        ),
+        Err("Not enough deposit for the swap")
+    );
+}
+
+#[test]
+fn test_lp_shares() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    let carol: near_sdk::AccountId = "carol.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+    let deposit = |contract: &mut crate::Contract,
+                   sender_id: &near_sdk::AccountId,
+                   token_id: &near_sdk::AccountId,
+                   amount: u128| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id.clone())
+            .build());
+        let res = contract.ft_on_transfer(sender_id.clone(), amount.into(), String::new());
+        assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.deposit = (10_000 * ONE_USDN).into();
+    token2_wallet.deposit = (40_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    // Owner provides liquidity from the wallet deposit without registration,
+    // the first provider mints the geometric mean of the amounts
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (4_000 * ONE_USDT).into()], None)
+        .unwrap();
+    assert_eq!(
+        contract.get_lp_balance(owner.clone()),
+        (2_000 * ONE_USDN).into()
+    );
+    assert_eq!(contract.get_lp_total_supply(), (2_000 * ONE_USDN).into());
+
+    // Other providers add liquidity from their own balances, only registered ones
+    call_as(&bob);
+    assert_eq!(
+        contract.add_liquidity([(500 * ONE_USDN).into(), (2_000 * ONE_USDT).into()], None),
+        Err("Account is not registered")
+    );
+    register_account(&mut contract, &bob);
+    register_account(&mut contract, &carol);
+    deposit(&mut contract, &bob, &usdn, 500 * ONE_USDN);
+    deposit(&mut contract, &bob, &usdt, 2_000 * ONE_USDT);
+    deposit(&mut contract, &carol, &usdn, 1_000 * ONE_USDN);
+    deposit(&mut contract, &carol, &usdt, 3_000 * ONE_USDT);
+
+    call_as(&carol);
+    assert_eq!(
+        contract.add_liquidity([(1_000 * ONE_USDN).into(), (4_000 * ONE_USDT).into()], None),
+        Err("Not enough deposit for Token2")
+    );
+
+    // Each provider mints shares proportional to the contributed liquidity
+    call_as(&bob);
+    contract
+        .add_liquidity([(500 * ONE_USDN).into(), (2_000 * ONE_USDT).into()], None)
+        .unwrap();
+    call_as(&carol);
+    contract
+        .add_liquidity([(750 * ONE_USDN).into(), (3_000 * ONE_USDT).into()], None)
+        .unwrap();
+    assert_eq!(
+        contract.get_lp_balance(bob.clone()),
+        (1_000 * ONE_USDN).into()
+    );
+    assert_eq!(
+        contract.get_lp_balance(carol.clone()),
+        (1_500 * ONE_USDN).into()
+    );
+    assert_eq!(contract.get_lp_total_supply(), (4_500 * ONE_USDN).into());
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdn.clone()),
+        0.into()
+    );
+    assert_eq!(
+        contract.get_account_balance(carol.clone(), usdn.clone()),
+        (250 * ONE_USDN).into()
+    );
+
+    // Removal burns shares proportional to the removed liquidity, rounded up,
+    // and credits the provider's balances
+    call_as(&bob);
+    contract
+        .remove_liquidity([(250 * ONE_USDN).into(), (1_000 * ONE_USDT).into()])
+        .unwrap();
+    assert_eq!(
+        contract.get_lp_balance(bob.clone()),
+        (500 * ONE_USDN).into()
+    );
+    contract.remove_liquidity([1.into(), 0.into()]).unwrap();
+    assert_eq!(
+        contract.get_lp_balance(bob.clone()),
+        (500 * ONE_USDN - 2).into()
+    );
+    assert_eq!(
+        contract.remove_liquidity([(500 * ONE_USDN).into(), 0.into()]),
+        Err("Not enough LP shares")
+    );
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdn.clone()),
+        (250 * ONE_USDN + 1).into()
+    );
+    assert_eq!(
+        contract.get_account_balance(bob, usdt.clone()),
+        (1_000 * ONE_USDT).into()
+    );
+
+    // Other providers & the owner's deposit are untouched
+    assert_eq!(contract.get_lp_balance(carol), (1_500 * ONE_USDN).into());
+    assert_eq!(contract.get_lp_balance(owner), (2_000 * ONE_USDN).into());
+    assert_eq!(
+        contract.get_lp_total_supply(),
+        (4_000 * ONE_USDN - 2).into()
+    );
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    let token2_wallet = contract.token2_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.deposit, (9_000 * ONE_USDN).into());
+    assert_eq!(token2_wallet.deposit, (36_000 * ONE_USDT).into());
+    assert_eq!(token1_wallet.accounts_deposit, (500 * ONE_USDN + 1).into());
+    assert_eq!(token2_wallet.accounts_deposit, (1_000 * ONE_USDT).into());
+}
+
+#[test]
+fn test_shares_for_output() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let alice: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    register_account(&mut contract, &alice);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.deposit = (3_000 * ONE_USDT).into();
+    token1_wallet.accounts_deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (3_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract
+        .internal_deposit(&alice, &usdn, 1_000 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&alice, &usdt, 3_000 * ONE_USDT)
+        .unwrap();
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (3_000 * ONE_USDT).into()], None)
+        .unwrap();
+    call_as(&alice);
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (3_000 * ONE_USDT).into()], None)
+        .unwrap();
+
+    // Dust accrued to the pool makes the pro-rata amounts round down
+    contract.token2_wallet.as_mut().unwrap().liquidity = (6_000 * ONE_USDT + 7).into();
+
+    assert_eq!(
+        contract.shares_for_output(usdt.clone(), 0.into()),
+        Err("Amount must be positive")
+    );
+    assert_eq!(
+        contract.shares_for_output(usdt.clone(), (6_000 * ONE_USDT + 8).into()),
+        Err("Not enough liquidity")
+    );
+    assert_eq!(
+        contract.shares_for_output("unknown.near".parse().unwrap(), 1.into()),
+        Err("Token is not supported")
+    );
+
+    for (token_id, amount) in [(&usdt, 1_234 * ONE_USDT + 1), (&usdn, 333 * ONE_USDN + 333)] {
+        let balance_before = contract.get_account_balance(alice.clone(), token_id.clone());
+        let shares = contract
+            .shares_for_output(token_id.clone(), amount.into())
+            .unwrap();
+        contract.remove_liquidity_by_shares(shares).unwrap();
+
+        let received = contract
+            .get_account_balance(alice.clone(), token_id.clone())
+            .0
+            - balance_before.0;
+        assert!(received >= amount);
+    }
+}
+
+#[test]
+fn test_lp_breakeven() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let alice: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    // Shares & their pro-rata reserves are rounded down, so the price is exact up to 1e-6
+    let assert_breakeven =
+        |contract: &crate::Contract, account_id: &near_sdk::AccountId, price: &str| {
+            let breakeven =
+                parse_price(&contract.get_lp_breakeven(account_id.clone()).unwrap()).unwrap();
+            let price = parse_price(price).unwrap();
+            let deviation = if breakeven > price {
+                breakeven - price
+            } else {
+                price - breakeven
+            };
+            assert!(
+                deviation <= U256::from(1_000_000),
+                "{}",
+                format_price(breakeven)
+            );
+        };
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    register_account(&mut contract, &alice);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.deposit = (2_000 * ONE_USDT).into();
+    token1_wallet.accounts_deposit = (100 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (200 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract
+        .internal_deposit(&alice, &usdn, 100 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&alice, &usdt, 200 * ONE_USDT)
+        .unwrap();
+    assert_eq!(
+        contract.get_lp_breakeven(alice.clone()),
+        Err("Account has no LP shares")
+    );
+
+    // Both providers enter at the price of 2 USDT per USDN
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (2_000 * ONE_USDT).into()], None)
+        .unwrap();
+    call_as(&alice);
+    contract
+        .add_liquidity([(100 * ONE_USDN).into(), (200 * ONE_USDT).into()], None)
+        .unwrap();
+    assert_eq!(
+        contract.get_account(&alice).unwrap().lp_entry_value,
+        400 * ONE_USDT
+    );
+
+    // Without fees the LP shares are worth the provided value only at the entry price
+    assert_breakeven(&contract, &owner, "2");
+    assert_breakeven(&contract, &alice, "2");
+
+    // Fees grew both reserves by 1%, so USDN may drop further before the LP shares are worth less
+    contract.token1_wallet.as_mut().unwrap().liquidity = (1_111 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (2_222 * ONE_USDT).into();
+    assert_breakeven(&contract, &alice, "1.960592098813");
+
+    // Burned LP shares take their part of the entry value, the rest breaks even at the same price
+    let shares = contract.get_lp_balance(alice.clone()).0;
+    contract
+        .remove_liquidity_by_shares((shares / 2).into())
+        .unwrap();
+    assert_eq!(
+        contract.get_account(&alice).unwrap().lp_entry_value,
+        200 * ONE_USDT
+    );
+    assert_breakeven(&contract, &alice, "1.960592098813");
+
+    contract
+        .remove_liquidity_by_shares((shares - shares / 2).into())
+        .unwrap();
+    assert_eq!(contract.get_account(&alice).unwrap().lp_entry_value, 0);
+    assert_eq!(
+        contract.get_lp_breakeven(alice),
+        Err("Account has no LP shares")
+    );
+}
+
+#[test]
+fn test_remove_liquidity_by_shares() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    register_account(&mut contract, &bob);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.deposit = (10_000 * ONE_USDN).into();
+    token2_wallet.deposit = (40_000 * ONE_USDT).into();
+    token1_wallet.accounts_deposit = (500 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (2_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract
+        .internal_deposit(&bob, &usdn, 500 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&bob, &usdt, 2_000 * ONE_USDT)
+        .unwrap();
+
+    // Both the owner & a registered account provide liquidity
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (4_000 * ONE_USDT).into()], None)
+        .unwrap();
+    call_as(&bob);
+    contract
+        .add_liquidity([(500 * ONE_USDN).into(), (2_000 * ONE_USDT).into()], None)
+        .unwrap();
+    assert_eq!(
+        contract.get_lp_balance(bob.clone()),
+        (1_000 * ONE_USDN).into()
+    );
+
+    // Some dust, e.g. swap fees, accrued to the pool
+    contract.token1_wallet.as_mut().unwrap().liquidity = (1_500 * ONE_USDN + 7).into();
+
+    assert_eq!(
+        contract.remove_liquidity_by_shares(0.into()),
+        Err("Shares must be positive")
+    );
+    assert_eq!(
+        contract.remove_liquidity_by_shares((3_001 * ONE_USDN).into()),
+        Err("Not enough LP shares")
+    );
+    // Total supply covers, but the caller's balance doesn't
+    assert_eq!(
+        contract.remove_liquidity_by_shares((1_001 * ONE_USDN).into()),
+        Err("Not enough LP shares")
+    );
+
+    // Half of the shares is a sixth of the pool, rounded down, credited to the caller's balances
+    contract
+        .remove_liquidity_by_shares((500 * ONE_USDN).into())
+        .unwrap();
+
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    let token2_wallet = contract.token2_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.liquidity, (1_250 * ONE_USDN + 6).into());
+    assert_eq!(token2_wallet.liquidity, (5_000 * ONE_USDT).into());
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdn.clone()),
+        (250 * ONE_USDN + 1).into()
+    );
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdt.clone()),
+        (1_000 * ONE_USDT).into()
+    );
+
+    // Owner's shares are withdrawn to the wallet deposit
+    call_as(&owner);
+    contract
+        .remove_liquidity_by_shares((2_000 * ONE_USDN).into())
+        .unwrap();
+
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    let token2_wallet = contract.token2_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.liquidity, (250 * ONE_USDN + 2).into());
+    assert_eq!(token2_wallet.liquidity, (1_000 * ONE_USDT).into());
+    assert_eq!(token1_wallet.deposit, (10_000 * ONE_USDN + 4).into());
+    assert_eq!(token2_wallet.deposit, (40_000 * ONE_USDT).into());
+    assert_eq!(token1_wallet.accounts_deposit, (250 * ONE_USDN + 1).into());
+
+    assert_eq!(contract.get_lp_balance(bob), (500 * ONE_USDN).into());
+    assert_eq!(contract.get_lp_balance(owner), 0.into());
+    assert_eq!(contract.get_lp_total_supply(), (500 * ONE_USDN).into());
+}
+
+#[test]
+fn test_swap_interleaved_with_liquidity_removal() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    register_account(&mut contract, &bob);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.deposit = (1_000 * ONE_USDT).into();
+    token1_wallet.accounts_deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+    contract
+        .internal_deposit(&bob, &usdn, 1_000 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&bob, &usdt, 1_000 * ONE_USDT)
+        .unwrap();
+
+    // Owner & bob provide equal liquidity
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()], None)
+        .unwrap();
+    call_as(&bob);
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()], None)
+        .unwrap();
+
+    // Bob starts a swap, its output is reserved right away
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(usdn.clone())
+        .build());
+    let res = contract.ft_on_transfer(
+        bob.clone(),
+        (100 * ONE_USDN).into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+    drop(res);
+    let args = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "on_swap_complete" => {
+                serde_json::from_slice::<serde_json::Value>(&args).ok()
+            }
+            _ => None,
+        })
+        .unwrap();
+    let amounts: SwapAmounts = serde_json::from_value(args["amounts"].clone()).unwrap();
+    let amount_out = amounts.amount_out.0;
+    assert_eq!(
+        contract.get_reserves(),
+        Ok([2_000 * ONE_USDN, 2_000 * ONE_USDT - amount_out])
+    );
+
+    // Bob removes all of his liquidity before the swap completes
+    call_as(&bob);
+    contract
+        .remove_liquidity_by_shares((1_000 * ONE_USDN).into())
+        .unwrap();
+
+    // Completed swap only credits its input, so the removal isn't undone while the shares stay burned
+    contract.on_swap_complete(bob.clone(), usdn.clone(), usdt.clone(), amounts, Ok(()));
+    assert_eq!(contract.get_lp_balance(bob.clone()), 0.into());
+    assert_eq!(contract.get_lp_total_supply(), (1_000 * ONE_USDN).into());
+
+    // Tracked amounts match the pool balances: the deposits, the swap input in & the output out
+    let reserves = contract.get_reserves().unwrap();
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    let token2_wallet = contract.token2_wallet.as_ref().unwrap();
+    assert_eq!(
+        reserves[0] + token1_wallet.accounts_deposit.0,
+        2_100 * ONE_USDN
+    );
+    assert_eq!(
+        reserves[1] + token2_wallet.accounts_deposit.0,
+        2_000 * ONE_USDT - amount_out
+    );
+    assert_eq!(
+        contract.get_account_balance(bob.clone(), usdn).0,
+        token1_wallet.accounts_deposit.0
+    );
+    assert_eq!(
+        contract.get_account_balance(bob, usdt).0,
+        token2_wallet.accounts_deposit.0
+    );
+}
+
+#[test]
+fn test_contract_snapshot() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let context = |predecessor: &str| {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(ONE_YOCTO)
+            .build()
+    };
+    near_sdk::testing_env!(context(owner.as_str()));
+
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.deposit = (1_000 * ONE_USDN).into();
+    token2_wallet.deposit = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+    contract
+        .add_liquidity([(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()], None)
+        .unwrap();
+
+    let snapshot = ContractSnapshot::take(&contract, context(owner.as_str()));
+
+    let swap = |contract: &mut crate::Contract| {
+        for (token_id, amount_in) in [
+            ("usdn.testnet", 100 * ONE_USDN),
+            ("usdt.fakes.testnet", 37 * ONE_USDT),
+            ("usdn.testnet", 3 * ONE_USDN),
+        ] {
+            near_sdk::testing_env!(context(token_id));
+            let res = contract.ft_on_transfer(
+                "user.near".parse().unwrap(),
+                amount_in.into(),
+                r#"{"type":"swap"}"#.to_string(),
+            );
+            assert!(matches!(res, PromiseOrValue::Promise(_)));
+            // Dropped promise is scheduled
+            drop(res);
+
+            // Output transfer succeeds
+            let args = get_created_receipts()
+                .into_iter()
+                .flat_map(|receipt| receipt.actions)
+                .find_map(|action| match action {
+                    VmAction::FunctionCall {
+                        function_name,
+                        args,
+                        ..
+                    } if function_name == "on_swap_complete" => {
+                        serde_json::from_slice::<serde_json::Value>(&args).ok()
+                    }
+                    _ => None,
+                })
+                .unwrap();
+            let arg = |name: &str| args[name].clone();
+            contract.on_swap_complete(
+                serde_json::from_value(arg("sender_id")).unwrap(),
+                serde_json::from_value(arg("token_id_in")).unwrap(),
+                serde_json::from_value(arg("token_id_out")).unwrap(),
+                serde_json::from_value(arg("amounts")).unwrap(),
+                Ok(()),
+            );
+        }
+
+        (
+            contract.get_reserves().unwrap(),
+            contract.try_to_vec().unwrap(),
+        )
+    };
+    let swapped = swap(&mut contract);
+    assert_ne!(swapped.0, [1_000 * ONE_USDN, 1_000 * ONE_USDT]);
+
+    // Restored state, including collections, matches the snapshot and swaps identically
+    let mut restored = snapshot.restore(context(owner.as_str()));
+    assert_eq!(
+        restored.get_reserves(),
+        Ok([1_000 * ONE_USDN, 1_000 * ONE_USDT])
+    );
+    assert_eq!(restored.get_lp_balance(owner), (1_000 * ONE_USDN).into());
+    assert_eq!(swap(&mut restored), swapped);
+}
+
+#[test]
+fn test_owner_withdraw_deposit() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet(usdn.as_str(), 6));
+    contract.token2_wallet = Some(test_token_wallet("usdt.fakes.testnet", 6));
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(usdn.clone())
+        .build());
+    let res = contract.ft_on_transfer(owner.clone(), (100 * ONE_USDN).into(), String::new());
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    assert!(contract
+        .owner_withdraw_deposit(usdn.clone(), (101 * ONE_USDN).into())
+        .is_err());
+
+    // Deposit is reserved upfront, while tokens are transferred to the owner
+    drop(
+        contract
+            .owner_withdraw_deposit(usdn.clone(), (40 * ONE_USDN).into())
+            .unwrap(),
+    );
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().deposit,
+        (60 * ONE_USDN).into()
+    );
+    let receipt = get_created_receipts().into_iter().next().unwrap();
+    assert_eq!(receipt.receiver_id, usdn);
+    assert!(matches!(
+        &receipt.actions[0],
+        VmAction::FunctionCall { function_name, args, deposit, .. }
+            if function_name == "ft_transfer"
+                && *deposit == ONE_YOCTO
+                && serde_json::from_slice::<serde_json::Value>(args).unwrap()
+                    == json!({ "receiver_id": owner, "amount": (40 * ONE_USDN).to_string(), "memo": null })
+    ));
+
+    // Successful transfer keeps the deposit decreased
+    assert!(contract.on_deposit_withdrawn(usdn.clone(), (40 * ONE_USDN).into(), Ok(())));
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().deposit,
+        (60 * ONE_USDN).into()
+    );
+
+    // Failed transfer restores the deposit
+    drop(
+        contract
+            .owner_withdraw_deposit(usdn.clone(), (60 * ONE_USDN).into())
+            .unwrap(),
+    );
+    assert_eq!(contract.token1_wallet.as_ref().unwrap().deposit, 0.into());
+    assert!(!contract.on_deposit_withdrawn(
+        usdn,
+        (60 * ONE_USDN).into(),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().deposit,
+        (60 * ONE_USDN).into()
+    );
+}
+
+#[test]
+fn test_protocol_fees() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let mut contract =
+        crate::Contract::init(Some(owner.clone()), None, None, Some(100), None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    assert_eq!(
+        contract.set_protocol_fee_bps(10_001),
+        Err("Protocol fee exceeds 100%")
+    );
+    contract.set_protocol_fee_bps(5_000).unwrap();
+    assert_eq!(contract.get_config().protocol_fee_bps, 5_000);
+    assert_eq!(
+        contract.withdraw_protocol_fees(usdn.clone()).err(),
+        Some("No protocol fees to withdraw")
+    );
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(usdn.clone())
+        .build());
+    let res = contract.ft_on_transfer(
+        "user.near".parse().unwrap(),
+        (100 * ONE_USDN).into(),
+        r#"{"type":"swap"}"#.to_string(),
+    );
+    drop(res);
+
+    // Half of 1% fee is routed to the protocol, the rest stays in the pool liquidity
+    let args = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "on_swap_complete" => {
+                serde_json::from_slice::<serde_json::Value>(&args).ok()
+            }
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(args["amounts"]["fee"], json!(ONE_USDN.to_string()));
+    assert_eq!(
+        args["amounts"]["protocol_fee"],
+        json!((ONE_USDN / 2).to_string())
+    );
+    let arg = |name: &str| args[name].clone();
+    contract.on_swap_complete(
+        serde_json::from_value(arg("sender_id")).unwrap(),
+        serde_json::from_value(arg("token_id_in")).unwrap(),
+        serde_json::from_value(arg("token_id_out")).unwrap(),
+        serde_json::from_value(arg("amounts")).unwrap(),
+        Ok(()),
+    );
+    assert_eq!(
+        contract.get_protocol_fees(),
+        [(ONE_USDN / 2).into(), 0.into()]
+    );
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().liquidity,
+        (1_000 * ONE_USDN + 100 * ONE_USDN - ONE_USDN / 2).into()
+    );
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    drop(contract.withdraw_protocol_fees(usdn.clone()).unwrap());
+    let receipt = get_created_receipts().into_iter().next().unwrap();
+    assert_eq!(receipt.receiver_id, usdn);
+    assert!(matches!(
+        &receipt.actions[0],
+        VmAction::FunctionCall { function_name, args, .. }
+            if function_name == "ft_transfer"
+                && serde_json::from_slice::<serde_json::Value>(args).unwrap()
+                    == json!({ "receiver_id": owner, "amount": (ONE_USDN / 2).to_string(), "memo": null })
+    ));
+
+    // Accrued fees are kept until the transfer succeeds
+    assert!(!contract.on_protocol_fees_withdrawn(
+        usdn.clone(),
+        (ONE_USDN / 2).into(),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    assert_eq!(
+        contract.get_protocol_fees(),
+        [(ONE_USDN / 2).into(), 0.into()]
+    );
+
+    assert!(contract.on_protocol_fees_withdrawn(usdn, (ONE_USDN / 2).into(), Ok(())));
+    assert_eq!(contract.get_protocol_fees(), [0.into(), 0.into()]);
+}
+
+#[test]
+fn test_fee_mode() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let reserve = 100_000 * ONE_USDN;
+    let setup = |fee_mode: FeeMode| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = crate::Contract::init(
+            Some(owner.clone()),
+            None,
+            None,
+            Some(100),
+            None,
+            Some(fee_mode),
+        );
+        let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+        let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+        token1_wallet.liquidity = reserve.into();
+        token2_wallet.liquidity = reserve.into();
+        contract.token1_wallet = Some(token1_wallet);
+        contract.token2_wallet = Some(token2_wallet);
+        contract.backing_verified = true;
+        contract
+            .update_config(ContractConfigPatch {
+                protocol_fee_bps: Some(5_000),
+                ..Default::default()
+            })
+            .unwrap();
+        contract
+    };
+
+    // Swaps 1000 USDN, returns amounts passed to the callback
+    let swap = |contract: &mut crate::Contract| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(usdn.clone())
+            .build());
+        let res = contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            (1_000 * ONE_USDN).into(),
+            r#"{"type":"swap"}"#.to_string(),
+        );
+        drop(res);
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "on_swap_complete" => {
+                    serde_json::from_slice::<serde_json::Value>(&args).ok()
+                }
+                _ => None,
+            })
+            .map(|args| serde_json::from_value::<SwapAmounts>(args["amounts"].clone()).unwrap())
+            .unwrap()
+    };
+    let complete = |contract: &mut crate::Contract, amounts, transfer_result| {
+        contract.on_swap_complete(
+            "user.near".parse().unwrap(),
+            usdn.clone(),
+            usdt.clone(),
+            amounts,
+            transfer_result,
+        );
+    };
+
+    let mut on_input = setup(FeeMode::OnInput);
+    let mut on_output = setup(FeeMode::OnOutput);
+    assert_eq!(on_input.get_config().fee_mode, FeeMode::OnInput);
+    assert_eq!(on_output.get_config().fee_mode, FeeMode::OnOutput);
+    let estimates = [&on_input, &on_output].map(|contract| {
+        contract
+            .get_swap_estimate(usdn.clone(), (1_000 * ONE_USDN).into())
+            .unwrap()
+    });
+
+    // Fee deducted from the input isn't priced
+    let amounts = swap(&mut on_input);
+    let fee = 10 * ONE_USDN;
+    assert_eq!(amounts.fee, fee.into());
+    assert_eq!(amounts.protocol_fee, (fee / 2).into());
+    assert_eq!(
+        amounts.amount_out.0,
+        compute_amount_out(reserve, 6, reserve, 6, 1_000 * ONE_USDN - fee).unwrap()
+    );
+    assert_eq!(amounts.amount_out, estimates[0]);
+    let amount_out_on_input = amounts.amount_out.0;
+    complete(&mut on_input, amounts, Ok(()));
+    assert_eq!(on_input.get_protocol_fees(), [(fee / 2).into(), 0.into()]);
+    assert_eq!(
+        on_input.get_reserves().unwrap(),
+        [
+            reserve + 1_000 * ONE_USDN - fee / 2,
+            reserve - amount_out_on_input
+        ]
+    );
+
+    // Whole input is priced and the fee is deducted from the output
+    let amounts = swap(&mut on_output);
+    let amount_out_priced = compute_amount_out(reserve, 6, reserve, 6, 1_000 * ONE_USDN).unwrap();
+    let amount_out_on_output = deduct_fee(amount_out_priced, 100).unwrap();
+    let fee = amount_out_priced - amount_out_on_output;
+    assert_eq!(amounts.amount_out, amount_out_on_output.into());
+    assert_eq!(amounts.amount_out, estimates[1]);
+    assert_eq!(amounts.fee, fee.into());
+    assert_eq!(amounts.protocol_fee, (fee / 2).into());
+    complete(&mut on_output, amounts, Ok(()));
+    assert_eq!(on_output.get_protocol_fees(), [0.into(), (fee / 2).into()]);
+    assert_eq!(
+        on_output.get_reserves().unwrap(),
+        [
+            reserve + 1_000 * ONE_USDN,
+            reserve - amount_out_on_output - fee / 2
+        ]
+    );
+
+    // Fee taken from the output of the priced input is a bit larger for the same trade
+    assert!(amount_out_on_output < amount_out_on_input);
+    assert!(amount_out_on_input - amount_out_on_output < ONE_USDT / 5);
+    for contract in [&on_input, &on_output] {
+        assert!(is_ratio_preserved(
+            [reserve, reserve],
+            contract.get_reserves().unwrap()
+        ));
+    }
+
+    // Failed output transfer returns the output along with the protocol fee taken from it
+    let reserves = on_output.get_reserves().unwrap();
+    let amounts = swap(&mut on_output);
+    complete(&mut on_output, amounts, Err(near_sdk::PromiseError::Failed));
+    assert_eq!(on_output.get_reserves().unwrap(), reserves);
+    assert_eq!(on_output.get_protocol_fees(), [0.into(), (fee / 2).into()]);
+}
+
+#[test]
+fn test_auto_compound_protocol_fees() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let setup = |auto_compound: bool| {
+        let mut contract =
+            crate::Contract::init(Some(owner.clone()), None, None, Some(100), None, None);
+        let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+        let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+        token1_wallet.liquidity = (100_000 * ONE_USDN).into();
+        token2_wallet.liquidity = (100_000 * ONE_USDT).into();
+        contract.token1_wallet = Some(token1_wallet);
+        contract.token2_wallet = Some(token2_wallet);
+        contract.backing_verified = true;
+        contract.total_lp_shares = 100_000 * ONE_USDN;
+        contract.owner_lp_shares = 100_000 * ONE_USDN;
+
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract
+            .update_config(ContractConfigPatch {
+                protocol_fee_bps: Some(5_000),
+                auto_compound: Some(auto_compound),
+                auto_compound_threshold: Some([(2 * ONE_USDN).into(), (2 * ONE_USDT).into()]),
+                ..Default::default()
+            })
+            .unwrap();
+        contract
+    };
+
+    // Swaps 100 tokens, each accruing half of the 1% fee to the protocol
+    let swap = |contract: &mut crate::Contract, token_id_in: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id_in.clone())
+            .build());
+        let res = contract.ft_on_transfer(
+            "user.near".parse().unwrap(),
+            (100 * ONE_USDN).into(),
+            r#"{"type":"swap"}"#.to_string(),
+        );
+        drop(res);
+        let args = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "on_swap_complete" => {
+                    serde_json::from_slice::<serde_json::Value>(&args).ok()
+                }
+                _ => None,
+            })
+            .unwrap();
+        let arg = |name: &str| args[name].clone();
+        contract.on_swap_complete(
+            serde_json::from_value(arg("sender_id")).unwrap(),
+            serde_json::from_value(arg("token_id_in")).unwrap(),
+            serde_json::from_value(arg("token_id_out")).unwrap(),
+            serde_json::from_value(arg("amounts")).unwrap(),
+            Ok(()),
+        );
+    };
+
+    let mut contract = setup(true);
+    let config = contract.get_config();
+    assert!(config.auto_compound);
+    assert_eq!(
+        config.auto_compound_threshold,
+        [(2 * ONE_USDN).into(), (2 * ONE_USDT).into()]
+    );
+    let mut plain_contract = setup(false);
+
+    // Fees below the threshold of either token stay accrued
+    for token_id_in in [&usdn, &usdn, &usdn, &usdn, &usdt, &usdt, &usdt] {
+        swap(&mut contract, token_id_in);
+        swap(&mut plain_contract, token_id_in);
+    }
+    assert_eq!(
+        contract.get_protocol_fees(),
+        [(2 * ONE_USDN).into(), (3 * ONE_USDT / 2).into()]
+    );
+    assert_eq!(contract.get_reserves(), plain_contract.get_reserves());
+    assert_eq!(contract.owner_lp_shares, 100_000 * ONE_USDN);
+
+    // Once both fees reach the threshold, their part proportional to the reserves is folded into the liquidity
+    swap(&mut contract, &usdt);
+    swap(&mut plain_contract, &usdt);
+    let protocol_fees = contract.get_protocol_fees().map(|fee| fee.0);
+    let plain_protocol_fees = plain_contract.get_protocol_fees().map(|fee| fee.0);
+    assert_eq!(plain_protocol_fees, [2 * ONE_USDN, 2 * ONE_USDT]);
+    assert!(protocol_fees[0] == 0 || protocol_fees[1] == 0);
+
+    let reserves = contract.get_reserves().unwrap();
+    let plain_reserves = plain_contract.get_reserves().unwrap();
+    for index in 0..2 {
+        let folded = plain_protocol_fees[index] - protocol_fees[index];
+        assert!(folded > 0);
+        assert_eq!(reserves[index], plain_reserves[index] + folded);
+    }
+
+    // Shares for the folded fees are minted to the owner
+    assert!(contract.owner_lp_shares > 100_000 * ONE_USDN);
+    assert_eq!(contract.total_lp_shares, contract.owner_lp_shares);
+    assert_eq!(plain_contract.owner_lp_shares, 100_000 * ONE_USDN);
+}
+
+#[test]
+fn test_estimate_dilution() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    assert_eq!(
+        contract.estimate_dilution([ONE_USDN.into(), ONE_USDT.into()]),
+        "0.00"
+    );
+
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.total_lp_shares = 1_000 * ONE_USDN;
+
+    assert_eq!(contract.estimate_dilution([0.into(), 0.into()]), "0.00");
+
+    // Doubling the pool halves the pool fraction of every existing share
+    assert_eq!(
+        contract.estimate_dilution([(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()]),
+        "50.00"
+    );
+
+    // One-sided add mints sqrt(1.1) - 1 ~ 4.88% of shares for the liquidity value growth
+    assert_eq!(
+        contract.estimate_dilution([(100 * ONE_USDN).into(), 0.into()]),
+        "4.65"
+    );
+}
+
+#[test]
+fn test_get_spot_price() {
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet(usdn.as_str(), 6));
+    contract.token2_wallet = Some(test_token_wallet(usdt.as_str(), 6));
+    assert_eq!(contract.get_spot_price(usdn.clone()), Err("Pool is empty"));
+
+    contract.token1_wallet.as_mut().unwrap().liquidity = (50_000 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (50_000 * ONE_USDT).into();
+    assert_eq!(contract.get_spot_price(usdn.clone()), Ok(ONE_USDT.into()));
+    assert_eq!(contract.get_spot_price(usdt.clone()), Ok(ONE_USDN.into()));
+    assert!(contract
+        .get_spot_price("unknown.testnet".parse().unwrap())
+        .is_err());
+
+    // Price of 1 ETH is scaled by ETH decimals
+    contract.token1_wallet = Some(test_token_wallet("eth.testnet", 18));
+    contract.token1_wallet.as_mut().unwrap().liquidity = (50 * ONE_ETH).into();
+    assert_eq!(
+        contract.get_spot_price("eth.testnet".parse().unwrap()),
+        Ok((1_000 * ONE_ETH).into())
+    );
+    assert_eq!(contract.get_spot_price(usdt), Ok((ONE_USDT / 1_000).into()));
+}
+
+#[test]
+fn test_add_liquidity_keeps_spot_price() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (50_000 * ONE_USDN).into();
+    token1_wallet.deposit = (10_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (25_000 * ONE_USDT).into();
+    token2_wallet.deposit = (10_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    let spot_prices = |contract: &crate::Contract| {
+        [&usdn, &usdt].map(|token_id| contract.get_spot_price(token_id.clone()).unwrap().0)
+    };
+    let prices_before = spot_prices(&contract);
+    assert_eq!(prices_before, [ONE_USDT / 2, 2 * ONE_USDN]);
+
+    // Proportional amounts, which don't divide evenly, move the price by rounding at most
+    contract
+        .add_liquidity(
+            [
+                (7_777 * ONE_USDN + 1).into(),
+                (3_888 * ONE_USDT + 500_000).into(),
+            ],
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        contract.get_reserves(),
+        Ok([57_777 * ONE_USDN + 1, 28_888 * ONE_USDT + 500_000])
+    );
+
+    for (price_after, price_before) in spot_prices(&contract).into_iter().zip(prices_before) {
+        assert!(price_after.abs_diff(price_before) <= 1);
+    }
+}
+
+#[test]
+fn test_override_decimals() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(Some(owner), None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet(usdn.as_str(), 6));
+    contract.token2_wallet = Some(test_token_wallet(usdt.as_str(), 6));
+    contract.token1_wallet.as_mut().unwrap().liquidity = (50_000 * ONE_USDN).into();
+    contract.token2_wallet.as_mut().unwrap().liquidity = (50_000 * ONE_USDT).into();
+    let amount_out = contract.get_amount_out(usdn.clone(), (100 * ONE_USDN).into());
+    assert_eq!(contract.get_spot_price(usdn.clone()), Ok(ONE_USDT.into()));
+
+    assert_eq!(contract.override_decimals(usdt.clone(), 8), Ok(()));
+    assert_eq!(
+        contract.override_decimals("unknown.testnet".parse().unwrap(), 8),
+        Err("Token is not supported")
+    );
+
+    // Prices are expressed in the overridden decimals, swap math is unchanged
+    assert_eq!(
+        contract.get_spot_price(usdn.clone()),
+        Ok((ONE_USDT / 100).into())
+    );
+    assert_eq!(
+        contract.get_marginal_price(usdn.clone()),
+        Ok("0.010000000000".to_string())
+    );
+    assert_eq!(contract.get_decimals(usdt), Ok(6));
+    assert_eq!(
+        contract.get_amount_out(usdn, (100 * ONE_USDN).into()),
+        amount_out
+    );
+}
+
+#[test]
+fn test_get_deposits() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    assert_eq!(contract.get_deposits(), Err("Token1 wallet is not created"));
+
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(test_token_wallet("usdt.fakes.testnet", 6));
+
+    // Deposits are reported without liquidity
+    for (token_id, amount) in [
+        ("usdn.testnet", 100 * ONE_USDN),
+        ("usdt.fakes.testnet", 7 * ONE_USDT),
+        ("usdn.testnet", 5 * ONE_USDN),
+    ] {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id.parse().unwrap())
+            .build());
+        contract.ft_on_transfer(owner.clone(), amount.into(), String::new());
+    }
+
+    assert_eq!(
+        contract.get_deposits(),
+        Ok([(105 * ONE_USDN).into(), (7 * ONE_USDT).into()])
+    );
+}
+
+#[test]
+fn test_verify_deposits() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(test_token_wallet(usdt.as_str(), 6));
+    contract
+        .update_config(ContractConfigPatch {
+            verify_deposits: Some([true, false]),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(contract.get_config().tokens.unwrap()[0].verify_deposits);
+
+    let deposit = |contract: &mut crate::Contract, token_id: &near_sdk::AccountId, amount: u128| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id.clone())
+            .build());
+        contract.ft_on_transfer(owner.clone(), amount.into(), String::new())
+    };
+    let verify_deposit = |contract: &mut crate::Contract, amount: u128, balance: u128| {
+        near_sdk::testing_env!(VMContextBuilder::new().build());
+        let res = contract.on_deposit_verified(usdn.clone(), amount.into(), Ok(balance.into()));
+        assert_eq!(res, 0.into());
+        get_logs()
+    };
+
+    // Deposit of a token without verification is accepted right away
+    let res = deposit(&mut contract, &usdt, 10 * ONE_USDT);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+
+    // Deposit of a verified token queries the pool balance at the token contract
+    let res = deposit(&mut contract, &usdn, 100 * ONE_USDN);
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+    drop(res);
+    let function_names: Vec<_> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall { function_name, .. } => Some(function_name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(function_names, vec!["ft_balance_of", "on_deposit_verified"]);
+
+    // Without an observed balance the increase is unknown, the balance becomes the baseline
+    assert_eq!(
+        verify_deposit(&mut contract, 100 * ONE_USDN, 1_150 * ONE_USDN),
+        vec![format!(
+            "Deposit of `{}` isn't verified, no previous balance is observed",
+            usdn
+        )]
+    );
+
+    // Standard token delivered the whole amount
+    deposit(&mut contract, &usdn, 100 * ONE_USDN);
+    assert!(verify_deposit(&mut contract, 100 * ONE_USDN, 1_250 * ONE_USDN).is_empty());
+
+    // Deflationary token burned 1% of the transfer, which 50 USDN held untracked, e.g. in escrow, don't hide
+    deposit(&mut contract, &usdn, 100 * ONE_USDN);
+    assert_eq!(
+        verify_deposit(&mut contract, 100 * ONE_USDN, 1_349 * ONE_USDN),
+        vec![format!(
+            "Deposit shortfall: {} `{}` of the deposited {} wasn't received",
+            ONE_USDN,
+            usdn,
+            100 * ONE_USDN
+        )]
+    );
+
+    // Backing check observes balances as well
+    near_sdk::testing_env!(VMContextBuilder::new().build());
+    contract.on_backing_verified(Ok((1_400 * ONE_USDN).into()), Ok((10 * ONE_USDT).into()));
+    deposit(&mut contract, &usdn, 100 * ONE_USDN);
+    assert!(verify_deposit(&mut contract, 100 * ONE_USDN, 1_500 * ONE_USDN).is_empty());
+    assert_eq!(
+        contract
+            .get_token_wallet(&usdn)
+            .unwrap()
+            .balance_observation,
+        Some(BalanceObservation {
+            balance: (1_500 * ONE_USDN).into(),
+            tracked: (1_400 * ONE_USDN).into(),
+        })
+    );
+}
+
+#[test]
+fn test_resolve_stuck_swap() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new().block_index(7).build());
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+
+    // Failed output transfer of the executed committed swap is refunded from escrow
+    let res = contract.on_committed_swap_complete(
+        user.clone(),
+        usdn.clone(),
+        (20 * ONE_USDN).into(),
+        Ok((10 * ONE_USDN).into()),
+    );
+    assert!(matches!(res, PromiseOrValue::Promise(_)));
+    drop(res);
+    let function_names: Vec<_> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .filter_map(|action| match action {
+            VmAction::FunctionCall { function_name, .. } => Some(function_name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(function_names, vec!["ft_transfer", "on_escrow_refunded"]);
+
+    // Successful refund isn't recorded, failed one is
+    contract.on_escrow_refunded(user.clone(), usdn.clone(), (10 * ONE_USDN).into(), Ok(()));
+    assert_eq!(contract.get_failed_swap(0), None);
+
+    contract.on_escrow_refunded(
+        user.clone(),
+        usdn.clone(),
+        (10 * ONE_USDN).into(),
+        Err(near_sdk::PromiseError::Failed),
+    );
+    let failed_swap = FailedSwap {
+        account_id: user.clone(),
+        token_id_in: usdn.clone(),
+        amount_in: (10 * ONE_USDN).into(),
+        block_height: 7.into(),
+        unresolved: false,
+    };
+    assert_eq!(contract.get_failed_swap(0), Some(failed_swap.clone()));
+
+    // Owner refunds the input to the user explicitly
+    let as_owner = || {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(owner.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+    as_owner();
+    assert_eq!(
+        contract.resolve_stuck_swap(1).err(),
+        Some("Failed swap is not found")
+    );
+    let res = contract.resolve_stuck_swap(0);
+    assert!(res.is_ok());
+    drop(res);
+    let receipts = get_created_receipts();
+    assert_eq!(receipts[0].receiver_id, usdn);
+    assert_matches!(
+        &receipts[0].actions[..],
+        [VmAction::FunctionCall { function_name, args, .. }] if function_name == "ft_transfer"
+            && serde_json::from_slice::<serde_json::Value>(args).unwrap()
+                == json!({ "receiver_id": user, "amount": (10 * ONE_USDN).to_string(), "memo": null })
+    );
+    assert_eq!(contract.get_failed_swap(0), None);
+
+    // Failed refund restores the record, so it can be resolved later
+    assert!(!contract.on_stuck_swap_resolved(
+        0,
+        failed_swap.clone(),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    assert_eq!(contract.get_failed_swap(0), Some(failed_swap.clone()));
+
+    contract.resolve_stuck_swap(0).unwrap();
+    assert!(contract.on_stuck_swap_resolved(0, failed_swap, Ok(())));
+    assert_eq!(contract.get_failed_swap(0), None);
+
+    // Unknown outcome of the executed committed swap is held unresolved, so it isn't refunded
+    let unknown_outcome = |contract: &mut crate::Contract| {
+        let res = contract.on_committed_swap_complete(
+            user.clone(),
+            usdn.clone(),
+            (20 * ONE_USDN).into(),
+            Err(near_sdk::PromiseError::Failed),
+        );
+        assert!(matches!(res, PromiseOrValue::Value(())));
+    };
+    unknown_outcome(&mut contract);
+    assert_eq!(
+        contract.get_failed_swap(1),
+        Some(FailedSwap {
+            account_id: user.clone(),
+            token_id_in: usdn.clone(),
+            amount_in: (20 * ONE_USDN).into(),
+            block_height: 0.into(),
+            unresolved: true,
+        })
+    );
+    as_owner();
+    assert_eq!(
+        contract.resolve_stuck_swap(1).err(),
+        Some("Failed swap is unresolved")
+    );
+
+    // Owner settles the part of the input found owed, which becomes refundable
+    assert_eq!(
+        contract.settle_unresolved_swap(1, (21 * ONE_USDN).into()),
+        Err("Owed amount exceeds escrowed input")
+    );
+    contract
+        .settle_unresolved_swap(1, (5 * ONE_USDN).into())
+        .unwrap();
+    assert_eq!(
+        contract.settle_unresolved_swap(1, 0.into()),
+        Err("Failed swap is already resolved")
+    );
+    assert_matches!(
+        contract.get_failed_swap(1),
+        Some(FailedSwap { amount_in, unresolved: false, .. }) if amount_in == (5 * ONE_USDN).into()
+    );
+    assert!(contract.resolve_stuck_swap(1).is_ok());
+
+    // Swap found completed owes nothing, so its record is dropped
+    unknown_outcome(&mut contract);
+    as_owner();
+    contract.settle_unresolved_swap(2, 0.into()).unwrap();
+    assert_eq!(contract.get_failed_swap(2), None);
+}
+
+#[test]
+fn test_legacy_account_upgrade() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract.accounts.insert(
+        &user,
+        &crate::account::VAccount::V0(crate::account::AccountV0 {
+            storage_balance: ONE_NEAR,
+            storage_usage: 100,
+        }),
+    );
+
+    let account = contract.get_account(&user).unwrap();
+    assert_eq!(account.storage_balance, ONE_NEAR);
+    assert_eq!(account.storage_usage, 136);
+    assert_eq!(account.lp_shares, 0);
+    assert!(account.balances.is_empty());
+    assert_eq!(account.lp_entry_value, 0);
+
+    contract.accounts.insert(
+        &user,
+        &crate::account::VAccount::V1(crate::account::AccountV1 {
+            storage_balance: ONE_NEAR,
+            storage_usage: 100,
+            lp_shares: 7,
+        }),
+    );
+
+    let account = contract.get_account(&user).unwrap();
+    assert_eq!(account.storage_usage, 120);
+    assert_eq!(account.lp_shares, 7);
+    assert!(account.balances.is_empty());
+
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    contract.accounts.insert(
+        &user,
+        &crate::account::VAccount::V2(crate::account::AccountV2 {
+            storage_balance: ONE_NEAR,
+            storage_usage: 100,
+            lp_shares: 7,
+            balances: [(usdn.clone(), ONE_USDN)].into_iter().collect(),
+        }),
+    );
+
+    let account = contract.get_account(&user).unwrap();
+    assert_eq!(account.storage_usage, 116);
+    assert_eq!(account.lp_shares, 7);
+    assert_eq!(account.balance(&usdn), ONE_USDN);
+    assert_eq!(account.lp_entry_value, 0);
+}
+
+#[test]
+fn test_account_balance() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    assert_eq!(
+        contract.internal_deposit(&user, &usdn, ONE_USDN),
+        Err("Account is not registered")
+    );
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        0.into()
+    );
+
+    // The first deposit of a token requires storage for its balance entry
+    contract.accounts.insert(
+        &user,
+        &ContractAccount::new(
+            &user,
+            Some(ContractAccount::required_deposit(Some(&user)).0),
+        )
+        .into(),
+    );
+    assert_eq!(
+        contract.internal_deposit(&user, &usdn, ONE_USDN),
+        Err("Insufficient storage balance")
+    );
+
+    register_account(&mut contract, &user);
+
+    let storage_usage_before = near_sdk::env::storage_usage();
+    contract.internal_deposit(&user, &usdn, ONE_USDN).unwrap();
+    let storage_usage = near_sdk::env::storage_usage() - storage_usage_before;
+    assert_eq!(
+        contract.get_account(&user).unwrap().storage_usage,
+        ContractAccount::initial_storage_usage(Some(&user)) + storage_usage
+    );
+
+    contract
+        .internal_deposit(&user, &usdn, 2 * ONE_USDN)
+        .unwrap();
+    contract.internal_deposit(&user, &usdt, ONE_USDT).unwrap();
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        (3 * ONE_USDN).into()
+    );
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdt.clone()),
+        ONE_USDT.into()
+    );
+
+    assert_eq!(
+        contract.internal_withdraw(&user, &usdt, 2 * ONE_USDT),
+        Err("Insufficient balance")
+    );
+    contract.internal_withdraw(&user, &usdn, ONE_USDN).unwrap();
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        (2 * ONE_USDN).into()
+    );
+
+    // Emptied balances release their storage
+    contract
+        .internal_withdraw(&user, &usdn, 2 * ONE_USDN)
+        .unwrap();
+    contract.internal_withdraw(&user, &usdt, ONE_USDT).unwrap();
+    let account = contract.get_account(&user).unwrap();
+    assert!(account.balances.is_empty());
+    assert_eq!(
+        account.storage_usage,
+        ContractAccount::initial_storage_usage(Some(&user))
+    );
+}
+
+#[test]
+fn test_account_deposit() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new().build());
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    token1_wallet.transfer_memo = Some("swap pool".to_string());
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(test_token_wallet(usdt.as_str(), 6));
+
+    let deposit =
+        |contract: &mut crate::Contract, sender_id: &near_sdk::AccountId, amount: u128| {
+            near_sdk::testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(usdn.clone())
+                .build());
+            contract.ft_on_transfer(sender_id.clone(), amount.into(), String::new())
+        };
+
+    // Deposit of an unregistered account is refunded
+    let res = deposit(&mut contract, &user, 10 * ONE_USDN);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (10 * ONE_USDN).into()));
+    assert!(get_logs().contains(&"Transfer failed. Error: Account is not registered".to_string()));
+
+    // Deposit of a registered account is credited to its balance, the owner's one to the wallet deposit
+    register_account(&mut contract, &user);
+    let res = deposit(&mut contract, &user, 10 * ONE_USDN);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+    deposit(&mut contract, &owner, 5 * ONE_USDN);
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        (10 * ONE_USDN).into()
+    );
+    let token1_wallet = contract.token1_wallet.as_ref().unwrap();
+    assert_eq!(token1_wallet.accounts_deposit, (10 * ONE_USDN).into());
+    assert_eq!(token1_wallet.deposit, (5 * ONE_USDN).into());
+
+    // Withdrawal transfers the balance back with the token memo
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    assert_eq!(
+        contract
+            .withdraw_deposit(usdn.clone(), (11 * ONE_USDN).into())
+            .err(),
+        Some("Insufficient balance")
+    );
+    let res = contract.withdraw_deposit(usdn.clone(), (4 * ONE_USDN).into());
+    assert!(res.is_ok());
+    drop(res);
+    let receipts = get_created_receipts();
+    assert_eq!(receipts[0].receiver_id, usdn);
+    assert_matches!(
+        &receipts[0].actions[..],
+        [VmAction::FunctionCall { function_name, args, .. }] if function_name == "ft_transfer"
+            && serde_json::from_slice::<serde_json::Value>(args).unwrap()
+                == json!({ "receiver_id": user, "amount": (4 * ONE_USDN).to_string(), "memo": "swap pool" })
+    );
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        (6 * ONE_USDN).into()
+    );
+
+    // Failed transfer restores the balance
+    assert!(!contract.on_account_deposit_withdrawn(
+        user.clone(),
+        usdn.clone(),
+        (4 * ONE_USDN).into(),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    assert_eq!(
+        contract.get_account_balance(user.clone(), usdn.clone()),
+        (10 * ONE_USDN).into()
+    );
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().accounts_deposit,
+        (10 * ONE_USDN).into()
+    );
+
+    // Deposit of a registered account beyond the cap is refunded, the one within it is accepted
+    contract
+        .token1_wallet
+        .as_mut()
+        .unwrap()
+        .max_deposit_per_account = Some((12 * ONE_USDN).into());
+    let res = deposit(&mut contract, &user, 3 * ONE_USDN);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == (3 * ONE_USDN).into()));
+    assert!(get_logs()
+        .contains(&"Transfer failed. Error: Deposit exceeds maximum per account".to_string()));
+    let res = deposit(&mut contract, &user, 2 * ONE_USDN);
+    assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+    assert_eq!(
+        contract.get_account_balance(user, usdn),
+        (12 * ONE_USDN).into()
+    );
+}
+
+#[test]
+fn test_withdraw_all_internal() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.accounts_deposit = (10 * ONE_USDN).into();
+    token2_wallet.accounts_deposit = (20 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+
+    register_account(&mut contract, &user);
+    assert_eq!(
+        contract.withdraw_all_internal().err(),
+        Some("No balances to withdraw")
+    );
+
+    contract
+        .internal_deposit(&user, &usdn, 10 * ONE_USDN)
+        .unwrap();
+    contract
+        .internal_deposit(&user, &usdt, 20 * ONE_USDT)
+        .unwrap();
+
+    // Both balances are transferred out in one call
+    let res = contract.withdraw_all_internal();
+    assert!(res.is_ok());
+    drop(res);
+    let transfers: Vec<_> = get_created_receipts()
+        .into_iter()
+        .filter_map(|receipt| match &receipt.actions[..] {
+            [VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            }] if function_name == "ft_transfer" => Some((
+                receipt.receiver_id.to_string(),
+                serde_json::from_slice::<serde_json::Value>(args).unwrap()["amount"].clone(),
+            )),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        transfers,
+        vec![
+            (usdn.to_string(), json!((10 * ONE_USDN).to_string())),
+            (usdt.to_string(), json!((20 * ONE_USDT).to_string())),
+        ]
+    );
+    for token_id in [&usdn, &usdt] {
+        assert_eq!(
+            contract.get_account_balance(user.clone(), token_id.clone()),
+            0.into()
+        );
+    }
+    assert_eq!(
+        contract.token1_wallet.as_ref().unwrap().accounts_deposit,
+        0.into()
+    );
+    assert_eq!(
+        contract.token2_wallet.as_ref().unwrap().accounts_deposit,
+        0.into()
+    );
+
+    // Failed transfer of one token restores only its balance
+    assert!(contract.on_account_deposit_withdrawn(
+        user.clone(),
+        usdn.clone(),
+        (10 * ONE_USDN).into(),
+        Ok(())
+    ));
+    assert!(!contract.on_account_deposit_withdrawn(
+        user.clone(),
+        usdt.clone(),
+        (20 * ONE_USDT).into(),
+        Err(near_sdk::PromiseError::Failed)
+    ));
+    assert_eq!(contract.get_account_balance(user.clone(), usdn), 0.into());
+    assert_eq!(
+        contract.get_account_balance(user, usdt),
+        (20 * ONE_USDT).into()
+    );
+}
+
+#[test]
+fn test_account_deposits() {
+    let alice: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let carol: near_sdk::AccountId = "carol.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract.token1_wallet = Some(test_token_wallet(usdn.as_str(), 6));
+    contract.token2_wallet = Some(test_token_wallet(usdt.as_str(), 6));
+    register_account(&mut contract, &alice);
+    register_account(&mut contract, &carol);
+
+    let mut deposit = |sender_id: &near_sdk::AccountId, token_id: &near_sdk::AccountId, amount| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id.clone())
+            .build());
+        let res = contract.ft_on_transfer(sender_id.clone(), amount, String::new());
+        assert!(matches!(res, PromiseOrValue::Value(amount) if amount == 0.into()));
+
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str::<Event<DepositEvent>>(event).unwrap())
+            .next_back()
+            .unwrap()
+            .data
+            .remove(0)
+    };
+
+    assert_eq!(
+        deposit(&alice, &usdn, (10 * ONE_USDN).into()),
+        DepositEvent {
+            account_id: alice.clone(),
+            token_id: usdn.clone(),
+            amount: (10 * ONE_USDN).into(),
+            balances: [(10 * ONE_USDN).into(), 0.into()],
+        }
+    );
+    assert_eq!(
+        deposit(&carol, &usdt, (5 * ONE_USDT).into()),
+        DepositEvent {
+            account_id: carol.clone(),
+            token_id: usdt.clone(),
+            amount: (5 * ONE_USDT).into(),
+            balances: [0.into(), (5 * ONE_USDT).into()],
+        }
+    );
+    assert_eq!(
+        deposit(&alice, &usdt, (7 * ONE_USDT).into()),
+        DepositEvent {
+            account_id: alice.clone(),
+            token_id: usdt,
+            amount: (7 * ONE_USDT).into(),
+            balances: [(10 * ONE_USDN).into(), (7 * ONE_USDT).into()],
+        }
+    );
+
+    // Each account reads only its own deposits
+    assert_eq!(
+        contract.get_account_deposits(alice),
+        [(10 * ONE_USDN).into(), (7 * ONE_USDT).into()]
+    );
+    assert_eq!(
+        contract.get_account_deposits(carol),
+        [0.into(), (5 * ONE_USDT).into()]
+    );
+    assert_eq!(
+        contract.get_account_deposits("dave.near".parse().unwrap()),
+        [0.into(), 0.into()]
+    );
+}
+
+#[test]
+fn test_lp_state() {
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    assert_eq!(contract.get_lp_state(), Err("Token1 wallet is not created"));
+
+    let mut token1_wallet = test_token_wallet("usdn.testnet", 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (3_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.total_lp_shares = 1_500 * ONE_USDN;
+
+    let lp_state = contract.get_lp_state().unwrap();
+    assert_eq!(lp_state.reserves, contract.get_pool().unwrap().amounts);
+    assert_eq!(lp_state.total_shares, contract.get_lp_total_supply());
+    assert_eq!(
+        lp_state,
+        LpState {
+            reserves: [(1_000 * ONE_USDN).into(), (3_000 * ONE_USDT).into()],
+            total_shares: (1_500 * ONE_USDN).into(),
+        }
+    );
+}
+
+#[test]
+fn test_dashboard() {
+    let alice: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let carol: near_sdk::AccountId = "carol.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet("usdt.fakes.testnet", 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.total_lp_shares = 1_000;
+    register_account(&mut contract, &alice);
+    contract
+        .update_account(&alice, |account| {
+            account.lp_shares = 250;
+            account.deposit(&usdn, 10 * ONE_USDN)
+        })
+        .unwrap();
+
+    let dashboard = contract.get_dashboard(alice.clone()).unwrap();
+    assert_eq!(
+        serde_json::to_value(&dashboard.pool).unwrap(),
+        serde_json::to_value(contract.get_pool().unwrap()).unwrap()
+    );
+    let account = dashboard.account.unwrap();
+    assert_eq!(
+        serde_json::to_value(Some(account.storage_balance)).unwrap(),
+        serde_json::to_value(contract.storage_balance_of(alice.clone())).unwrap()
+    );
+    assert_eq!(account.last_swap, contract.get_last_swap(alice.clone()));
+    assert_eq!(account.swap_count, contract.get_swap_count(alice.clone()));
+    assert_eq!(
+        dashboard.user_shares,
+        contract.get_lp_balance(alice.clone())
+    );
+    assert_eq!(dashboard.user_shares, 250.into());
+    assert_eq!(
+        dashboard.user_deposits,
+        contract.get_account_deposits(alice)
+    );
+    assert_eq!(dashboard.user_deposits, [(10 * ONE_USDN).into(), 0.into()]);
+
+    // Unregistered account has no account data, shares or deposits
+    let dashboard = contract.get_dashboard(carol).unwrap();
+    assert!(dashboard.account.is_none());
+    assert_eq!(dashboard.user_shares, 0.into());
+    assert_eq!(dashboard.user_deposits, [0.into(), 0.into()]);
+}
+
+#[test]
+fn test_account_deposit_during_pending_swap() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new().build());
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let mut token1_wallet = test_token_wallet(usdn.as_str(), 6);
+    let mut token2_wallet = test_token_wallet(usdt.as_str(), 6);
+    token1_wallet.liquidity = (1_000 * ONE_USDN).into();
+    token2_wallet.liquidity = (1_000 * ONE_USDT).into();
+    contract.token1_wallet = Some(token1_wallet);
+    contract.token2_wallet = Some(token2_wallet);
+    contract.backing_verified = true;
+    register_account(&mut contract, &user);
+
+    let transfer = |contract: &mut crate::Contract,
+                    token_id: &near_sdk::AccountId,
+                    amount: u128,
+                    msg: &str| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(token_id.clone())
+            .build());
+        contract.ft_on_transfer(user.clone(), amount.into(), msg.to_string())
+    };
+
+    // Swap is started, so its callback is pending
+    let res = transfer(&mut contract, &usdn, 10 * ONE_USDN, r#"{"type":"swap"}"#);
+    drop(res);
+    let args = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| receipt.actions)
+        .find_map(|action| match action {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } if function_name == "on_swap_complete" => {
+                serde_json::from_slice::<serde_json::Value>(&args).ok()
+            }
+            _ => None,
+        })
+        .unwrap();
+
+    // Deposit & withdrawal of the output token happen meanwhile
+    transfer(&mut contract, &usdt, 5 * ONE_USDT, "");
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    drop(
+        contract
+            .withdraw_deposit(usdt.clone(), (2 * ONE_USDT).into())
+            .unwrap(),
+    );
+
+    // Completed swap keeps both, so the wallet total still matches the account balance
+    let arg = |name: &str| args[name].clone();
+    contract.on_swap_complete(
+        serde_json::from_value(arg("sender_id")).unwrap(),
+        serde_json::from_value(arg("token_id_in")).unwrap(),
+        serde_json::from_value(arg("token_id_out")).unwrap(),
+        serde_json::from_value(arg("amounts")).unwrap(),
+        Ok(()),
+    );
+    assert_eq!(
+        contract.get_account_balance(user, usdt),
+        (3 * ONE_USDT).into()
+    );
+    assert_eq!(
+        contract.token2_wallet.as_ref().unwrap().accounts_deposit,
+        (3 * ONE_USDT).into()
+    );
+}
+
+#[test]
+fn test_allowed_tokens() {
+    let usdn: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    let usdt: near_sdk::AccountId = "usdt.fakes.testnet".parse().unwrap();
+    let eth: near_sdk::AccountId = "eth.fakes.testnet".parse().unwrap();
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let init = |tokens: (&near_sdk::AccountId, &near_sdk::AccountId), allowed_tokens| {
+        std::panic::catch_unwind(|| {
+            crate::Contract::init(
+                Some(owner.clone()),
+                Some((tokens.0.clone(), tokens.1.clone())),
+                None,
+                None,
+                allowed_tokens,
+                None,
+            )
+        })
+    };
+
+    // Any token is allowed without the whitelist
+    assert!(init((&usdn, &eth), None).is_ok());
+
+    let allowed_tokens = Some(vec![usdn.clone(), usdt.clone()]);
+    assert!(init((&usdn, &usdt), allowed_tokens.clone()).is_ok());
+    assert!(init((&usdn, &eth), allowed_tokens.clone()).is_err());
+    assert!(init((&eth, &usdt), allowed_tokens.clone()).is_err());
+
+    // Owner can't create wallets against a disallowed token either
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(2 * ONE_NEAR)
+        .build());
+    let mut contract =
+        crate::Contract::init(Some(owner.clone()), None, None, None, allowed_tokens, None);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.owner_create_wallets(usdn.clone(), eth.clone())
+    }))
+    .is_err());
+    contract.owner_create_wallets(usdn, usdt);
+}
+
+#[test]
+fn test_update_config_fees() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let mut contract = crate::Contract::init(Some(owner), None, None, None, None, None);
+    let patch = |patch: serde_json::Value| -> ContractConfigPatch {
+        serde_json::from_value(patch).unwrap()
+    };
+
+    assert_eq!(
+        contract.update_config(patch(json!({ "fee_bps": MAX_BPS + 1 }))),
+        Err("Fee exceeds 100%")
+    );
+    assert_eq!(
+        contract.update_config(patch(json!({ "protocol_fee_bps": MAX_BPS + 1 }))),
+        Err("Protocol fee exceeds 100%")
+    );
+    assert_eq!(
+        contract.update_config(patch(json!({ "max_swap_bps": MAX_BPS + 1 }))),
+        Err("Max swap exceeds 100%")
+    );
+
+    contract
+        .update_config(patch(
+            json!({ "fee_bps": 50, "protocol_fee_bps": 1_000, "max_swap_bps": 500 }),
+        ))
+        .unwrap();
+    let config = contract.get_config();
+    assert_eq!(config.fee_bps, 50);
+    assert_eq!(config.protocol_fee_bps, 1_000);
+    assert_eq!(config.max_swap_bps, Some(500));
+
+    // Missing field keeps the swap cap, `null` removes it
+    contract.update_config(patch(json!({}))).unwrap();
+    assert_eq!(contract.get_config().max_swap_bps, Some(500));
+    contract
+        .update_config(patch(json!({ "max_swap_bps": null })))
+        .unwrap();
+    assert_eq!(contract.get_config().max_swap_bps, None);
+}
+
+#[test]
+fn test_get_config() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("deployer.near".parse().unwrap())
+        .build());
+    let contract = crate::Contract::init(
+        Some(owner.clone()),
+        None,
+        Some([Some(U128(ONE_USDN)), None]),
+        Some(5),
+        None,
+        None,
+    );
+
+    let config = contract.get_config();
+    assert_matches!(
+        &config,
+        ContractConfig {
+            owner_id,
+            running_state: RunningState::Running,
+            token1_id: None,
+            token2_id: None,
+            fee_bps: 5,
+            tokens: None,
+            ..
+        } if owner_id == &owner
+    );
+    assert_eq!(config.min_liquidity_for_swaps, [Some(U128(ONE_USDN)), None]);
+
+    assert_eq!(
+        serde_json::to_value(&config).unwrap()["running_state"],
+        json!("Running")
+    );
+}
+
+#[test]
+fn test_config_hash() {
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("owner.near".parse().unwrap())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+
+    let initial_hash = contract.get_config_hash();
+    assert_eq!(initial_hash.len(), 64);
+    assert_eq!(contract.get_config_hash(), initial_hash);
+
+    contract.set_fee_bps(10).unwrap();
+    let fee_hash = contract.get_config_hash();
+    assert_ne!(fee_hash, initial_hash);
+
+    contract
+        .update_config(ContractConfigPatch {
+            max_add_deviation_bps: Some(MAX_BPS),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_ne!(contract.get_config_hash(), fee_hash);
+
+    // Restoring the settings restores the hash
+    contract.set_fee_bps(crate::misc::DEFAULT_FEE_BPS).unwrap();
+    contract
+        .update_config(ContractConfigPatch {
+            max_add_deviation_bps: Some(crate::tokens::DEFAULT_MAX_ADD_DEVIATION_BPS),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(contract.get_config_hash(), initial_hash);
+}
+
+#[test]
+fn test_ownership_transfer() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let alice: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let bob: near_sdk::AccountId = "bob.near".parse().unwrap();
+    let call_as = |account_id: &near_sdk::AccountId| {
+        near_sdk::testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+    };
+
+    call_as(&owner);
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+    assert_eq!(contract.get_pending_owner(), None);
+
+    // Proposal can be made by the owner only
+    call_as(&alice);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.propose_new_owner(alice.clone())
+    }))
+    .is_err());
+
+    // New proposal replaces the pending one
+    call_as(&owner);
+    contract.propose_new_owner(alice.clone());
+    contract.propose_new_owner(bob.clone());
+    assert_eq!(contract.get_pending_owner(), Some(bob.clone()));
+
+    // Replaced or never proposed accounts can't accept
+    for account_id in [&alice, &owner] {
+        call_as(account_id);
+        assert_eq!(
+            contract.accept_ownership(),
+            Err("Caller is not the pending owner")
+        );
+    }
+    assert_eq!(contract.get_owner(), owner);
+
+    call_as(&bob);
+    assert_eq!(contract.accept_ownership(), Ok(()));
+    assert_eq!(contract.get_owner(), bob);
+    assert_eq!(contract.get_pending_owner(), None);
+
+    // Previous owner lost admin rights
+    call_as(&owner);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.pause())).is_err());
+    call_as(&bob);
+    contract.pause();
+}
+
+#[test]
+fn test_pause_reason() {
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id("owner.near".parse().unwrap())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    assert_eq!(
+        contract.get_status(),
+        ContractStatus {
+            running_state: RunningState::Running,
+            pause_reason: None,
+        }
+    );
+
+    contract.set_running_state(
+        RunningState::Paused,
+        Some("Token contract upgrade".to_string()),
+    );
+    assert_eq!(
+        contract.get_status(),
+        ContractStatus {
+            running_state: RunningState::Paused,
+            pause_reason: Some("Token contract upgrade".to_string()),
+        }
+    );
+    assert_eq!(
+        serde_json::to_value(contract.get_status()).unwrap(),
+        json!({ "running_state": "Paused", "pause_reason": "Token contract upgrade" })
+    );
+    assert!(get_logs().contains(&"Contract paused by owner: Token contract upgrade".to_string()));
+
+    // Too long reason is rejected
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        contract.set_running_state(RunningState::Paused, Some("a".repeat(257)))
+    }))
+    .is_err());
+
+    // Resume clears the reason
+    contract.resume();
+    assert_eq!(
+        contract.get_status(),
+        ContractStatus {
+            running_state: RunningState::Running,
+            pause_reason: None,
+        }
+    );
+}
+
+#[test]
+fn test_deduct_fee() {
+    assert_eq!(deduct_fee(10_000, 30), Ok(9_970));
+    assert_eq!(deduct_fee(10_001, 30), Ok(9_970));
+    assert_eq!(deduct_fee(1, 30), Ok(0));
+    assert_eq!(deduct_fee(1_000, 0), Ok(1_000));
+    assert_eq!(deduct_fee(u128::MAX, 10_000), Ok(0));
+    assert_eq!(deduct_fee(u128::MAX, 0), Ok(u128::MAX));
+
+    // Added fee is the smallest amount covering the fee deduction
+    for (amount, fee_bps) in [(9_970, 30), (1, 30), (123_456_789, 1), (1_000, 0)] {
+        let amount_with_fee = add_fee(amount, fee_bps).unwrap();
+        assert!(deduct_fee(amount_with_fee, fee_bps).unwrap() >= amount);
+        assert!(deduct_fee(amount_with_fee - 1, fee_bps).unwrap() < amount);
+    }
+    assert!(add_fee(1, 10_000).is_err());
+}
+
+#[test]
+fn test_swap_ratio_non_decreasing() {
+    let mut reserves = [50_000 * ONE_USDN, 50_000 * ONE_USDT];
+
+    // Small odd-sized swaps in alternating directions maximize rounding
+    for i in 0..1_000u128 {
+        let amount_in = 1 + i * 7_919 % 1_000_003;
+        let reserves_before = reserves;
+
+        if i % 2 == 0 {
+            let amount_out = compute_amount_out(reserves[0], 6, reserves[1], 6, amount_in).unwrap();
+            reserves = [reserves[0] + amount_in, reserves[1] - amount_out];
+        } else {
+            let amount_out = compute_amount_out(reserves[1], 6, reserves[0], 6, amount_in).unwrap();
+            reserves = [reserves[0] - amount_out, reserves[1] + amount_in];
+        }
+
+        assert!(is_ratio_preserved(reserves_before, reserves));
+    }
+
+    // Ratio of the pool drained by a single unit is not preserved
+    assert!(!is_ratio_preserved([10, 10], [11, 9]));
+}
+
+#[tokio::test]
+async fn test_swap_reserve_buffer() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    // Seed a tiny pool, where a large swap would otherwise drain the output reserve
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        10.into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        10.into(),
+    )
+    .await?;
+    add_liquidity(&pool.contract, &pool.owner, [10.into(), 10.into()]).await?;
+
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        1_000.into(),
+    )
+    .await?;
+
+    // Output is capped at reserve minus default buffer of 1, only 90 of 1000 USDN are used
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN - 90).into()
+    );
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (200_000 * ONE_USDT + 9).into()
+    );
+    assert_eq!(
+        get_pool_view(&pool.contract).await?.amounts,
+        [100.into(), 1.into()]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_output_registration() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    // User is registered at both token contracts
+    assert!(check_output_registration(&pool.contract, &pool.user, pool.usdn_contract.id()).await?);
+    assert!(check_output_registration(&pool.contract, &pool.user, pool.usdt_contract.id()).await?);
+
+    // Fresh account is registered at neither of token contracts
+    let unregistered = pool
+        .user
+        .create_subaccount("unregistered")
+        .initial_balance(ONE_NEAR)
+        .transact()
+        .await?
+        .into_result()?;
+    register_user(&pool.usdt_contract, &unregistered).await?;
+
+    // Swapping USDN requires registration at USDT, but not the other way around
+    assert!(
+        check_output_registration(&pool.contract, &unregistered, pool.usdn_contract.id()).await?
+    );
+    assert!(
+        !check_output_registration(&pool.contract, &unregistered, pool.usdt_contract.id()).await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_marginal_price() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (40_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (40_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    assert_eq!(
+        get_marginal_price(&pool.contract, pool.usdn_contract.id()).await?,
+        "0.800000000000"
+    );
+    assert_eq!(
+        get_marginal_price(&pool.contract, pool.usdt_contract.id()).await?,
+        "1.250000000000"
+    );
+
+    // Sizeable trade executes at a worse average price than the marginal one
+    let amount_in = 10_000 * ONE_USDN;
+    let amount_out = get_amount_out(
+        &pool.contract,
+        pool.usdn_contract.id(),
+        amount_in.into(),
+        None,
+    )
+    .await?;
+    assert_eq!(amount_out, U128(6_666_666_666));
+    assert!(amount_out.0 < amount_in * 8 / 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_compute_price() {
+    assert_eq!(
+        compute_price(50 * ONE_ETH, 18, 100_000 * ONE_USDT, 6).map(format_price),
+        Ok("2000.000000000000".to_string())
+    );
+    assert_eq!(
+        compute_price(100_000 * ONE_USDT, 6, 50 * ONE_ETH, 18).map(format_price),
+        Ok("0.000500000000".to_string())
+    );
+    assert_eq!(
+        compute_price(3 * ONE_USDN, 6, ONE_USDT, 6).map(format_price),
+        Ok("0.333333333333".to_string())
+    );
+    assert!(compute_price(0, 6, ONE_USDT, 6).is_err());
+}
+
+#[test]
+fn test_compute_tokens_ratio_bounds() {
+    // Largest possible reserves still produce a ratio
+    assert_eq!(
+        compute_tokens_ratio(u128::MAX, 6, u128::MAX, 6),
+        Ok(U256::from(u128::MAX) * U256::from(u128::MAX))
+    );
+
+    // Swap against largest possible reserves is still computed
+    assert_eq!(compute_amount_out(u128::MAX - 1, 6, u128::MAX, 6, 1), Ok(1));
+
+    // Normalization of largest possible reserves with mismatched decimals overflows
+    assert_eq!(
+        compute_tokens_ratio(u128::MAX, 6, u128::MAX, 18),
+        Err("Computation overflow")
+    );
+}
+
+#[tokio::test]
+async fn test_owner_capabilities() -> anyhow::Result<()> {
+    // Pool without wallets can only create them
+    let pool = setup_usdn_usdt_pool_with(json!({ "tokens": null })).await?;
+    assert_eq!(
+        get_owner_capabilities(&pool.contract).await?,
+        OwnerCapabilities {
+            create_wallets: true,
+            configure_tokens: false,
+            add_liquidity: false,
+            remove_liquidity: false,
+            pause: true,
+            resume: false,
+        }
+    );
+
+    // Fully set up pool with liquidity & idle deposit
+    let pool = setup_usdn_usdt_pool().await?;
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(40_000 * ONE_USDN).into(), (40_000 * ONE_USDT).into()],
+    )
+    .await?;
+    assert_eq!(
+        get_owner_capabilities(&pool.contract).await?,
+        OwnerCapabilities {
+            create_wallets: false,
+            configure_tokens: true,
+            add_liquidity: true,
+            remove_liquidity: true,
+            pause: true,
+            resume: false,
+        }
+    );
+
+    // Paused pool can only be resumed
+    call_owner_action(&pool.contract, &pool.owner, "pause").await?;
+    assert_eq!(
+        get_owner_capabilities(&pool.contract).await?,
+        OwnerCapabilities {
+            create_wallets: false,
+            configure_tokens: true,
+            add_liquidity: false,
+            remove_liquidity: false,
+            pause: false,
+            resume: true,
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auto_pause_on_transfer_failure() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({ "auto_pause_on_transfer_failure": true }),
+    )
+    .await?;
+
+    // Account not registered at USDT contract can't receive swap output
+    let unregistered = pool
+        .user
+        .create_subaccount("unregistered")
+        .initial_balance(ONE_NEAR)
+        .transact()
+        .await?
+        .into_result()?;
+    register_user(&pool.usdn_contract, &unregistered).await?;
+    transfer_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        &unregistered,
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &unregistered,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Contract paused due to output transfer failure"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &unregistered).await?,
+        (1_000 * ONE_USDN).into()
+    );
+
+    // Further swaps are refunded
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Contract paused"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_price() {
+    assert_eq!(parse_price("1"), Ok(U256::from(1_000_000_000_000u128)));
+    assert_eq!(parse_price("0.8"), Ok(U256::from(800_000_000_000u128)));
+    assert_eq!(
+        parse_price("2000.000000000001"),
+        Ok(U256::from(2_000_000_000_000_001u128))
+    );
+    assert_eq!(
+        parse_price("0.333333333333").map(format_price),
+        Ok("0.333333333333".to_string())
+    );
+
+    for price in ["", ".5", "1.0000000000001", "-1", "1e3", "1.2.3"] {
+        assert!(parse_price(price).is_err(), "{}", price);
+    }
+}
+
+#[tokio::test]
+async fn test_add_liquidity_expected_price() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+
+    // USDT amount with mistaken decimals implies a wrong initial price
+    let res = add_liquidity_at_price(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), 50_000.into()],
+        "1",
+    )
+    .await;
+    assert!(res.is_err());
+    assert_eq!(
+        get_pool_view(&pool.contract).await?.amounts,
+        [0.into(), 0.into()]
+    );
+
+    // Price within tolerance is accepted
+    add_liquidity_at_price(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (49_800 * ONE_USDT).into()],
+        "1",
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_meaningful_output() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({ "min_meaningful_output": [ONE_USDN.to_string(), ONE_USDT.to_string()] }),
+    )
+    .await?;
+
+    // Non-zero output of 99 USDT units is below threshold, so the swap is refunded
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        100.into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Swap output is too small, try a larger trade"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // Output above threshold is swapped
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (2 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_998 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_compute_amount_to_price() {
+    for (reserves, decimals, target_price) in [
+        ([50_000 * ONE_USDN, 50_000 * ONE_USDT], [6, 6], "0.81"),
+        ([50 * ONE_ETH, 100_000 * ONE_USDT], [18, 6], "1500"),
+        ([100_000 * ONE_USDT, 50 * ONE_ETH], [6, 18], "0.0004"),
+    ] {
+        let target_price = parse_price(target_price).unwrap();
+        let amount_in = compute_amount_to_price(
+            reserves[0],
+            decimals[0],
+            reserves[1],
+            decimals[1],
+            target_price,
+        )
+        .unwrap();
+
+        // Executing the amount moves the marginal price to the target within 0.01%
+        let amount_out = compute_amount_out(
+            reserves[0],
+            decimals[0],
+            reserves[1],
+            decimals[1],
+            amount_in,
+        )
+        .unwrap();
+        let price = compute_price(
+            reserves[0] + amount_in,
+            decimals[0],
+            reserves[1] - amount_out,
+            decimals[1],
+        )
+        .unwrap();
+        let deviation = if price > target_price {
+            price - target_price
+        } else {
+            target_price - price
+        };
+        assert!(deviation * U256::from(10_000) <= target_price);
+    }
+
+    // Selling can't raise the price
+    assert!(compute_amount_to_price(
+        50_000 * ONE_USDN,
+        6,
+        50_000 * ONE_USDT,
+        6,
+        parse_price("1.2").unwrap()
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_amount_to_price() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let res = pool
+        .contract
+        .view("amount_to_price")
+        .args_json(json!({
+            "token_in": pool.usdn_contract.id(),
+            "target_price": "0.81",
+        }))
+        .await?;
+    let amount_in = res.json::<U128>()?;
+
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        amount_in,
+    )
+    .await?;
+
+    let price = get_marginal_price(&pool.contract, pool.usdn_contract.id()).await?;
+    assert!(price.starts_with("0.8099") || price.starts_with("0.8100"));
+
+    Ok(())
+}
+
+#[test]
+fn test_required_deposit() {
+    let short_id: near_sdk::AccountId = "alice.near".parse().unwrap();
+    let long_id: near_sdk::AccountId = "a".repeat(64).parse().unwrap();
+
+    for account_id in [&short_id, &long_id] {
+        let mut contract = crate::Contract::init(None, None, None, None, None, None);
+
+        // Initial storage usage matches the actual storage used by registration
+        let storage_usage_before = near_sdk::env::storage_usage();
+        contract
+            .accounts
+            .insert(account_id, &ContractAccount::new(account_id, None).into());
+        let storage_usage = near_sdk::env::storage_usage() - storage_usage_before;
+
+        assert_eq!(
+            ContractAccount::initial_storage_usage(Some(account_id)),
+            storage_usage
+        );
+        assert_eq!(
+            ContractAccount::required_deposit(Some(account_id)),
+            (storage_usage as u128 * near_sdk::env::storage_byte_cost()).into()
+        );
+    }
+
+    // Shorter id is cheaper, maximum length id is the worst case
+    assert!(
+        ContractAccount::required_deposit(Some(&short_id)).0
+            < ContractAccount::required_deposit(None).0
+    );
+    assert_eq!(
+        ContractAccount::required_deposit(Some(&long_id)),
+        ContractAccount::required_deposit(None)
+    );
+
+    // Maximum deposit covers the measured storage of balances of both pool tokens with maximum id length
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    contract
+        .store_account(
+            &long_id,
+            ContractAccount::new(&long_id, Some(ContractAccount::max_deposit().0)),
+        )
+        .unwrap();
+    for token_id in ["b".repeat(64), "c".repeat(64)] {
+        contract
+            .internal_deposit(&long_id, &token_id.parse().unwrap(), u128::MAX)
+            .unwrap();
+    }
+    assert_eq!(
+        contract.get_account(&long_id).unwrap().storage_usage as u128
+            * near_sdk::env::storage_byte_cost(),
+        ContractAccount::max_deposit().0
+    );
+}
+
+#[test]
+fn test_storage_deposit_refund_excess() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_NEAR)
+        .build());
+
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let max_balance = contract.storage_balance_bounds().max.unwrap();
+
+    // New account is credited up to maximum balance
+    assert_eq!(contract.storage_deposit(None, None).total, max_balance);
+    assert_eq!(
+        get_created_receipts().last().unwrap().actions,
+        vec![VmAction::Transfer {
+            deposit: ONE_NEAR - max_balance.0
+        }]
+    );
+
+    // Existing account at maximum balance is refunded the whole deposit
+    assert_eq!(contract.storage_deposit(None, None).total, max_balance);
+    assert_eq!(
+        get_created_receipts().last().unwrap().actions,
+        vec![VmAction::Transfer { deposit: ONE_NEAR }]
+    );
+    assert_eq!(
+        contract
+            .storage_balance_of(user)
+            .map(|balance| balance.total),
+        Some(max_balance)
+    );
+}
+
+#[test]
+fn test_storage_deposit_batch() {
+    let integrator: near_sdk::AccountId = "integrator.near".parse().unwrap();
+    let users: Vec<near_sdk::AccountId> = ["alice.near", "bob.near", "carol.near"]
+        .iter()
+        .map(|account_id| account_id.parse().unwrap())
+        .collect();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    // Each account is charged by its own id length, which is below the maximum length bound
+    let bounds_min_balance = contract.storage_balance_bounds().min;
+    let min_balance = |account_id: &near_sdk::AccountId| {
+        let min_balance = ContractAccount::required_deposit(Some(account_id));
+        assert!(min_balance.0 < bounds_min_balance.0);
+        min_balance
+    };
+    let total_min_balance: u128 = users.iter().map(|user| min_balance(user).0).sum();
+
+    // Deposit covers all minimums, the remainder is refunded to the caller
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(integrator.clone())
+        .attached_deposit(total_min_balance + 5)
+        .build());
+    let storage_balances = contract.storage_deposit_batch(users.clone());
+    assert_eq!(storage_balances.len(), 3);
+    assert_eq!(
+        get_created_receipts().last().unwrap().actions,
+        vec![VmAction::Transfer { deposit: 5 }]
+    );
+
+    for user in &users {
+        let storage_balance = contract.storage_balance_of(user.clone()).unwrap();
+        assert_eq!(storage_balance.total, min_balance(user));
+        assert_eq!(storage_balance.available, 0.into());
+    }
+    assert!(contract.storage_balance_of(integrator.clone()).is_none());
+
+    // Already registered accounts are skipped & refunded
+    let dave: near_sdk::AccountId = "dave.near".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(integrator)
+        .attached_deposit(min_balance(&dave).0 + 5)
+        .build());
+    contract.storage_deposit_batch(vec![users[0].clone(), dave.clone()]);
+    assert_eq!(
+        get_created_receipts().last().unwrap().actions,
+        vec![VmAction::Transfer { deposit: 5 }]
+    );
+    assert_eq!(
+        contract
+            .storage_balance_of(dave.clone())
+            .map(|balance| balance.total),
+        Some(min_balance(&dave))
+    );
+    assert_eq!(
+        contract
+            .storage_balance_of(users[0].clone())
+            .map(|balance| balance.total),
+        Some(min_balance(&users[0]))
+    );
+}
+
+#[test]
+fn test_storage_withdraw_covers_used_storage() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let min_balance = ContractAccount::required_deposit(None).0;
+    let max_balance = ContractAccount::max_deposit().0;
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(max_balance)
+        .build());
+    contract.storage_deposit(None, None);
+
+    // Grow account state by token balances, bytes used are charged from the storage balance
+    let storage_usage_before = near_sdk::env::storage_usage();
+    for token_id in ["usdn.testnet", "usdt.fakes.testnet"] {
+        contract
+            .internal_deposit(&user, &token_id.parse().unwrap(), 1)
+            .unwrap();
+    }
+    let storage_usage = contract.get_account(&user).unwrap().storage_usage;
+    assert_eq!(
+        storage_usage,
+        ContractAccount::initial_storage_usage(Some(&user)) + near_sdk::env::storage_usage()
+            - storage_usage_before
+    );
+    let used_balance = storage_usage as u128 * near_sdk::env::storage_byte_cost();
+    assert!(used_balance > min_balance);
+
+    let storage_balance = contract.storage_balance_of(user.clone()).unwrap();
+    assert_eq!(
+        storage_balance.available,
+        (max_balance - used_balance).into()
+    );
+
+    // Withdrawal is capped at the balance not used by storage
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let storage_balance = contract.storage_withdraw(None);
+    assert_eq!(storage_balance.total, used_balance.into());
+    assert_eq!(storage_balance.available, 0.into());
+
+    // State can't grow past the storage balance
+    assert_eq!(
+        contract.internal_deposit(&user, &"eth.fakes.testnet".parse().unwrap(), 1),
+        Err("Insufficient storage balance")
+    );
+    assert_eq!(
+        contract.get_account_balance(user.clone(), "eth.fakes.testnet".parse().unwrap()),
+        0.into()
+    );
+    assert_eq!(
+        contract.get_account(&user).unwrap().storage_usage,
+        storage_usage
+    );
+}
+
+#[test]
+fn test_storage_withdraw_keeps_minimum() {
+    let user: near_sdk::AccountId = "user.near".parse().unwrap();
+    let mut contract = crate::Contract::init(None, None, None, None, None, None);
+    let min_balance = ContractAccount::required_deposit(Some(&user));
+
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(2 * min_balance.0)
+        .build());
+    contract.storage_deposit(None, None);
+
+    // Full withdraw leaves exactly the minimum of the account's own id length
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(user.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    let storage_balance = contract.storage_withdraw(None);
+    assert_eq!(storage_balance.total, min_balance);
+    assert_eq!(storage_balance.available, 0.into());
+
+    let storage_balance = contract.storage_balance_of(user).unwrap();
+    assert_eq!(storage_balance.total, min_balance);
+    assert_eq!(storage_balance.available, 0.into());
+}
+
+#[tokio::test]
+async fn test_swap_marginal_price_event() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let logs = swap_tokens(
+        &pool.usdt_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDT).into(),
+    )
+    .await?;
+
+    let event = logs
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(serde_json::from_str::<Event<SwapEvent>>)
+        .next()
+        .expect("Swap event")?;
+
+    assert_eq!(
+        event.data[0].marginal_price,
+        Some(get_marginal_price(&pool.contract, pool.usdt_contract.id()).await?)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_max_slippage() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Slippage above 100% is rejected
+    assert!(update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({ "default_max_slippage_bps": 10_001 }),
+    )
+    .await
+    .is_err());
+
+    update_config(
+        &pool.contract,
+        &pool.owner,
+        json!({ "default_max_slippage_bps": 100 }),
+    )
+    .await?;
+
+    // 1000 USDN move the price by ~2%, so the swap is refunded
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Swap slippage exceeds default maximum"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // 100 USDN move the price by ~0.2%
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (100 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_900 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blacklist() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+    for account in [&pool.user, &pool.owner] {
+        register_pool_account(&pool.contract, account).await?;
+    }
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Only owner manages the blacklist
+    assert!(
+        set_blacklisted(&pool.contract, &pool.user, &pool.user, true)
+            .await
+            .is_err()
+    );
+    set_blacklisted(&pool.contract, &pool.owner, &pool.user, true).await?;
+
+    // Blacklisted account's swap is refunded
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Account is blacklisted"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // Non-blacklisted account swaps
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(get_swap_count(&pool.contract, &pool.owner).await?, 1);
+
+    // Removed from blacklist account swaps again
+    set_blacklisted(&pool.contract, &pool.owner, &pool.user, false).await?;
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(get_swap_count(&pool.contract, &pool.user).await?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_pool_view_serialization() {
+    let pool_view = PoolView {
+        token_ids: [
+            "usdn.testnet".parse().unwrap(),
+            "usdt.fakes.testnet".parse().unwrap(),
+        ],
+        decimals: [6, 6],
+        amounts: [u128::MAX.into(), u128::MAX.into()],
+        ratio: U256::MAX,
+        fee_bps: 30,
+    };
+
+    // Large amounts are serialized as decimal strings
+    let json = serde_json::to_value(&pool_view).unwrap();
+    assert_eq!(json["amounts"][0], json!(u128::MAX.to_string()));
+    assert_eq!(json["ratio"], json!(U256::MAX.to_string()));
+
+    let pool_view: PoolView = serde_json::from_value(json).unwrap();
+    assert_eq!(pool_view.amounts, [u128::MAX.into(), u128::MAX.into()]);
+    assert_eq!(pool_view.ratio, U256::MAX);
+
+    // Numbers are not accepted
+    assert!(serde_json::from_value::<PoolView>(json!({
+        "token_ids": ["usdn.testnet", "usdt.fakes.testnet"],
+        "decimals": [6, 6],
+        "amounts": ["1", "1"],
+        "ratio": 1,
+        "fee_bps": 30,
+    }))
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_committed_swap() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+    // Committed swap is stored at the account's expense
+    register_pool_account(&pool.contract, &pool.user).await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Commit escrows input tokens and locks the quote
+    commit_swap(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+
+    let res = pool
+        .contract
+        .view("get_committed_swap")
+        .args_json(json!({ "account_id": pool.user.id() }))
+        .await?;
+    let committed_swap = res.json::<serde_json::Value>()?;
+    assert_eq!(committed_swap["amount_out"], json!("980392156"));
+
+    // Only one swap can be committed at once
+    commit_swap(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+
+    // Execution in a later transaction swaps escrowed tokens
+    call_committed_swap(&pool.contract, &pool.user, "execute_swap").await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (200_000 * ONE_USDT + 980_392_156).into()
+    );
+    assert!(
+        call_committed_swap(&pool.contract, &pool.user, "execute_swap")
+            .await
+            .is_err()
+    );
+
+    // Cancellation refunds escrowed tokens
+    commit_swap(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    call_committed_swap(&pool.contract, &pool.user, "cancel_swap").await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_admin_methods_require_one_yocto() {
+    let owner: near_sdk::AccountId = "owner.near".parse().unwrap();
+    let token_id: near_sdk::AccountId = "usdn.testnet".parse().unwrap();
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .build());
+
+    let mut contract = crate::Contract::init(Some(owner.clone()), None, None, None, None, None);
+
+    type AdminCall<'a> = Box<dyn Fn(&mut crate::Contract) + 'a>;
+    let admin_calls: Vec<(&str, AdminCall)> = vec![
+        (
+            "update_config",
+            Box::new(|contract| {
+                let _ = contract.update_config(ContractConfigPatch::default());
+            }),
+        ),
+        (
+            "add_liquidity",
+            Box::new(|contract| {
+                let _ = contract.add_liquidity([1.into(), 1.into()], None);
+            }),
+        ),
+        (
+            "add_liquidity_balanced",
+            Box::new(|contract| {
+                let _ = contract.add_liquidity_balanced([1.into(), 1.into()]);
+            }),
+        ),
+        (
+            "resolve_stuck_swap",
+            Box::new(|contract| {
+                let _ = contract.resolve_stuck_swap(0);
+            }),
+        ),
+        (
+            "settle_unresolved_swap",
+            Box::new(|contract| {
+                let _ = contract.settle_unresolved_swap(0, 0.into());
+            }),
+        ),
+        (
+            "set_max_swap_bps",
+            Box::new(|contract| {
+                let _ = contract.set_max_swap_bps(None);
+            }),
+        ),
+        (
+            "override_decimals",
+            Box::new(|contract| {
+                let _ = contract.override_decimals("usdn.testnet".parse().unwrap(), 6);
+            }),
+        ),
+        (
+            "remove_liquidity",
+            Box::new(|contract| {
+                let _ = contract.remove_liquidity([1.into(), 1.into()]);
+            }),
+        ),
+        (
+            "owner_withdraw_deposit",
+            Box::new(|contract| {
+                let _ = contract.owner_withdraw_deposit("usdn.testnet".parse().unwrap(), 1.into());
+            }),
+        ),
+        (
+            "withdraw_protocol_fees",
+            Box::new(|contract| {
+                let _ = contract.withdraw_protocol_fees(token_id.clone());
+            }),
+        ),
+        (
+            "set_protocol_fee_bps",
+            Box::new(|contract| {
+                let _ = contract.set_protocol_fee_bps(0);
+            }),
+        ),
+        (
+            "remove_liquidity_by_shares",
+            Box::new(|contract| {
+                let _ = contract.remove_liquidity_by_shares(1.into());
+            }),
+        ),
+        (
+            "set_max_deposit_per_account",
+            Box::new(|contract| {
+                let _ = contract.set_max_deposit_per_account(token_id.clone(), None);
+            }),
+        ),
+        (
+            "set_transfer_memo",
+            Box::new(|contract| {
+                let _ = contract.set_transfer_memo(token_id.clone(), None);
+            }),
+        ),
+        (
+            "rebalance_and_add",
+            Box::new(|contract| {
+                let _ =
+                    contract.rebalance_and_add(token_id.clone(), 1.into(), [0.into(), 0.into()]);
+            }),
+        ),
+        (
+            "propose_new_owner",
+            Box::new(|contract| contract.propose_new_owner(owner.clone())),
+        ),
+        ("pause", Box::new(|contract| contract.pause())),
+        ("resume", Box::new(|contract| contract.resume())),
+        (
+            "set_running_state",
+            Box::new(|contract| contract.set_running_state(RunningState::Paused, None)),
+        ),
+        (
+            "set_fee_bps",
+            Box::new(|contract| {
+                let _ = contract.set_fee_bps(0);
+            }),
+        ),
+        (
+            "add_to_blacklist",
+            Box::new(|contract| contract.add_to_blacklist(owner.clone())),
+        ),
+        (
+            "remove_from_blacklist",
+            Box::new(|contract| contract.remove_from_blacklist(owner.clone())),
+        ),
+    ];
+
+    // Zero deposit call is rejected
+    for (method, admin_call) in &admin_calls {
+        let res =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| admin_call(&mut contract)));
+        assert!(res.is_err(), "{} accepted zero deposit", method);
+    }
+
+    // 1 yoctoNEAR confirms the action
+    near_sdk::testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(owner.clone())
+        .attached_deposit(ONE_YOCTO)
+        .build());
+    assert_eq!(
+        contract.update_config(ContractConfigPatch::default()),
+        Ok(())
+    );
+    contract.add_to_blacklist(owner.clone());
+    assert!(contract.is_blacklisted(owner.clone()));
+}
+
+#[tokio::test]
+async fn test_estimate_registration_cost() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    let res = pool
+        .user
+        .call(pool.contract.id(), "estimate_registration_cost")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    let registration_cost = res.json::<U128>()?;
+
+    let mut expected_cost = 0;
+    for token_contract in [&pool.usdn_contract, &pool.usdt_contract] {
+        let bounds = token_contract
+            .view("storage_balance_bounds")
+            .args_json(json!(()))
+            .await?
+            .json::<serde_json::Value>()?;
+        expected_cost += bounds["min"].as_str().unwrap().parse::<u128>()?;
+    }
+
+    assert!(expected_cost > 0);
+    assert_eq!(registration_cost, U128(expected_cost));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_liquidity_for_swaps() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool_with(json!({
+        "min_liquidity_for_swaps": [(10_000 * ONE_USDN).to_string(), (10_000 * ONE_USDT).to_string()],
+    }))
+    .await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (20_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (20_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(5_000 * ONE_USDN).into(), (5_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Under-seeded pool refunds swaps
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (100 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Pool liquidity is below minimum for swaps"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // Swaps are enabled once both reserves exceed their floors
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(10_000 * ONE_USDN).into(), (10_000 * ONE_USDT).into()],
+    )
+    .await?;
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (100 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_900 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_fee() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool_with(json!({ "fee_bps": 100 })).await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let pool_view_before = get_pool_view(&pool.contract).await?;
+    assert_eq!(pool_view_before.fee_bps, 100);
+
+    let amount_in = 1_000 * ONE_USDN;
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        amount_in.into(),
+    )
+    .await?;
+
+    // Output is priced for the input less 1% fee, while the whole input stays in the pool
+    let amount_out = compute_amount_out(
+        50_000 * ONE_USDN,
+        6,
+        50_000 * ONE_USDT,
+        6,
+        deduct_fee(amount_in, 100).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (100_000 * ONE_USDT + amount_out).into()
+    );
+
+    let pool_view_after = get_pool_view(&pool.contract).await?;
+    assert_eq!(
+        pool_view_after.amounts,
+        [
+            (51_000 * ONE_USDN).into(),
+            (50_000 * ONE_USDT - amount_out).into()
+        ]
+    );
+
+    // Ratio grows at least by the fee retained against the output reserve
+    let fee = amount_in - deduct_fee(amount_in, 100).unwrap();
+    assert!(
+        pool_view_after.ratio - pool_view_before.ratio
+            >= U256::from(fee) * U256::from(50_000 * ONE_USDT - amount_out)
+    );
+
+    // Fee above 100% is rejected, owner can change the fee
+    for (fee_bps, is_success) in [(10_001, false), (0, true)] {
+        let res = pool
+            .owner
+            .call(pool.contract.id(), "set_fee_bps")
+            .args_json(json!({ "fee_bps": fee_bps }))
+            .deposit(ONE_YOCTO)
+            .max_gas()
+            .transact()
+            .await?;
+        assert_eq!(res.is_success(), is_success);
+    }
+    assert_eq!(get_pool_view(&pool.contract).await?.fee_bps, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_min_amount_out() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Unrealistic minimum output refunds the whole input and leaves the pool untouched
+    let logs = swap_tokens_with_min(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+        Some((1_000 * ONE_USDT).into()),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Swap output is below minimum"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (100_000 * ONE_USDT).into()
+    );
+    assert_eq!(
+        get_pool_view(&pool.contract).await?.amounts,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()]
+    );
+
+    // Quoted output is accepted as the minimum
+    let amount_out = get_amount_out(
+        &pool.contract,
+        pool.usdn_contract.id(),
+        (1_000 * ONE_USDN).into(),
+        None,
+    )
+    .await?;
+    swap_tokens_with_min(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+        Some(amount_out),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (100_000 * ONE_USDT + amount_out.0).into()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_estimate() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool_with(json!({ "fee_bps": 30 })).await?;
+
+    // Empty pool can't be quoted
+    let res = pool
+        .contract
+        .view("get_swap_estimate")
+        .args_json(json!({
+            "token_id_in": pool.usdn_contract.id(),
+            "amount_in": U128(1_000 * ONE_USDN),
+        }))
+        .await;
+    assert!(format!("{:?}", res.err()).contains("Pool is empty"));
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let estimate = pool
+        .contract
+        .view("get_swap_estimate")
+        .args_json(json!({
+            "token_id_in": pool.usdn_contract.id(),
+            "amount_in": U128(1_000 * ONE_USDN),
+        }))
+        .await?
+        .json::<U128>()?;
+
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (100_000 * ONE_USDT + estimate.0).into()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_exact_out() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool_with(json!({ "fee_bps": 30 })).await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Required input is rounded up, fee is added on top
+    let amount_in = add_fee(
+        compute_amount_in(50_000 * ONE_USDN, 6, 50_000 * ONE_USDT, 6, 1_000 * ONE_USDT).unwrap(),
+        30,
+    )
+    .unwrap();
+
+    // Not enough input is refunded in full
+    let logs = swap_tokens_exact_out(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (amount_in - 1).into(),
+        (1_000 * ONE_USDT).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Not enough input for the exact output"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // Excess of the transferred tokens is refunded
+    swap_tokens_exact_out(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (2_000 * ONE_USDN).into(),
+        (1_000 * ONE_USDT).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN - amount_in).into()
+    );
+    assert_eq!(
+        ft_balance_of(&pool.usdt_contract, &pool.user).await?,
+        (101_000 * ONE_USDT).into()
+    );
+    assert_eq!(
+        get_pool_view(&pool.contract).await?.amounts,
+        [
+            (50_000 * ONE_USDN + amount_in).into(),
+            (49_000 * ONE_USDT).into()
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_event() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(50_000 * ONE_USDN).into(), (50_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    let amount_out = get_amount_out(
+        &pool.contract,
+        pool.usdn_contract.id(),
+        (1_000 * ONE_USDN).into(),
+        None,
+    )
+    .await?;
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+
+    let events = logs
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(serde_json::from_str::<Event<SwapEvent>>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        events,
+        vec![Event {
+            standard: "swap_pool".to_string(),
+            version: "1.0.0".to_string(),
+            event: "swap".to_string(),
+            data: vec![SwapEvent {
+                sender_id: pool.user.id().parse()?,
+                token_in: pool.usdn_contract.id().parse()?,
+                token_out: pool.usdt_contract.id().parse()?,
+                status: SwapStatus::Filled,
+                amount_in: (1_000 * ONE_USDN).into(),
+                amount_out,
+                amount_refunded: 0.into(),
+                marginal_price: Some(
+                    get_marginal_price(&pool.contract, pool.usdn_contract.id()).await?
+                ),
+            }],
+        }]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pause_resume() -> anyhow::Result<()> {
+    let pool = setup_usdn_usdt_pool().await?;
+
+    deposit_tokens(
+        &pool.usdn_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDN).into(),
+    )
+    .await?;
+    deposit_tokens(
+        &pool.usdt_contract,
+        &pool.owner,
+        pool.contract.as_account(),
+        (50_000 * ONE_USDT).into(),
+    )
+    .await?;
+    add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(40_000 * ONE_USDN).into(), (40_000 * ONE_USDT).into()],
+    )
+    .await?;
+
+    // Only owner can pause
+    assert!(call_owner_action(&pool.contract, &pool.user, "pause")
+        .await
+        .is_err());
+    call_owner_action(&pool.contract, &pool.owner, "pause").await?;
+
+    // Paused swap is refunded in full
+    let logs = swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert!(logs
+        .iter()
+        .any(|log| log == "Transfer failed. Error: Contract paused"));
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (100_000 * ONE_USDN).into()
+    );
+
+    // Liquidity can't be changed while paused
+    assert!(add_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()],
+    )
+    .await
+    .is_err());
+    assert!(remove_liquidity(
+        &pool.contract,
+        &pool.owner,
+        [(1_000 * ONE_USDN).into(), (1_000 * ONE_USDT).into()],
+    )
+    .await
+    .is_err());
+
+    // Resumed pool swaps again
+    call_owner_action(&pool.contract, &pool.owner, "resume").await?;
+    swap_tokens(
+        &pool.usdn_contract,
+        &pool.user,
+        pool.contract.as_account(),
+        (1_000 * ONE_USDN).into(),
+    )
+    .await?;
+    assert_eq!(
+        ft_balance_of(&pool.usdn_contract, &pool.user).await?,
+        (99_000 * ONE_USDN).into()
+    );
+
+    Ok(())
+}
+
+/// Snapshot of the contract state together with the mocked storage of its collections
+pub(crate) struct ContractSnapshot {
+    state: Vec<u8>,
+    storage: std::collections::HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl ContractSnapshot {
+    /// Serializes `contract` & copies the mocked storage, the blockchain is reset with `context`
+    pub(crate) fn take(contract: &crate::Contract, context: near_sdk::VMContext) -> Self {
+        let snapshot = Self {
+            state: contract.try_to_vec().unwrap(),
+            storage: near_sdk::mock::with_mocked_blockchain(|blockchain| blockchain.take_storage()),
+        };
+        snapshot.set_blockchain(context);
+
+        snapshot
+    }
+
+    /// Deserializes the contract & restores the mocked storage, the blockchain is reset with `context`
+    pub(crate) fn restore(&self, context: near_sdk::VMContext) -> crate::Contract {
+        self.set_blockchain(context);
+
+        crate::Contract::try_from_slice(&self.state).unwrap()
+    }
+
+    fn set_blockchain(&self, context: near_sdk::VMContext) {
+        near_sdk::env::set_blockchain_interface(near_sdk::MockedBlockchain::new(
+            context,
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            self.storage.clone(),
+            Default::default(),
+            None,
+        ));
+    }
+}
+
+/// Registers account with maximum storage balance for unit tests
+pub(crate) fn register_account(contract: &mut crate::Contract, account_id: &near_sdk::AccountId) {
+    contract.accounts.insert(
+        account_id,
+        &ContractAccount::new(account_id, Some(ContractAccount::max_deposit().0)).into(),
+    );
+}
+
+/// Token wallet with empty deposit & liquidity for unit tests
+pub(crate) fn test_token_wallet(token_id: &str, decimals: u8) -> TokenWallet {
+    TokenWallet::new(
+        token_id.parse().unwrap(),
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: token_id.to_string(),
+            symbol: token_id.to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        },
+    )
+}
+
+/// Sandboxed USDN/USDT pool with an owner and a user, both funded with 100k USDN & 100k USDT
+pub(crate) struct TestPool {
+    pub(crate) contract: Contract,
+    pub(crate) owner: Account,
+    pub(crate) user: Account,
+    pub(crate) usdn_contract: Contract,
+    pub(crate) usdt_contract: Contract,
+}
+
+pub(crate) async fn setup_usdn_usdt_pool() -> anyhow::Result<TestPool> {
+    setup_usdn_usdt_pool_with(json!({})).await
+}
+
+/// Same as [setup_usdn_usdt_pool], but `init_args` override default contract init arguments
+pub(crate) async fn setup_usdn_usdt_pool_with(
+    init_args: serde_json::Value,
+) -> anyhow::Result<TestPool> {
+    let worker_testnet = workspaces::testnet_archival().await?;
+    let worker = workspaces::sandbox().await?;
+
+    let usdt_token_id = "usdt.fakes.testnet".parse()?;
+    let usdt_contract = worker
+        .import_contract(&usdt_token_id, &worker_testnet)
+        .initial_balance(parse_near!("10000000 N"))
+        .with_data()
+        .block_height(72_000_000)
+        .transact()
+        .await?;
+
+    let usdn_token_id = "usdn.testnet".parse()?;
+    let usdn_contract = worker
+        .import_contract(&usdn_token_id, &worker_testnet)
+        .initial_balance(parse_near!("10000000 N"))
+        .with_data()
+        .block_height(82_000_000)
+        .transact()
+        .await?;
+
+    let user = gen_user_account(&worker, "user.test.near").await?;
+    let owner = gen_user_account(&worker, "owner.test.near").await?;
+
+    // Fee-free by default, so expected swap amounts follow the bare tokens ratio
+    let mut args = json!({
+      "owner_id": owner.id(),
+      "tokens": (usdn_token_id, usdt_token_id),
+      "fee_bps": 0,
+    });
+    if let (Some(args), Some(init_args)) = (args.as_object_mut(), init_args.as_object()) {
+        args.extend(init_args.clone());
+    }
+    let contract = build_contract(&worker, "./", args).await?;
+
+    for account in [&user, &owner] {
+        let _ = contract
+            .as_account()
+            .transfer_near(account.id(), 25 * ONE_NEAR)
+            .await?
+            .into_result()?;
+
+        register_user(&usdn_contract, account).await?;
+        register_user(&usdt_contract, account).await?;
+    }
+
+    register_user(&usdt_contract, usdn_contract.as_account()).await?;
+    mint_tokens(
+        &usdt_contract,
+        usdn_contract.as_account(),
+        (300_000 * ONE_USDT).into(),
+    )
+    .await?;
+
+    for account in [&user, &owner] {
+        mint_tokens(&usdt_contract, account, (200_000 * ONE_USDT).into()).await?;
+        exchange_usdt_for_usdn(&usdn_contract, account, (100_000 * ONE_USDN).into()).await?;
+    }
+
+    Ok(TestPool {
+        contract,
+        owner,
+        user,
+        usdn_contract,
+        usdt_contract,
+    })
+}
+
+pub(crate) async fn gen_user_account<T>(
+    worker: &Worker<T>,
+    account_id: &str,
+) -> anyhow::Result<Account>
+where
+    T: DevNetwork + Send + Sync,
+{
+    let id = workspaces::AccountId::from_str(account_id)?;
+    let sk = SecretKey::from_random(KeyType::ED25519);
+
+    let account = worker.create_tla(id, sk).await?.into_result()?;
+
+    Ok(account)
+}
+
+pub(crate) async fn build_contract<T>(
+    worker: &Worker<T>,
+    project_path: &str,
+    args_json: serde_json::Value,
+) -> anyhow::Result<Contract>
+where
+    T: NetworkInfo + NetworkClient + DevNetwork + Send + Sync,
+{
+    let wasm = workspaces::compile_project(project_path).await?;
+    let (id, sk) = worker.dev_generate().await;
+
+    let contract = worker
+        .create_tla_and_deploy(id, sk, &wasm)
+        .await?
+        .into_result()?;
+
+    // initialize contract
+    let res = contract
+        .call("init")
+        .args_json(args_json)
+        .max_gas()
+        .transact()
+        .await;
+
+    match res {
+        Ok(_) => (),
+        Err(_) => {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to build & init contract. Log {:?}",
+                res
+            )))
+        }
+    };
+
+    Ok(contract)
+}
+
+/// Registers `account` at the pool with the maximum storage balance, the excess deposit is refunded
+async fn register_pool_account(pool_contract: &Contract, account: &Account) -> anyhow::Result<()> {
+    account
+        .call(pool_contract.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(ONE_NEAR)
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    Ok(())
+}
+
+async fn register_user(target_contract: &Contract, user: &Account) -> anyhow::Result<()> {
+    let res = user
+        .call(target_contract.id(), "storage_deposit")
+        .args_json(json!({
+            "registration_only": true,
+        }))
+        .deposit(ONE_NEAR)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Registration of user `{:?}` failed on target contract `{:?}`. Log {:?}",
+            user.id(),
+            target_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn mint_tokens(
+    token_contract: &Contract,
+    user: &Account,
+    amount: U128,
+) -> anyhow::Result<()> {
+    let res = user
+        .call(token_contract.id(), "mint")
+        .args_json(json!({
+            "account_id": user.id(),
+            "amount": amount,
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Mint token `{:?}` for user `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            user.id(),
+            res
+        ))),
+    }
+}
+
+async fn exchange_usdt_for_usdn(
+    usdn_contract: &Contract,
+    user: &Account,
+    amount: U128,
+) -> anyhow::Result<()> {
+    let res = usdn_contract
+        .call("ft_transfer")
+        .args_json(json!({
+            "receiver_id": user.id(),
+            "amount": amount,
+            "msg": "",
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Exchange USDT to USDN for user `{:?}` failed. Log {:?}",
+            user.id(),
+            res
+        ))),
+    }
+}
+
+async fn deposit_tokens(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+) -> anyhow::Result<Vec<String>> {
+    let res = sender
+        .call(token_contract.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver.id(),
+            "amount": amount,
+            "msg": "",
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(res.logs().into_iter().map(String::from).collect()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Deposit tokens `{:?}` by user `{:?}` to user `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            sender.id(),
+            receiver.id(),
+            res
+        ))),
+    }
+}
+
+async fn transfer_tokens(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+) -> anyhow::Result<()> {
+    let res = sender
+        .call(token_contract.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": receiver.id(),
+            "amount": amount,
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Transfer tokens `{:?}` by user `{:?}` to user `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            sender.id(),
+            receiver.id(),
+            res
+        ))),
+    }
+}
+
+async fn swap_tokens(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+) -> anyhow::Result<Vec<String>> {
+    swap_tokens_with_min(token_contract, sender, receiver, amount, None).await
+}
+
+async fn swap_tokens_with_min(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+    min_amount_out: Option<U128>,
+) -> anyhow::Result<Vec<String>> {
+    let res = sender
+        .call(token_contract.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver.id(),
+            "amount": amount,
+            "msg": serde_json::to_string(&TransferCommand { r#type: TransferType::Swap, min_amount_out, amount_out: None, min_output_usd: None, max_price_impact_bps: None, deadline_ns: None }).unwrap(),
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(res.logs().into_iter().map(String::from).collect()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Swap tokens `{:?}` by user `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            sender.id(),
+            receiver.id(),
+            res
+        ))),
+    }
+}
+
+async fn swap_tokens_exact_out(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+    amount_out: U128,
+) -> anyhow::Result<Vec<String>> {
+    let res = sender
+        .call(token_contract.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver.id(),
+            "amount": amount,
+            "msg": serde_json::to_string(&TransferCommand { r#type: TransferType::SwapExactOut, min_amount_out: None, amount_out: Some(amount_out), min_output_usd: None, max_price_impact_bps: None, deadline_ns: None }).unwrap(),
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(res.logs().into_iter().map(String::from).collect()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Swap tokens `{:?}` for exact output by user `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            sender.id(),
+            receiver.id(),
+            res
+        ))),
+    }
+}
+
+async fn commit_swap(
+    token_contract: &Contract,
+    sender: &Account,
+    receiver: &Account,
+    amount: U128,
+) -> anyhow::Result<()> {
+    let res = sender
+        .call(token_contract.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver.id(),
+            "amount": amount,
+            "msg": serde_json::to_string(&TransferCommand { r#type: TransferType::CommitSwap, min_amount_out: None, amount_out: None, min_output_usd: None, max_price_impact_bps: None, deadline_ns: None }).unwrap(),
+        }))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Commit swap of tokens `{:?}` by user `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_contract.id(),
+            sender.id(),
+            receiver.id(),
+            res
+        ))),
+    }
+}
+
+/// Calls `execute_swap` or `cancel_swap` of the user's committed swap
+async fn call_committed_swap(
+    pool_contract: &Contract,
+    user: &Account,
+    method: &str,
+) -> anyhow::Result<()> {
+    let res = user
+        .call(pool_contract.id(), method)
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Call `{}` by user `{:?}` at pool `{:?}` failed. Log {:?}",
+            method,
+            user.id(),
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+/// Adds liquidity and verifies the pool backing, so tests can swap right away
 async fn add_liquidity(
     pool_contract: &Contract,
     user: &Account,
@@ -692,7 +7189,7 @@ async fn add_liquidity(
         .await?;
 
     match res.clone().into_result() {
-        Ok(_) => Ok(()),
+        Ok(_) => verify_backing(pool_contract, user).await,
         Err(_) => Err(anyhow::Error::msg(format!(
             "Add liquidity to the pool `{:?}` by user `{:?}` failed. Log {:?}",
             pool_contract.id(),
@@ -702,6 +7199,52 @@ async fn add_liquidity(
     }
 }
 
+async fn add_liquidity_at_price(
+    pool_contract: &Contract,
+    user: &Account,
+    amounts: [U128; 2],
+    expected_price: &str,
+) -> anyhow::Result<()> {
+    let res = user
+        .call(pool_contract.id(), "add_liquidity")
+        .args_json(json!({
+            "amounts": amounts,
+            "expected_price": expected_price,
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => verify_backing(pool_contract, user).await,
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Add liquidity at price {} to the pool `{:?}` by user `{:?}` failed. Log {:?}",
+            expected_price,
+            pool_contract.id(),
+            user.id(),
+            res
+        ))),
+    }
+}
+
+async fn verify_backing(pool_contract: &Contract, user: &Account) -> anyhow::Result<()> {
+    let res = user
+        .call(pool_contract.id(), "verify_backing")
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().json::<bool>() {
+        Ok(true) => Ok(()),
+        _ => Err(anyhow::Error::msg(format!(
+            "Verify backing of the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
 async fn remove_liquidity(
     pool_contract: &Contract,
     user: &Account,
@@ -768,3 +7311,341 @@ async fn create_token_wallets(
         ))),
     }
 }
+
+async fn set_max_deposit_per_account(
+    pool_contract: &Contract,
+    owner: &Account,
+    token_id: &AccountId,
+    max_deposit: Option<U128>,
+) -> anyhow::Result<()> {
+    let res = owner
+        .call(pool_contract.id(), "set_max_deposit_per_account")
+        .args_json(json!({
+            "token_id": token_id,
+            "max_deposit": max_deposit,
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Set max deposit per account at the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn ft_balance_of(token_contract: &Contract, user: &Account) -> anyhow::Result<U128> {
+    let res = token_contract
+        .view("ft_balance_of")
+        .args_json(json!({
+            "account_id": user.id(),
+        }))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<U128>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse balance failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View balance of user `{:?}` at token `{:?}` failed. Log {:?}",
+            user.id(),
+            token_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_account_balance(
+    pool_contract: &Contract,
+    user: &Account,
+    token_id: &AccountId,
+) -> anyhow::Result<U128> {
+    let res = pool_contract
+        .view("get_account_balance")
+        .args_json(json!({
+            "account_id": user.id(),
+            "token_id": token_id,
+        }))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<U128>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse account balance failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View balance of user `{:?}` at pool `{:?}` failed. Log {:?}",
+            user.id(),
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_swap_count(pool_contract: &Contract, user: &Account) -> anyhow::Result<u64> {
+    let res = pool_contract
+        .view("get_swap_count")
+        .args_json(json!({
+            "account_id": user.id(),
+        }))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<u64>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse swap count failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View swap count of user `{:?}` at pool `{:?}` failed. Log {:?}",
+            user.id(),
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+/// Calls argument-less owner action `method`, confirmed by 1 yoctoNEAR
+async fn call_owner_action(
+    pool_contract: &Contract,
+    caller: &Account,
+    method: &str,
+) -> anyhow::Result<()> {
+    let res = caller
+        .call(pool_contract.id(), method)
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Call `{}` of the pool `{:?}` failed. Log {:?}",
+            method,
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn update_config(
+    pool_contract: &Contract,
+    owner: &Account,
+    patch: serde_json::Value,
+) -> anyhow::Result<()> {
+    let res = owner
+        .call(pool_contract.id(), "update_config")
+        .args_json(json!({
+            "patch": patch,
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Update config of the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_decimals(pool_contract: &Contract, token_id: &AccountId) -> anyhow::Result<u8> {
+    let res = pool_contract
+        .view("get_decimals")
+        .args_json(json!({
+            "token_id": token_id,
+        }))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<u8>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse decimals failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View decimals of token `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_id,
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_activity(pool_contract: &Contract) -> anyhow::Result<Activity> {
+    let res = pool_contract
+        .view("get_activity")
+        .args_json(json!(()))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<Activity>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse `Activity` failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View activity of the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+/// Views `get_amount_out`, or `get_amount_out_at` if hypothetical `reserves` are provided
+async fn get_amount_out(
+    pool_contract: &Contract,
+    token_id_in: &AccountId,
+    amount_in: U128,
+    reserves: Option<[U128; 2]>,
+) -> anyhow::Result<U128> {
+    let res = match reserves {
+        Some(reserves) => pool_contract.view("get_amount_out_at").args_json(json!({
+            "token_id_in": token_id_in,
+            "amount_in": amount_in,
+            "reserves": reserves,
+        })),
+        None => pool_contract.view("get_amount_out").args_json(json!({
+            "token_id_in": token_id_in,
+            "amount_in": amount_in,
+        })),
+    }
+    .await;
+
+    match res {
+        Ok(res) => res
+            .json::<U128>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse amount out failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View amount out of token `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_id_in,
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_utilization(pool_contract: &Contract) -> anyhow::Result<[String; 2]> {
+    let res = pool_contract
+        .view("get_utilization")
+        .args_json(json!(()))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<[String; 2]>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse utilization failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View utilization of the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn check_output_registration(
+    pool_contract: &Contract,
+    user: &Account,
+    token_in: &AccountId,
+) -> anyhow::Result<bool> {
+    let res = user
+        .call(pool_contract.id(), "check_output_registration")
+        .args_json(json!({
+            "account_id": user.id(),
+            "token_in": token_in,
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(res) => res
+            .json::<bool>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse output registration failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Output registration check of user `{:?}` at pool `{:?}` failed. Log {:?}",
+            user.id(),
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_marginal_price(
+    pool_contract: &Contract,
+    token_in: &AccountId,
+) -> anyhow::Result<String> {
+    let res = pool_contract
+        .view("get_marginal_price")
+        .args_json(json!({
+            "token_in": token_in,
+        }))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<String>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse marginal price failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View marginal price of token `{:?}` at pool `{:?}` failed. Log {:?}",
+            token_in,
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+async fn get_owner_capabilities(pool_contract: &Contract) -> anyhow::Result<OwnerCapabilities> {
+    let res = pool_contract
+        .view("get_owner_capabilities")
+        .args_json(json!(()))
+        .await;
+
+    match res {
+        Ok(res) => res
+            .json::<OwnerCapabilities>()
+            .map_err(|e| anyhow::Error::msg(format!("Parse `OwnerCapabilities` failed. {:?}", e))),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "View owner capabilities of the pool `{:?}` failed. Log {:?}",
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
+
+/// Adds `account` to the pool blacklist or removes it from there
+async fn set_blacklisted(
+    pool_contract: &Contract,
+    owner: &Account,
+    account: &Account,
+    blacklisted: bool,
+) -> anyhow::Result<()> {
+    let method = match blacklisted {
+        true => "add_to_blacklist",
+        false => "remove_from_blacklist",
+    };
+
+    let res = owner
+        .call(pool_contract.id(), method)
+        .args_json(json!({
+            "account_id": account.id(),
+        }))
+        .deposit(ONE_YOCTO)
+        .max_gas()
+        .transact()
+        .await?;
+
+    match res.clone().into_result() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "Call `{}` for user `{:?}` at pool `{:?}` failed. Log {:?}",
+            method,
+            account.id(),
+            pool_contract.id(),
+            res
+        ))),
+    }
+}
@@ -1,18 +1,25 @@
-use crate::misc::compute_tokens_ratio;
+use crate::misc::{
+    add_fee, ceil_div, compute_amount_in, compute_amount_out, compute_amount_to_price,
+    compute_amounts_for_shares, compute_balanced_amounts, compute_breakeven_price,
+    compute_entry_value, compute_entry_value_burned, compute_price, compute_shares_to_burn,
+    compute_shares_to_mint, compute_tokens_ratio, compute_value_in, deduct_fee, emit_event,
+    format_price, invert_price, is_ratio_preserved, now_ns, parse_price, FeeMode, RunningState,
+    MAX_BPS, PRICE_DECIMALS,
+};
 use crate::{Contract, ContractExt};
 use near_contract_standards::fungible_token::core::ext_ft_core;
 pub(crate) use near_contract_standards::fungible_token::metadata::{
     ext_ft_metadata, FungibleTokenMetadata,
 };
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-use near_contract_standards::storage_management::StorageBalance;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json;
 use near_sdk::{
-    env, ext_contract, near_bindgen, AccountId, Promise, PromiseError, PromiseOrValue, ONE_NEAR,
-    ONE_YOCTO,
+    assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance, Promise, PromiseError,
+    PromiseOrValue, ONE_NEAR, ONE_YOCTO,
 };
 use primitive_types::U256;
 use std::cmp::Ordering;
@@ -20,24 +27,69 @@ use std::cmp::Ordering;
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct TokenWallet {
-    token_id: AccountId,
-    metadata: FungibleTokenMetadata,
-    deposit: U128,
-    liquidity: U128,
+    pub(crate) token_id: AccountId,
+    pub(crate) metadata: FungibleTokenMetadata,
+    pub(crate) deposit: U128,
+    pub(crate) liquidity: U128,
+    /// Total of the token balances held for registered accounts other than the owner
+    pub(crate) accounts_deposit: U128,
+    /// Optional cap of the deposit tracked for a single account
+    pub(crate) max_deposit_per_account: Option<U128>,
+    /// Optional memo attached to outgoing token transfers
+    pub(crate) transfer_memo: Option<String>,
+    /// Amount of liquidity a swap never takes out of the pool
+    pub(crate) reserve_buffer: U128,
+    /// Amount of swap input retained by the pool on top of the priced input, absorbs rounding
+    pub(crate) rounding_buffer: U128,
+    /// Swap output below this amount is not worth the gas, such swaps are refunded
+    pub(crate) min_meaningful_output: U128,
+    /// Decimals used by the price helpers instead of the metadata ones, swap math keeps native units
+    pub(crate) decimals_override: Option<u8>,
+    /// Token may deliver less than the transferred amount, so deposits are verified against the actual balance
+    pub(crate) verify_deposits: bool,
+    /// Last observed balance of the pool at the token contract, the baseline of deposit verification
+    pub(crate) balance_observation: Option<BalanceObservation>,
+}
+
+/// Balance of the pool at the token contract observed together with the amount tracked by the pool
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BalanceObservation {
+    pub balance: U128,
+    /// Liquidity, deposits & protocol fees tracked at the time of the observation
+    pub tracked: U128,
 }
 
+/// Default amount of liquidity a swap never takes out of the pool, so tokens ratio never drops to zero
+pub(crate) const DEFAULT_RESERVE_BUFFER: u128 = 1;
+
+/// Maximum length of the memo attached to outgoing token transfers
+pub(crate) const MAX_TRANSFER_MEMO_LENGTH: usize = 256;
+
+/// $NEAR attached to register a user at the output token, covers the storage of a standard fungible token
+/// account, while the excess is refunded to the pool
+pub(crate) const SPONSORED_REGISTRATION_DEPOSIT: Balance = 1_250_000_000_000_000_000_000;
+
+/// Tolerance of the initial pool price from the expected one in basis points
+pub(crate) const EXPECTED_PRICE_TOLERANCE_BPS: u64 = 100;
+
+/// Default maximum deviation of added liquidity amounts from the reserves ratio in basis points
+pub(crate) const DEFAULT_MAX_ADD_DEVIATION_BPS: u32 = 10;
+
 pub(crate) trait TokenWalletProvider {
     fn create_token_wallet(&mut self, token: AccountId) -> Promise;
 
+    #[allow(clippy::too_many_arguments)]
     fn on_created_tokens_wallets(
         &mut self,
         token1_id: AccountId,
         token2_id: AccountId,
+        deposit: U128,
         token1_metadata: Result<FungibleTokenMetadata, PromiseError>,
         token1_wallet_storage_balance: Result<StorageBalance, PromiseError>,
         token2_metadata: Result<FungibleTokenMetadata, PromiseError>,
         token2_wallet_storage_balance: Result<StorageBalance, PromiseError>,
-    );
+    ) -> bool;
 }
 
 pub(crate) trait SwapProvider {
@@ -46,14 +98,17 @@ pub(crate) trait SwapProvider {
         sender_id: AccountId,
         token_id_in: AccountId,
         amount_in: u128,
+        min_amount_out: Option<u128>,
+        exact_amount_out: Option<u128>,
+        max_price_impact_bps: Option<u16>,
     ) -> Result<PromiseOrValue<U128>, &'static str>;
 
     fn on_swap_complete(
         &mut self,
-        token_wallet_in: TokenWallet,
-        token_wallet_out: TokenWallet,
-        token1_is_input: bool,
-        amount_in: U128,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
         transfer_result: Result<(), PromiseError>,
     ) -> PromiseOrValue<U128>;
 }
@@ -64,11 +119,157 @@ pub struct PoolView {
     pub token_ids: [AccountId; 2],
     pub decimals: [u8; 2],
     pub amounts: [U128; 2],
-    pub ratio: String,
+    #[serde(with = "crate::misc::u256_dec_format")]
+    pub ratio: U256,
+    pub fee_bps: u16,
+}
+
+/// Reserves & LP shares read together for LP math
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LpState {
+    /// Token1 & Token2 liquidity
+    pub reserves: [U128; 2],
+    /// Total LP shares owned by liquidity providers
+    pub total_shares: U128,
+}
+
+/// Data of a registered account, as of [Contract::get_dashboard]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountView {
+    pub storage_balance: StorageBalance,
+    pub last_swap: Option<LastSwap>,
+    pub swap_count: u64,
+}
+
+/// Pool & account data loaded by a frontend in one view call
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Dashboard {
+    pub pool: PoolView,
+    /// `None` for unregistered accounts
+    pub account: Option<AccountView>,
+    pub user_shares: U128,
+    pub user_deposits: [U128; 2],
+}
+
+/// Outcome of a swap
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SwapStatus {
+    /// Whole input was swapped
+    Filled,
+    /// Part of the input was swapped due to the output cap, the rest was refunded
+    PartiallyFilled,
+    /// Whole input was refunded
+    Refunded,
+}
+
+/// Data of the `swap` event
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapEvent {
+    pub sender_id: AccountId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub status: SwapStatus,
+    /// Input amount filled by the swap
+    pub amount_in: U128,
+    pub amount_out: U128,
+    /// Input amount refunded to the sender
+    pub amount_refunded: U128,
+    /// Decimals-adjusted marginal price of `token_in` after the swap, as of [Contract::get_marginal_price],
+    /// `None` if the pool is empty
+    pub marginal_price: Option<String>,
+}
+
+/// Data of the `deposit` event
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositEvent {
+    pub account_id: AccountId,
+    pub token_id: AccountId,
+    pub amount: U128,
+    /// Token1 & Token2 balances of the account after the deposit, as of [Contract::get_account_deposits]
+    pub balances: [U128; 2],
+}
+
+/// Amounts of a swap passed to [SwapProvider::on_swap_complete]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAmounts {
+    /// Input amount used by the swap, including the fee
+    pub amount_in: U128,
+    /// Output amount transferred to the sender
+    pub amount_out: U128,
+    /// Input amount left unused due to the output cap, which is refunded
+    pub amount_unused: U128,
+    /// Fee in the token of the pool's [FeeMode], including the protocol share
+    pub fee: U128,
+    /// Protocol's share of the fee, which is accrued outside liquidity once the swap completes
+    pub protocol_fee: U128,
+}
+
+/// Last swap of an account, either filled or refunded
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LastSwap {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub status: SwapStatus,
+    /// Input amount filled by the swap
+    pub amount_in: U128,
+    pub amount_out: U128,
+    /// Input amount refunded to the sender
+    pub amount_refunded: U128,
+    /// Block height of the swap completion
+    pub block_height: U64,
+}
+
+/// Swap amounts computed by [TokenWallet::quote_swap]
+pub(crate) struct SwapQuote {
+    /// Input amount used by the swap
+    pub(crate) amount_in: u128,
+    /// Input amount less fee & rounding buffer, which is priced by the tokens ratio
+    pub(crate) amount_in_priced: u128,
+    /// Output amount, less the output fee
+    pub(crate) amount_out: u128,
+    /// Input amount left unused due to the output cap, which is refunded
+    pub(crate) amount_unused: u128,
+    /// Fee deducted from the output in [FeeMode::OnOutput], which is retained in the output liquidity
+    pub(crate) output_fee: u128,
+}
+
+/// Swap checked by [Contract::prepare_swap]
+pub(crate) struct PreparedSwap {
+    pub(crate) amounts: SwapAmounts,
+    /// Input & output token liquidity after the swap completes, less the protocol fee
+    pub(crate) liquidity: [u128; 2],
+}
+
+/// Swap moving the pool price to a reference one
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ArbInfo {
+    /// Token to sell to the pool
+    pub token_in: AccountId,
+    /// Token to buy from the pool
+    pub token_out: AccountId,
+    /// Input amount moving the pool price to the reference one
+    pub amount_in: U128,
+    /// Swap output for `amount_in`
+    pub amount_out: U128,
+    /// Value of `amount_out` at the reference price less `amount_in`, in units of `token_in`
+    pub expected_profit: U128,
 }
 
 #[near_bindgen]
 impl TokenWalletProvider for Contract {
+    /// Called internally when the pool wallets are created, as it spends the pool's $NEAR
+    #[private]
     fn create_token_wallet(&mut self, token: AccountId) -> Promise {
         // first fetch token metadata and then creates a wallet for it
         ext_ft_metadata::ext(token.clone()).ft_metadata().and(
@@ -83,26 +284,57 @@ impl TokenWalletProvider for Contract {
         &mut self,
         token1_id: AccountId,
         token2_id: AccountId,
+        deposit: U128,
         #[callback_result] token1_metadata: Result<FungibleTokenMetadata, PromiseError>,
         #[callback_result] token1_wallet_storage_balance: Result<StorageBalance, PromiseError>,
         #[callback_result] token2_metadata: Result<FungibleTokenMetadata, PromiseError>,
         #[callback_result] token2_wallet_storage_balance: Result<StorageBalance, PromiseError>,
-    ) {
-        let _ = token1_wallet_storage_balance
-            .unwrap_or_else(|_| env::panic_str("Token1 wallet failed to register"));
+    ) -> bool {
+        // $NEAR locked by successful wallets registrations at token contracts
+        let storage_used: Balance = [
+            &token1_wallet_storage_balance,
+            &token2_wallet_storage_balance,
+        ]
+        .into_iter()
+        .filter_map(|storage_balance| storage_balance.as_ref().ok())
+        .map(|storage_balance| u128::from(storage_balance.total))
+        .sum();
 
-        self.token1_wallet = Some(TokenWallet::new(
-            token1_id,
-            token1_metadata.unwrap_or_else(|_| env::panic_str("Failed to fetch Token1 metadata")),
-        ));
+        let result = match (
+            token1_metadata,
+            token1_wallet_storage_balance,
+            token2_metadata,
+            token2_wallet_storage_balance,
+        ) {
+            (Ok(token1_metadata), Ok(_), Ok(token2_metadata), Ok(_)) => {
+                Ok((token1_metadata, token2_metadata))
+            }
+            (_, Err(_), _, _) => Err("Token1 wallet failed to register"),
+            (Err(_), _, _, _) => Err("Failed to fetch Token1 metadata"),
+            (_, _, _, Err(_)) => Err("Token2 wallet failed to register"),
+            _ => Err("Failed to fetch Token2 metadata"),
+        };
 
-        let _ = token2_wallet_storage_balance
-            .unwrap_or_else(|_| env::panic_str("Token2 wallet failed to register"));
+        match result {
+            Ok((token1_metadata, token2_metadata)) => {
+                self.token1_wallet = Some(TokenWallet::new(token1_id, token1_metadata));
+                self.token2_wallet = Some(TokenWallet::new(token2_id, token2_metadata));
 
-        self.token2_wallet = Some(TokenWallet::new(
-            token2_id,
-            token2_metadata.unwrap_or_else(|_| env::panic_str("Failed to fetch Token2 metadata")),
-        ));
+                true
+            }
+
+            // Leave wallets uncreated, so the owner could retry, and refund unused deposit
+            Err(e) => {
+                env::log_str(&format!("Wallets creation failed. Error: {}", e));
+
+                let refund = u128::from(deposit).saturating_sub(storage_used);
+                if refund > 0 {
+                    Promise::new(self.owner_id.clone()).transfer(refund);
+                }
+
+                false
+            }
+        }
     }
 }
 
@@ -113,10 +345,19 @@ pub trait StorageManagementProvider {
         account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance;
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds;
 }
 
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
+    /// Deposits or swaps transferred tokens depending on `msg`
+    ///
+    /// Resolves to the unused amount of transferred tokens, which the token contract refunds to the sender:
+    /// the whole amount if the command fails, otherwise the input left unused by the swap. Swap output isn't
+    /// part of the result, it's reported by the `swap` event and [Contract::get_last_swap]
     #[payable]
     fn ft_on_transfer(
         &mut self,
@@ -131,11 +372,69 @@ impl FungibleTokenReceiver for Contract {
 
         let token_id = env::predecessor_account_id();
 
-        let result = match serde_json::from_str::<TransferCommand>(&msg) {
-            Ok(TransferCommand {
-                r#type: TransferType::Swap,
-            }) => self.on_transfer_swap(sender_id, token_id, amount),
-            _ => self.on_transfer_deposit(sender_id, token_id, amount),
+        let result = if self.token1_wallet.is_none() || self.token2_wallet.is_none() {
+            Err("Pool not ready")
+        } else if msg.is_empty() {
+            self.on_transfer_deposit(sender_id, token_id, amount)
+        } else {
+            // Malformed command is refunded rather than deposited, so client typos don't go unnoticed
+            match serde_json::from_str::<TransferCommand>(&msg) {
+                // Zero deadline is always in the past
+                Ok(TransferCommand {
+                    deadline_ns: Some(deadline_ns),
+                    ..
+                }) if now_ns() >= deadline_ns.0 => Err("Swap deadline has passed"),
+                Ok(TransferCommand {
+                    r#type: TransferType::Swap,
+                    min_amount_out,
+                    min_output_usd,
+                    max_price_impact_bps,
+                    ..
+                }) => self
+                    .with_min_output_usd(&token_id, min_amount_out, min_output_usd)
+                    .and_then(|min_amount_out| {
+                        self.on_transfer_swap(
+                            sender_id,
+                            token_id,
+                            amount,
+                            min_amount_out,
+                            None,
+                            max_price_impact_bps,
+                        )
+                    }),
+                Ok(TransferCommand {
+                    r#type: TransferType::SwapExactOut,
+                    amount_out,
+                    max_price_impact_bps,
+                    ..
+                }) => match amount_out {
+                    // Exact output is the caller's own slippage bound
+                    Some(amount_out) => self.on_transfer_swap(
+                        sender_id,
+                        token_id,
+                        amount,
+                        Some(amount_out),
+                        Some(amount_out),
+                        max_price_impact_bps,
+                    ),
+                    None => Err("Exact output amount is not provided"),
+                },
+                Ok(TransferCommand {
+                    r#type: TransferType::CommitSwap,
+                    min_amount_out,
+                    min_output_usd,
+                    ..
+                }) => self
+                    .with_min_output_usd(&token_id, min_amount_out, min_output_usd)
+                    .and_then(|min_amount_out| {
+                        self.on_transfer_commit_swap(sender_id, token_id, amount, min_amount_out)
+                    }),
+                Err(e) => {
+                    env::log_str(&format!("Invalid transfer command: {}", e));
+
+                    Err("Invalid transfer command")
+                }
+            }
         };
 
         match result {
@@ -153,72 +452,63 @@ impl FungibleTokenReceiver for Contract {
 
 #[near_bindgen]
 impl SwapProvider for Contract {
+    /// Called internally once the input of `sender_id` is received, as the input isn't checked here
+    #[private]
     #[handle_result]
     fn swap_tokens(
         &mut self,
         sender_id: AccountId,
         token_id_in: AccountId,
         amount_in: u128,
+        min_amount_out: Option<u128>,
+        exact_amount_out: Option<u128>,
+        max_price_impact_bps: Option<u16>,
     ) -> Result<PromiseOrValue<U128>, &'static str> {
         env::log_str(&*format!(
             "User {:?} requests to swap {} `{}` token(s)",
             sender_id, amount_in, token_id_in
         ));
 
-        let (token_wallet_in, token_wallet_out, token1_is_input) =
-            self.get_swap_tokens_wallets_mut(&token_id_in)?;
+        self.check_contract_running()?;
 
-        let ratio = compute_tokens_ratio(
-            token_wallet_in.liquidity.into(),
-            token_wallet_out.liquidity.into(),
+        self.update_price_cumulative();
+
+        let PreparedSwap { amounts, liquidity } = self.prepare_swap(
+            &sender_id,
+            &token_id_in,
+            amount_in,
+            min_amount_out,
+            exact_amount_out,
+            max_price_impact_bps,
         )?;
 
-        let amount_out = if token1_is_input {
-            // amount_out = token2_liquidity - ratio / (token1_liquidity + amount_in)
-            U256::from(u128::from(token_wallet_in.liquidity))
-                .checked_add(amount_in.into())
-                .and_then(|sum| ratio.checked_div(sum))
-                .and_then(|res| U256::from(u128::from(token_wallet_out.liquidity)).checked_sub(res))
-                .ok_or("Computation overflow")?
-                .as_u128()
-        } else {
-            // amount_out = ratio / (token2_liquidity - amount_in) - token1_liquidity
-            U256::from(u128::from(token_wallet_in.liquidity))
-                .checked_sub(amount_in.into())
-                .and_then(|sub| ratio.checked_div(sub))
-                .and_then(|res| res.checked_sub(U256::from(u128::from(token_wallet_out.liquidity))))
-                .ok_or("Computation overflow")?
-                .as_u128()
-        };
+        let (token_wallet_in, token_wallet_out, _) =
+            self.get_swap_tokens_wallets_mut(&token_id_in)?;
 
         env::log_str(&*format!(
             "Swap {} {} for {} {}",
-            amount_in,
+            amounts.amount_in.0,
             token_wallet_in.metadata.symbol,
-            amount_out,
+            amounts.amount_out.0,
             token_wallet_out.metadata.symbol
         ));
 
-        let mut token_wallet_in_new = token_wallet_in.clone();
-        token_wallet_in_new.liquidity = u128::from(token_wallet_in.liquidity)
-            .checked_add(amount_in)
-            .ok_or("Input token liquidity overflow")?
-            .into();
-
-        let mut token_wallet_out_new = token_wallet_out.clone();
-        token_wallet_out_new.liquidity = u128::from(token_wallet_out_new.liquidity)
-            .checked_sub(amount_out)
-            .ok_or("Output token liquidity overflow")?
-            .into();
+        // Output is reserved until the transfer resolves, so nothing else can take it out of the pool meanwhile,
+        // while the input is credited to the liquidity only once the swap completes
+        token_wallet_out.liquidity = liquidity[1].into();
 
         Ok(ext_ft_core::ext(token_wallet_out.token_id.clone())
             .with_attached_deposit(ONE_YOCTO)
-            .ft_transfer(sender_id, amount_out.into(), None)
+            .ft_transfer(
+                sender_id.clone(),
+                amounts.amount_out,
+                token_wallet_out.transfer_memo.clone(),
+            )
             .then(Self::ext(env::current_account_id()).on_swap_complete(
-                token_wallet_in_new,
-                token_wallet_out_new,
-                token1_is_input,
-                amount_in.into(),
+                sender_id,
+                token_wallet_in.token_id.clone(),
+                token_wallet_out.token_id.clone(),
+                amounts,
             ))
             .into())
     }
@@ -226,39 +516,258 @@ impl SwapProvider for Contract {
     #[private]
     fn on_swap_complete(
         &mut self,
-        token_wallet_in: TokenWallet,
-        token_wallet_out: TokenWallet,
-        token1_is_input: bool,
-        amount_in: U128,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
         #[callback_result] transfer_result: Result<(), PromiseError>,
     ) -> PromiseOrValue<U128> {
         match transfer_result {
-            Ok(_) if token1_is_input => {
-                self.token1_wallet = Some(token_wallet_in);
-                self.token2_wallet = Some(token_wallet_out);
+            Ok(_) => self.complete_swap(sender_id, token_id_in, token_id_out, amounts),
+            // Output transfer may have failed because the sender isn't registered at the output token
+            Err(_) if self.can_sponsor_user_registration() => self
+                .retry_swap_with_sponsored_registration(
+                    sender_id,
+                    token_id_in,
+                    token_id_out,
+                    amounts,
+                )
+                .into(),
+            Err(_) => self.fail_swap(sender_id, token_id_in, token_id_out, amounts),
+        }
+    }
+}
 
-                PromiseOrValue::Value(0.into())
-            }
+impl Contract {
+    /// Credits the input of the swap, whose output was transferred, to the liquidity,
+    /// returns the unused input refunded to the sender
+    ///
+    /// Only the swap's own changes are applied, so anything changed while the transfer was pending is kept
+    fn complete_swap(
+        &mut self,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
+    ) -> PromiseOrValue<U128> {
+        let SwapAmounts {
+            amount_in,
+            amount_out,
+            amount_unused,
+            protocol_fee,
+            ..
+        } = amounts;
 
-            Ok(_) => {
-                self.token1_wallet = Some(token_wallet_out);
-                self.token2_wallet = Some(token_wallet_in);
+        self.record_swap(
+            sender_id.clone(),
+            token_id_in.clone(),
+            token_id_out.clone(),
+            &amounts,
+        );
 
-                PromiseOrValue::Value(0.into())
-            }
+        // Reserves held until the callback are accumulated before the swap applies
+        self.update_price_cumulative();
 
-            Err(_) => {
-                env::log_str(&*format!(
-                    "Swap tokens {} `{:?}` for `{:?}` failed!",
-                    u128::from(amount_in),
-                    token_wallet_in.metadata.symbol,
-                    token_wallet_out.metadata.symbol
-                ));
+        // Output was reserved when the swap started, so only the input less protocol fee is left to apply
+        let protocol_fee_in = match self.fee_mode {
+            FeeMode::OnInput => protocol_fee.0,
+            FeeMode::OnOutput => 0,
+        };
+        let fee_token_id = self.fee_token_id(&token_id_in, &token_id_out);
+        if let Ok(is_token1) = self.is_token1(fee_token_id) {
+            let index = if is_token1 { 0 } else { 1 };
+            self.protocol_fees[index] = self.protocol_fees[index].saturating_add(protocol_fee.0);
+        }
+        if let Ok(token_wallet_in) = self.get_token_wallet_mut(&token_id_in) {
+            token_wallet_in.liquidity = token_wallet_in
+                .liquidity
+                .0
+                .saturating_add(amount_in.0 - protocol_fee_in)
+                .into();
+        }
 
-                PromiseOrValue::Value(amount_in)
-            }
+        if self.auto_compound {
+            self.compound_protocol_fees();
+        }
+
+        // Reported once the input is credited, so the event carries the post-swap marginal price
+        self.report_swap(
+            sender_id.clone(),
+            LastSwap {
+                token_in: token_id_in,
+                token_out: token_id_out,
+                status: if amount_unused.0 > 0 {
+                    SwapStatus::PartiallyFilled
+                } else {
+                    SwapStatus::Filled
+                },
+                amount_in,
+                amount_out,
+                amount_refunded: amount_unused,
+                block_height: env::block_height().into(),
+            },
+        );
+
+        self.increment_swap_count(&sender_id);
+        self.activity.last_swap_ts = Some(now_ns().into());
+
+        PromiseOrValue::Value(amount_unused)
+    }
+
+    /// Handles failed output transfer of the swap, returns the reserved output to the liquidity
+    /// and the whole input refunded to the sender
+    fn fail_swap(
+        &mut self,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
+    ) -> PromiseOrValue<U128> {
+        let SwapAmounts {
+            amount_in,
+            amount_out,
+            amount_unused,
+            protocol_fee,
+            ..
+        } = amounts;
+
+        env::log_str(&*format!(
+            "Swap tokens {} `{}` for `{}` failed!",
+            u128::from(amount_in),
+            token_id_in,
+            token_id_out
+        ));
+
+        // Reserved output, including the protocol fee taken from it, is returned to the liquidity,
+        // though actual pool balance may have drifted
+        let protocol_fee_out = match self.fee_mode {
+            FeeMode::OnInput => 0,
+            FeeMode::OnOutput => protocol_fee.0,
+        };
+        if let Ok(token_wallet_out) = self.get_token_wallet_mut(&token_id_out) {
+            token_wallet_out.liquidity = token_wallet_out
+                .liquidity
+                .0
+                .saturating_add(amount_out.0 + protocol_fee_out)
+                .into();
+        }
+        env::log_str(&format!(
+            "Critical: output transfer of `{}` failed, pool balance may be lower than tracked liquidity",
+            token_id_out
+        ));
+
+        if self.auto_pause_on_transfer_failure {
+            self.running_state = RunningState::Paused;
+            self.pause_reason = Some("Swap output transfer failed".to_string());
+            env::log_str("Contract paused due to output transfer failure");
+        }
+
+        // Refund full transferred amount
+        let amount_refunded = U128(amount_in.0 + amount_unused.0);
+        self.report_swap(
+            sender_id,
+            LastSwap {
+                token_in: token_id_in,
+                token_out: token_id_out,
+                status: SwapStatus::Refunded,
+                amount_in: 0.into(),
+                amount_out: 0.into(),
+                amount_refunded,
+                block_height: env::block_height().into(),
+            },
+        );
+
+        PromiseOrValue::Value(amount_refunded)
+    }
+
+    /// Records the swap outcome as the sender's last swap & emits the `swap` event
+    fn report_swap(&mut self, sender_id: AccountId, last_swap: LastSwap) {
+        // Post-swap marginal price saves arbitrageurs a follow-up view call
+        let marginal_price = self.get_marginal_price(last_swap.token_in.clone()).ok();
+
+        emit_event(
+            "swap",
+            SwapEvent {
+                sender_id: sender_id.clone(),
+                token_in: last_swap.token_in.clone(),
+                token_out: last_swap.token_out.clone(),
+                status: last_swap.status,
+                amount_in: last_swap.amount_in,
+                amount_out: last_swap.amount_out,
+                amount_refunded: last_swap.amount_refunded,
+                marginal_price,
+            },
+        );
+
+        // Sender pays for its last swap, which isn't kept if the sender can't
+        let storage_usage_before = env::storage_usage();
+        let prev_last_swap = self.last_swaps.insert(&sender_id, &last_swap);
+        if self
+            .charge_account_storage(&sender_id, storage_usage_before)
+            .is_err()
+        {
+            match prev_last_swap {
+                Some(prev_last_swap) => self.last_swaps.insert(&sender_id, &prev_last_swap),
+                None => self.last_swaps.remove(&sender_id),
+            };
         }
     }
+
+    /// Returns `true` if sponsoring of user registrations is enabled & the pool can afford it
+    /// without dropping below its storage staking
+    fn can_sponsor_user_registration(&self) -> bool {
+        let storage_staked = Balance::from(env::storage_usage()) * env::storage_byte_cost();
+
+        self.sponsor_user_registration
+            && env::account_balance()
+                >= storage_staked.saturating_add(SPONSORED_REGISTRATION_DEPOSIT)
+    }
+
+    /// Registers the sender at the output token using the pool's $NEAR and retries the output transfer
+    fn retry_swap_with_sponsored_registration(
+        &mut self,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
+    ) -> Promise {
+        let sponsored = self
+            .sponsored_registrations
+            .get(&sender_id)
+            .unwrap_or_default()
+            .saturating_add(SPONSORED_REGISTRATION_DEPOSIT);
+        self.sponsored_registrations.insert(&sender_id, &sponsored);
+        // Registered sender pays for the registration right away, otherwise once it tops up its storage balance
+        self.charge_sponsored_registration(&sender_id);
+
+        env::log_str(&format!(
+            "Sponsoring registration of {} at `{}` to retry the swap output transfer",
+            sender_id, token_id_out
+        ));
+
+        let memo = self
+            .get_token_wallet(&token_id_out)
+            .ok()
+            .and_then(|token_wallet| token_wallet.transfer_memo.clone());
+
+        // Registration of an already registered account is refunded to the pool
+        ext_storage_management::ext(token_id_out.clone())
+            .with_attached_deposit(SPONSORED_REGISTRATION_DEPOSIT)
+            .storage_deposit(Some(sender_id.clone()), Some(true))
+            .then(
+                ext_ft_core::ext(token_id_out.clone())
+                    .with_attached_deposit(ONE_YOCTO)
+                    .ft_transfer(sender_id.clone(), amounts.amount_out, memo),
+            )
+            .then(
+                Self::ext(env::current_account_id()).on_sponsored_swap_complete(
+                    sender_id,
+                    token_id_in,
+                    token_id_out,
+                    amounts,
+                ),
+            )
+    }
 }
 
 #[near_bindgen]
@@ -269,18 +778,62 @@ impl Contract {
         token_id: AccountId,
         amount: U128,
     ) -> Result<PromiseOrValue<U128>, &'static str> {
-        if !self.is_owner(&sender_id) {
-            return Err("Deposit can be added only by contract owner");
-        }
+        let token_wallet = self.get_token_wallet(&token_id)?;
+        let max_deposit = token_wallet.max_deposit_per_account;
+        let verify_deposits = token_wallet.verify_deposits;
 
-        let token_wallet = self.get_token_wallet_mut(&token_id)?;
+        if self.is_owner(&sender_id) {
+            // Owner's deposit is tracked by the wallet, so it's available to the owner's admin functions
+            let token_wallet = self.get_token_wallet_mut(&token_id)?;
+            let deposit = u128::from(token_wallet.deposit)
+                .checked_add(amount.into())
+                .ok_or("Token deposit overflow")?;
 
-        token_wallet.deposit = u128::from(token_wallet.deposit)
-            .checked_add(amount.into())
-            .ok_or("Token deposit overflow")?
-            .into();
+            if max_deposit.is_some_and(|max_deposit| deposit > max_deposit.0) {
+                return Err("Deposit exceeds maximum per account");
+            }
+
+            token_wallet.deposit = deposit.into();
+        } else {
+            // Deposit of any other account is credited to its balance, the deposit is refunded as a whole
+            // on any failure, so all changes are validated before applying any of them
+            let balance = self
+                .get_account(&sender_id)?
+                .balance(&token_id)
+                .checked_add(amount.into())
+                .ok_or("Token deposit overflow")?;
+
+            if max_deposit.is_some_and(|max_deposit| balance > max_deposit.0) {
+                return Err("Deposit exceeds maximum per account");
+            }
+
+            let accounts_deposit = u128::from(token_wallet.accounts_deposit)
+                .checked_add(amount.into())
+                .ok_or("Token deposit overflow")?;
+
+            self.internal_deposit(&sender_id, &token_id, amount.into())?;
+            self.get_token_wallet_mut(&token_id)?.accounts_deposit = accounts_deposit.into();
+
+            emit_event(
+                "deposit",
+                DepositEvent {
+                    balances: self.get_account_deposits(sender_id.clone()),
+                    account_id: sender_id,
+                    token_id: token_id.clone(),
+                    amount,
+                },
+            );
+        }
+
+        if !verify_deposits {
+            return Ok(PromiseOrValue::Value(0.into()));
+        }
 
-        Ok(PromiseOrValue::Value(0.into()))
+        // Non-standard token may have delivered less than claimed, which is only known from the balance
+        Ok(ext_ft_core::ext(token_id.clone())
+            .ft_balance_of(env::current_account_id())
+            .then(Self::ext(env::current_account_id()).on_deposit_verified(token_id, amount))
+            .into())
     }
 
     fn on_transfer_swap(
@@ -288,88 +841,282 @@ impl Contract {
         sender_id: AccountId,
         token_id_in: AccountId,
         amount_in: U128,
+        min_amount_out: Option<U128>,
+        exact_amount_out: Option<U128>,
+        max_price_impact_bps: Option<u16>,
     ) -> Result<PromiseOrValue<U128>, &'static str> {
-        self.swap_tokens(sender_id, token_id_in, amount_in.into())
+        let result = self
+            .check_contract_running()
+            .and_then(|_| self.assert_min_liquidity_for_swaps())
+            .and_then(|_| {
+                self.swap_tokens(
+                    sender_id.clone(),
+                    token_id_in.clone(),
+                    amount_in.into(),
+                    min_amount_out.map(u128::from),
+                    exact_amount_out.map(u128::from),
+                    max_price_impact_bps,
+                )
+            });
+
+        // Rejected swap of a pool token is refunded as a whole
+        if result.is_err() {
+            if let Ok((_, token_wallet_out)) = self.get_swap_tokens_wallets(&token_id_in) {
+                let token_out = token_wallet_out.token_id.clone();
+                self.report_swap(
+                    sender_id,
+                    LastSwap {
+                        token_in: token_id_in,
+                        token_out,
+                        status: SwapStatus::Refunded,
+                        amount_in: 0.into(),
+                        amount_out: 0.into(),
+                        amount_refunded: amount_in,
+                        block_height: env::block_height().into(),
+                    },
+                );
+            }
+        }
+
+        result
     }
 
-    /// Adds liquidity to the pool from owner's deposit by provided amounts
+    /// Adds liquidity to the pool from the caller's deposit by provided amounts, minting LP shares proportional
+    /// to the contributed liquidity
+    ///
+    /// The owner provides liquidity from the owner's deposit, any other registered account from its balances
+    ///
+    /// When seeding an empty pool, optional `expected_price` of Token1 in Token2, in the format of
+    /// [Contract::get_marginal_price], guards the initial price implied by `amounts`
     #[payable]
     #[handle_result]
-    pub fn add_liquidity(&mut self, amounts: [U128; 2]) -> Result<(), &'static str> {
-        self.assert_owner();
+    pub fn add_liquidity(
+        &mut self,
+        amounts: [U128; 2],
+        expected_price: Option<String>,
+    ) -> Result<(), &'static str> {
+        assert_one_yocto();
+        self.check_contract_running()?;
+
+        self.update_price_cumulative();
 
         let token1_wallet = self
             .token1_wallet
-            .as_mut()
+            .as_ref()
             .ok_or("Token1 wallet is not created")?;
         let token2_wallet = self
             .token2_wallet
-            .as_mut()
+            .as_ref()
             .ok_or("Token2 wallet is not created")?;
 
-        // Move tokens from deposit
-        token1_wallet.deposit = u128::from(token1_wallet.deposit)
-            .checked_sub(amounts[0].into())
-            .ok_or("Not enough deposit for Token1")?
-            .into();
-        token2_wallet.deposit = u128::from(token2_wallet.deposit)
-            .checked_sub(amounts[1].into())
-            .ok_or("Not enough deposit for Token2")?
-            .into();
+        let is_empty = token1_wallet.liquidity.0 == 0 && token2_wallet.liquidity.0 == 0;
+        if let (true, Some(expected_price)) = (is_empty, expected_price) {
+            let expected_price = parse_price(&expected_price)?;
+            let price = compute_price(
+                amounts[0].into(),
+                token1_wallet.price_decimals(),
+                amounts[1].into(),
+                token2_wallet.price_decimals(),
+            )?;
 
-        // Move tokens to liquidity
-        token1_wallet.liquidity = u128::from(token1_wallet.liquidity)
+            let deviation = if price > expected_price {
+                price - expected_price
+            } else {
+                expected_price - price
+            };
+            if deviation * U256::from(10_000)
+                > expected_price * U256::from(EXPECTED_PRICE_TOLERANCE_BPS)
+            {
+                return Err("Initial price deviates from expected price");
+            }
+        }
+
+        // Non-empty pool accepts amounts only close to its reserves ratio, so additions don't move the price
+        if !is_empty && self.max_add_deviation_bps < MAX_BPS {
+            // Relative growth of each reserve `amount / reserve`, cross-multiplied by the other reserve
+            let token1_growth = U256::from(amounts[0].0) * U256::from(token2_wallet.liquidity.0);
+            let token2_growth = U256::from(amounts[1].0) * U256::from(token1_wallet.liquidity.0);
+            let max_growth = token1_growth.max(token2_growth);
+
+            if (max_growth - token1_growth.min(token2_growth)) * U256::from(MAX_BPS)
+                > max_growth * U256::from(self.max_add_deviation_bps)
+            {
+                return Err("Liquidity amounts deviate from the pool ratio");
+            }
+        }
+
+        // Tokens ratio of the resulting reserves must stay computable, otherwise every swap of the pool fails
+        let token1_liquidity = u128::from(token1_wallet.liquidity)
             .checked_add(amounts[0].into())
-            .ok_or("Liquidity overflow for Token1")?
-            .into();
-        token2_wallet.liquidity = u128::from(token2_wallet.liquidity)
+            .ok_or("Liquidity overflow for Token1")?;
+        let token2_liquidity = u128::from(token2_wallet.liquidity)
             .checked_add(amounts[1].into())
-            .ok_or("Liquidity overflow for Token2")?
-            .into();
+            .ok_or("Liquidity overflow for Token2")?;
+        compute_tokens_ratio(
+            token1_liquidity,
+            token1_wallet.metadata.decimals,
+            token2_liquidity,
+            token2_wallet.metadata.decimals,
+        )
+        .map_err(|_| "Liquidity exceeds tokens ratio bounds")?;
+
+        let shares = compute_shares_to_mint(
+            [token1_wallet.liquidity.0, token2_wallet.liquidity.0],
+            [amounts[0].0, amounts[1].0],
+            self.total_lp_shares,
+        )?;
+        let token_ids = [
+            token1_wallet.token_id.clone(),
+            token2_wallet.token_id.clone(),
+        ];
+
+        // Entry value in Token2 units at the pool price, which the amounts don't move
+        let entry_value = compute_entry_value(
+            [token1_liquidity, token2_liquidity],
+            [amounts[0].0, amounts[1].0],
+        )?;
+
+        // Move tokens from the provider's deposit & mint LP shares to the provider
+        let account_id = env::predecessor_account_id();
+        if self.is_owner(&account_id) {
+            let token1_wallet = self.get_token_wallet_mut(&token_ids[0])?;
+            token1_wallet.deposit = u128::from(token1_wallet.deposit)
+                .checked_sub(amounts[0].into())
+                .ok_or("Not enough deposit for Token1")?
+                .into();
+            let token2_wallet = self.get_token_wallet_mut(&token_ids[1])?;
+            token2_wallet.deposit = u128::from(token2_wallet.deposit)
+                .checked_sub(amounts[1].into())
+                .ok_or("Not enough deposit for Token2")?
+                .into();
+
+            self.owner_lp_shares = self
+                .owner_lp_shares
+                .checked_add(shares)
+                .ok_or("LP shares overflow")?;
+            self.owner_lp_entry_value = self.owner_lp_entry_value.saturating_add(entry_value);
+        } else {
+            self.update_account(&account_id, |account| {
+                account
+                    .withdraw(&token_ids[0], amounts[0].into())
+                    .map_err(|_| "Not enough deposit for Token1")?;
+                account
+                    .withdraw(&token_ids[1], amounts[1].into())
+                    .map_err(|_| "Not enough deposit for Token2")?;
+                account.lp_shares = account
+                    .lp_shares
+                    .checked_add(shares)
+                    .ok_or("LP shares overflow")?;
+                account.lp_entry_value = account.lp_entry_value.saturating_add(entry_value);
+
+                Ok(())
+            })?;
+
+            for (token_id, amount) in token_ids.iter().zip(amounts) {
+                let token_wallet = self.get_token_wallet_mut(token_id)?;
+                token_wallet.accounts_deposit = token_wallet
+                    .accounts_deposit
+                    .0
+                    .saturating_sub(amount.0)
+                    .into();
+            }
+        }
+
+        #[cfg(feature = "debug")]
+        let reserves_before = self.get_reserves()?;
+
+        // Move tokens to liquidity
+        self.get_token_wallet_mut(&token_ids[0])?.liquidity = token1_liquidity.into();
+        self.get_token_wallet_mut(&token_ids[1])?.liquidity = token2_liquidity.into();
+
+        #[cfg(feature = "debug")]
+        crate::misc::check_price_preserved(reserves_before, self.get_reserves()?);
+
+        self.total_lp_shares = self
+            .total_lp_shares
+            .checked_add(shares)
+            .ok_or("LP shares overflow")?;
+
+        self.activity.last_liquidity_ts = Some(now_ns().into());
 
         Ok(())
     }
 
-    /// Remove liquidity from the pool to owner's deposit by provided amounts
+    /// Adds the largest liquidity within `max_amounts` of the caller's deposit, which matches the pool ratio,
+    /// the rest stays in the deposit. Amounts of an empty pool set its ratio, so they are added in full
+    ///
+    /// Deposit is the same as of [Contract::add_liquidity], the owner's one or the account balances
+    ///
+    /// Returns added amounts
+    #[payable]
+    #[handle_result]
+    pub fn add_liquidity_balanced(
+        &mut self,
+        max_amounts: [U128; 2],
+    ) -> Result<[U128; 2], &'static str> {
+        assert_one_yocto();
+
+        let amounts =
+            compute_balanced_amounts(self.get_reserves()?, max_amounts.map(u128::from))?.map(U128);
+
+        self.add_liquidity(amounts, None)?;
+
+        Ok(amounts)
+    }
+
+    /// Remove liquidity from the pool to the caller's deposit by provided amounts
     #[payable]
     #[handle_result]
     pub fn remove_liquidity(&mut self, amounts: [U128; 2]) -> Result<(), &'static str> {
-        self.assert_owner();
+        assert_one_yocto();
+        self.check_contract_running()?;
 
-        let token1_wallet = self
-            .token1_wallet
-            .as_mut()
-            .ok_or("Token1 wallet is not created")?;
-        let token2_wallet = self
-            .token2_wallet
-            .as_mut()
-            .ok_or("Token2 wallet is not created")?;
+        let amounts = [amounts[0].0, amounts[1].0];
+        let shares = compute_shares_to_burn(self.get_reserves()?, amounts, self.total_lp_shares)?;
 
-        // Move tokens from liquidity
-        token1_wallet.liquidity = u128::from(token1_wallet.liquidity)
-            .checked_sub(amounts[0].into())
-            .ok_or("Not enough liquidity for Token1")?
-            .into();
-        token2_wallet.liquidity = u128::from(token2_wallet.liquidity)
-            .checked_sub(amounts[1].into())
-            .ok_or("Not enough liquidity for Token2")?
-            .into();
+        self.withdraw_liquidity(amounts, shares)
+    }
 
-        // Move tokens to deposit
-        token1_wallet.deposit = u128::from(token1_wallet.deposit)
-            .checked_add(amounts[0].into())
-            .ok_or("Deposit overflow for Token1")?
-            .into();
-        token2_wallet.deposit = u128::from(token2_wallet.deposit)
-            .checked_add(amounts[1].into())
-            .ok_or("Deposit overflow for Token2")?
-            .into();
+    /// Burns `shares` of the caller's LP shares and moves the pro-rata part of the liquidity to the caller's
+    /// deposit, withdrawn amounts are rounded down, so the dust stays with the remaining LP shares
+    #[payable]
+    #[handle_result]
+    pub fn remove_liquidity_by_shares(&mut self, shares: U128) -> Result<(), &'static str> {
+        assert_one_yocto();
+        self.check_contract_running()?;
 
-        Ok(())
+        let amounts =
+            compute_amounts_for_shares(self.get_reserves()?, shares.into(), self.total_lp_shares)?;
+
+        self.withdraw_liquidity(amounts, shares.into())
     }
 
+    /// Returns Token1 price in Token2, in the format of [Contract::get_marginal_price], at which the LP shares
+    /// of `account_id` are worth the value of the liquidity it provided at the pool price it was added at
+    ///
+    /// Without accrued fees it's the entry price, fees accrued to the LP shares lower it. LP shares owned before
+    /// the entry value was recorded aren't valued
     #[handle_result]
-    pub fn get_pool(&self) -> Result<PoolView, &'static str> {
+    pub fn get_lp_breakeven(&self, account_id: AccountId) -> Result<String, &'static str> {
+        let shares = self.get_lp_balance(account_id.clone()).0;
+        if shares == 0 {
+            return Err("Account has no LP shares");
+        }
+
+        let owner_lp_entry_value = if self.is_owner(&account_id) {
+            self.owner_lp_entry_value
+        } else {
+            0
+        };
+        let value = self
+            .get_account(&account_id)
+            .map(|account| account.lp_entry_value)
+            .unwrap_or_default()
+            .saturating_add(owner_lp_entry_value);
+        if value == 0 {
+            return Err("LP entry value is not recorded");
+        }
+
         let token1_wallet = self
             .token1_wallet
             .as_ref()
@@ -378,42 +1125,1505 @@ impl Contract {
             .token2_wallet
             .as_ref()
             .ok_or("Token2 wallet is not created")?;
+        let lp_reserves =
+            compute_amounts_for_shares(self.get_reserves()?, shares, self.total_lp_shares)?;
 
-        Ok(PoolView {
-            token_ids: [
-                token1_wallet.token_id.clone(),
-                token2_wallet.token_id.clone(),
-            ],
-            decimals: [
-                token1_wallet.metadata.decimals,
-                token2_wallet.metadata.decimals,
-            ],
-            amounts: [token1_wallet.liquidity, token2_wallet.liquidity],
-            ratio: compute_tokens_ratio(
-                token1_wallet.liquidity.into(),
-                token2_wallet.liquidity.into(),
-            )?
-            .to_string(),
-        })
+        compute_breakeven_price(
+            value,
+            lp_reserves,
+            token1_wallet.price_decimals(),
+            token2_wallet.price_decimals(),
+        )
+        .map(format_price)
     }
 
-    pub(crate) fn get_swap_tokens_wallets_mut(
-        &mut self,
-        token_id_in: &AccountId,
-    ) -> Result<(&mut TokenWallet, &mut TokenWallet, bool), &'static str> {
-        let token1_wallet = self
-            .token1_wallet
-            .as_mut()
-            .ok_or("Token1 wallet is not registered")?;
-        let token2_wallet = self
-            .token2_wallet
-            .as_mut()
-            .ok_or("Token2 wallet is not registered")?;
-
-        match token_id_in.cmp(&token1_wallet.token_id) {
-            Ordering::Equal => Ok((token1_wallet, token2_wallet, true)),
-            _ => Ok((token2_wallet, token1_wallet, false)),
+    /// Returns LP shares to burn by [Contract::remove_liquidity_by_shares] to receive at least `amount`
+    /// of `token_id`, along with the pro-rata amount of the other token
+    ///
+    /// Shares are rounded up, so the amount received covers `amount` despite the rounding down on removal
+    #[handle_result]
+    pub fn shares_for_output(
+        &self,
+        token_id: AccountId,
+        amount: U128,
+    ) -> Result<U128, &'static str> {
+        if amount.0 == 0 {
+            return Err("Amount must be positive");
+        }
+
+        let reserve = self.get_token_wallet(&token_id)?.liquidity.0;
+        if amount.0 > reserve {
+            return Err("Not enough liquidity");
+        }
+
+        // u128 * u128 always fits into U256, the result doesn't exceed total shares as `amount <= reserve`
+        let shares = ceil_div(
+            U256::from(amount.0) * U256::from(self.total_lp_shares),
+            U256::from(reserve),
+        )
+        .ok_or("Not enough liquidity")?;
+
+        Ok(shares.as_u128().into())
+    }
+
+    /// Owner's function to transfer `amount` of `token_id` deposit back to the owner
+    ///
+    /// Deposit is decreased upfront, so concurrent withdrawals can't exceed it, and restored if the transfer fails
+    #[payable]
+    #[handle_result]
+    pub fn owner_withdraw_deposit(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+    ) -> Result<Promise, &'static str> {
+        self.assert_owner_action();
+
+        if amount.0 == 0 {
+            return Err("Amount must be positive");
+        }
+
+        let owner_id = self.owner_id.clone();
+        let token_wallet = self.get_token_wallet_mut(&token_id)?;
+        token_wallet.deposit = u128::from(token_wallet.deposit)
+            .checked_sub(amount.into())
+            .ok_or("Not enough deposit")?
+            .into();
+
+        Ok(ext_ft_core::ext(token_id.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(owner_id, amount, token_wallet.transfer_memo.clone())
+            .then(Self::ext(env::current_account_id()).on_deposit_withdrawn(token_id, amount)))
+    }
+
+    /// Transfers `amount` of `token_id` from the caller's balance back to the caller
+    ///
+    /// Balance is decreased upfront, so concurrent withdrawals can't exceed it, and restored if the transfer fails
+    #[payable]
+    #[handle_result]
+    pub fn withdraw_deposit(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+    ) -> Result<Promise, &'static str> {
+        assert_one_yocto();
+
+        if amount.0 == 0 {
+            return Err("Amount must be positive");
+        }
+
+        self.transfer_account_deposit(env::predecessor_account_id(), token_id, amount)
+    }
+
+    /// Transfers the caller's whole balances of both pool tokens back to the caller
+    ///
+    /// Each token is transferred separately, so a failed transfer restores only its own balance
+    #[payable]
+    #[handle_result]
+    pub fn withdraw_all_internal(&mut self) -> Result<Promise, &'static str> {
+        assert_one_yocto();
+
+        let account_id = env::predecessor_account_id();
+        let account = self.get_account(&account_id)?;
+        let token_ids = [self.token1_wallet.as_ref(), self.token2_wallet.as_ref()]
+            .map(|token_wallet| token_wallet.map(|token_wallet| token_wallet.token_id.clone()));
+
+        let mut promise: Option<Promise> = None;
+        for token_id in token_ids.into_iter().flatten() {
+            let amount = account.balance(&token_id);
+            if amount == 0 {
+                continue;
+            }
+
+            let transfer =
+                self.transfer_account_deposit(account_id.clone(), token_id, amount.into())?;
+            promise = Some(match promise {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+        }
+
+        promise.ok_or("No balances to withdraw")
+    }
+
+    #[private]
+    pub fn on_account_deposit_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> bool {
+        if transfer_result.is_ok() {
+            env::log_str(&format!(
+                "{:?} withdrew {} `{}` token(s) from deposit",
+                account_id, amount.0, token_id
+            ));
+
+            return true;
+        }
+
+        // Transferred tokens are returned, so the balance is restored
+        match self.internal_deposit(&account_id, &token_id, amount.into()) {
+            Ok(_) => {
+                if let Ok(token_wallet) = self.get_token_wallet_mut(&token_id) {
+                    token_wallet.accounts_deposit = token_wallet
+                        .accounts_deposit
+                        .0
+                        .saturating_add(amount.0)
+                        .into();
+                }
+
+                env::log_str(&format!(
+                    "Withdrawal of {} `{}` token(s) by {:?} failed, balance is restored",
+                    amount.0, token_id, account_id
+                ));
+            }
+            Err(e) => env::log_str(&format!(
+                "Critical: withdrawal of {} `{}` token(s) by {:?} failed, balance isn't restored: {}",
+                amount.0, token_id, account_id, e
+            )),
+        }
+
+        false
+    }
+
+    #[private]
+    pub fn on_deposit_withdrawn(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> bool {
+        if transfer_result.is_ok() {
+            env::log_str(&format!(
+                "Owner withdrew {} `{}` token(s) from deposit",
+                amount.0, token_id
+            ));
+
+            return true;
+        }
+
+        // Transferred tokens are returned, so the deposit is restored
+        if let Ok(token_wallet) = self.get_token_wallet_mut(&token_id) {
+            token_wallet.deposit = token_wallet.deposit.0.saturating_add(amount.0).into();
+        }
+
+        env::log_str(&format!(
+            "Withdrawal of {} `{}` token(s) failed, deposit is restored",
+            amount.0, token_id
+        ));
+
+        false
+    }
+
+    /// Completes the swap retried after sponsoring the sender registration at the output token,
+    /// unlike [SwapProvider::on_swap_complete] the output transfer isn't retried again
+    #[private]
+    pub fn on_sponsored_swap_complete(
+        &mut self,
+        sender_id: AccountId,
+        token_id_in: AccountId,
+        token_id_out: AccountId,
+        amounts: SwapAmounts,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        match transfer_result {
+            Ok(_) => self.complete_swap(sender_id, token_id_in, token_id_out, amounts),
+            Err(_) => self.fail_swap(sender_id, token_id_in, token_id_out, amounts),
+        }
+    }
+
+    /// Returns $NEAR the pool attached to register `account_id` at pool tokens which isn't charged to
+    /// the account's storage balance yet
+    ///
+    /// Outstanding amount is repaid from the account's next storage deposit, charged to its available
+    /// storage balance on withdrawal & deducted from the refund when the account unregisters
+    pub fn get_sponsored_registration(&self, account_id: AccountId) -> U128 {
+        self.sponsored_registrations
+            .get(&account_id)
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Owner's function to transfer protocol fees accrued in `token_id` to the owner
+    ///
+    /// Accrued fees are reset only once the transfer succeeds
+    #[payable]
+    #[handle_result]
+    pub fn withdraw_protocol_fees(&mut self, token_id: AccountId) -> Result<Promise, &'static str> {
+        self.assert_owner_action();
+
+        let index = usize::from(!self.is_token1(&token_id)?);
+        let amount = U128(self.protocol_fees[index]);
+        if amount.0 == 0 {
+            return Err("No protocol fees to withdraw");
+        }
+
+        let memo = self.get_token_wallet(&token_id)?.transfer_memo.clone();
+
+        Ok(ext_ft_core::ext(token_id.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(self.owner_id.clone(), amount, memo)
+            .then(
+                Self::ext(env::current_account_id()).on_protocol_fees_withdrawn(token_id, amount),
+            ))
+    }
+
+    #[private]
+    pub fn on_protocol_fees_withdrawn(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] transfer_result: Result<(), PromiseError>,
+    ) -> bool {
+        if transfer_result.is_err() {
+            env::log_str(&format!(
+                "Withdrawal of {} `{}` protocol fees failed",
+                amount.0, token_id
+            ));
+
+            return false;
+        }
+
+        // Fees accrued while the transfer was in flight are kept
+        if let Ok(is_token1) = self.is_token1(&token_id) {
+            let protocol_fees = &mut self.protocol_fees[usize::from(!is_token1)];
+            *protocol_fees = protocol_fees.saturating_sub(amount.0);
+        }
+
+        env::log_str(&format!(
+            "Owner withdrew {} `{}` protocol fees",
+            amount.0, token_id
+        ));
+
+        true
+    }
+
+    /// Returns Token1 & Token2 protocol fees accrued on swaps and not withdrawn yet
+    pub fn get_protocol_fees(&self) -> [U128; 2] {
+        self.protocol_fees.map(U128)
+    }
+
+    /// Moves `amounts` of liquidity to the caller's deposit and burns `shares` of the caller's LP shares,
+    /// the owner's deposit is the wallet one, any other account's are its balances
+    fn withdraw_liquidity(&mut self, amounts: [u128; 2], shares: u128) -> Result<(), &'static str> {
+        self.update_price_cumulative();
+
+        let reserves_before = self.get_reserves()?;
+
+        // Validate liquidity before moving any tokens
+        let token1_liquidity = reserves_before[0]
+            .checked_sub(amounts[0])
+            .ok_or("Not enough liquidity for Token1")?;
+        let token2_liquidity = reserves_before[1]
+            .checked_sub(amounts[1])
+            .ok_or("Not enough liquidity for Token2")?;
+
+        let token_ids = [
+            self.token1_wallet
+                .as_ref()
+                .ok_or("Token1 wallet is not created")?
+                .token_id
+                .clone(),
+            self.token2_wallet
+                .as_ref()
+                .ok_or("Token2 wallet is not created")?
+                .token_id
+                .clone(),
+        ];
+
+        // Move tokens to the provider's deposit & burn LP shares of the provider
+        let account_id = env::predecessor_account_id();
+        if self.is_owner(&account_id) {
+            let owner_lp_shares = self
+                .owner_lp_shares
+                .checked_sub(shares)
+                .ok_or("Not enough LP shares")?;
+            let token1_deposit = u128::from(self.get_token_wallet(&token_ids[0])?.deposit)
+                .checked_add(amounts[0])
+                .ok_or("Deposit overflow for Token1")?;
+            let token2_deposit = u128::from(self.get_token_wallet(&token_ids[1])?.deposit)
+                .checked_add(amounts[1])
+                .ok_or("Deposit overflow for Token2")?;
+
+            self.owner_lp_entry_value -=
+                compute_entry_value_burned(self.owner_lp_entry_value, shares, self.owner_lp_shares);
+            self.owner_lp_shares = owner_lp_shares;
+            self.get_token_wallet_mut(&token_ids[0])?.deposit = token1_deposit.into();
+            self.get_token_wallet_mut(&token_ids[1])?.deposit = token2_deposit.into();
+        } else {
+            self.update_account(&account_id, |account| {
+                let lp_shares = account
+                    .lp_shares
+                    .checked_sub(shares)
+                    .ok_or("Not enough LP shares")?;
+                account.lp_entry_value -=
+                    compute_entry_value_burned(account.lp_entry_value, shares, account.lp_shares);
+                account.lp_shares = lp_shares;
+                account.deposit(&token_ids[0], amounts[0])?;
+                account.deposit(&token_ids[1], amounts[1])
+            })?;
+
+            for (token_id, amount) in token_ids.iter().zip(amounts) {
+                let token_wallet = self.get_token_wallet_mut(token_id)?;
+                token_wallet.accounts_deposit = token_wallet
+                    .accounts_deposit
+                    .0
+                    .saturating_add(amount)
+                    .into();
+            }
+        }
+
+        self.get_token_wallet_mut(&token_ids[0])?.liquidity = token1_liquidity.into();
+        self.get_token_wallet_mut(&token_ids[1])?.liquidity = token2_liquidity.into();
+
+        #[cfg(feature = "debug")]
+        crate::misc::check_price_preserved(reserves_before, self.get_reserves()?);
+
+        self.total_lp_shares -= shares;
+
+        self.activity.last_liquidity_ts = Some(now_ns().into());
+
+        Ok(())
+    }
+
+    /// Swaps `swap_amount` of owner's `swap_token_in` deposit against the pool liquidity into the deposit
+    /// of the other token, then adds `add_amounts` of the deposits as liquidity
+    ///
+    /// Both steps are applied atomically, any failure reverts the whole call
+    #[payable]
+    #[handle_result]
+    pub fn rebalance_and_add(
+        &mut self,
+        swap_token_in: AccountId,
+        swap_amount: U128,
+        add_amounts: [U128; 2],
+    ) -> Result<(), &'static str> {
+        self.assert_owner_action();
+        self.check_contract_running()?;
+
+        self.update_price_cumulative();
+
+        // Swap leg is subject to the same quote & checks as a regular swap
+        let PreparedSwap { amounts, liquidity } = self.prepare_swap(
+            &env::predecessor_account_id(),
+            &swap_token_in,
+            swap_amount.into(),
+            None,
+            None,
+            None,
+        )?;
+        let SwapAmounts {
+            amount_in,
+            amount_out,
+            protocol_fee,
+            ..
+        } = amounts;
+
+        let swap_token_out = self
+            .get_swap_tokens_wallets(&swap_token_in)?
+            .1
+            .token_id
+            .clone();
+        let index = if self.is_token1(self.fee_token_id(&swap_token_in, &swap_token_out))? {
+            0
+        } else {
+            1
+        };
+        self.protocol_fees[index] = self.protocol_fees[index]
+            .checked_add(protocol_fee.0)
+            .ok_or("Protocol fee overflow")?;
+
+        let (token_wallet_in, token_wallet_out, _) =
+            self.get_swap_tokens_wallets_mut(&swap_token_in)?;
+
+        // Input left unused by the output cap stays in the deposit
+        token_wallet_in.deposit = u128::from(token_wallet_in.deposit)
+            .checked_sub(amount_in.0)
+            .ok_or("Not enough deposit for the swap")?
+            .into();
+        token_wallet_in.liquidity = liquidity[0].into();
+        token_wallet_out.liquidity = liquidity[1].into();
+        token_wallet_out.deposit = u128::from(token_wallet_out.deposit)
+            .checked_add(amount_out.0)
+            .ok_or("Token deposit overflow")?
+            .into();
+
+        env::log_str(&format!(
+            "Rebalance swap {} {} for {} {}",
+            amount_in.0,
+            token_wallet_in.metadata.symbol,
+            amount_out.0,
+            token_wallet_out.metadata.symbol
+        ));
+
+        self.add_liquidity(add_amounts, None)
+    }
+
+    /// Sets maximum deposit per account for the provided token, `None` removes the cap
+    #[payable]
+    #[handle_result]
+    pub fn set_max_deposit_per_account(
+        &mut self,
+        token_id: AccountId,
+        max_deposit: Option<U128>,
+    ) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        self.get_token_wallet_mut(&token_id)?
+            .max_deposit_per_account = max_deposit;
+
+        Ok(())
+    }
+
+    /// Sets memo attached to outgoing transfers of the provided token, `None` removes the memo
+    #[payable]
+    #[handle_result]
+    pub fn set_transfer_memo(
+        &mut self,
+        token_id: AccountId,
+        memo: Option<String>,
+    ) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        if let Some(memo) = memo.as_ref() {
+            validate_transfer_memo(memo)?;
+        }
+
+        self.get_token_wallet_mut(&token_id)?.transfer_memo = memo;
+
+        Ok(())
+    }
+
+    /// Overrides decimals of the provided token used by the price helpers, swap math keeps native units
+    #[payable]
+    #[handle_result]
+    pub fn override_decimals(
+        &mut self,
+        token_id: AccountId,
+        decimals: u8,
+    ) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        self.get_token_wallet_mut(&token_id)?.decimals_override = Some(decimals);
+
+        Ok(())
+    }
+
+    /// Sets swap fee in basis points
+    #[payable]
+    #[handle_result]
+    pub fn set_fee_bps(&mut self, fee_bps: u16) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        if u32::from(fee_bps) > MAX_BPS {
+            return Err("Fee exceeds 100%");
+        }
+
+        self.fee_bps = fee_bps;
+
+        Ok(())
+    }
+
+    /// Sets share of the swap fee taken by the protocol in basis points
+    #[payable]
+    #[handle_result]
+    pub fn set_protocol_fee_bps(&mut self, protocol_fee_bps: u16) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        if u32::from(protocol_fee_bps) > MAX_BPS {
+            return Err("Protocol fee exceeds 100%");
+        }
+
+        self.protocol_fee_bps = protocol_fee_bps;
+
+        Ok(())
+    }
+
+    /// Sets maximum swap input as a share of the input token liquidity in basis points, `None` removes the cap
+    #[payable]
+    #[handle_result]
+    pub fn set_max_swap_bps(&mut self, max_swap_bps: Option<u16>) -> Result<(), &'static str> {
+        self.assert_owner_action();
+
+        if max_swap_bps.is_some_and(|max_swap_bps| u32::from(max_swap_bps) > MAX_BPS) {
+            return Err("Max swap exceeds 100%");
+        }
+
+        self.max_swap_bps = max_swap_bps;
+
+        Ok(())
+    }
+
+    #[handle_result]
+    pub fn get_pool(&self) -> Result<PoolView, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok(PoolView {
+            token_ids: [
+                token1_wallet.token_id.clone(),
+                token2_wallet.token_id.clone(),
+            ],
+            decimals: [
+                token1_wallet.metadata.decimals,
+                token2_wallet.metadata.decimals,
+            ],
+            amounts: [token1_wallet.liquidity, token2_wallet.liquidity],
+            ratio: compute_tokens_ratio(
+                token1_wallet.liquidity.into(),
+                token1_wallet.metadata.decimals,
+                token2_wallet.liquidity.into(),
+                token2_wallet.metadata.decimals,
+            )?,
+            fee_bps: self.fee_bps,
+        })
+    }
+
+    /// Returns Token1 & Token2 owner's deposits, which are held by the pool but not provided as liquidity
+    #[handle_result]
+    pub fn get_deposits(&self) -> Result<[U128; 2], &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok([token1_wallet.deposit, token2_wallet.deposit])
+    }
+
+    /// Returns reserves & total LP shares as of the same block, as of [Contract::get_pool]
+    /// & [Contract::get_lp_total_supply]
+    #[handle_result]
+    pub fn get_lp_state(&self) -> Result<LpState, &'static str> {
+        Ok(LpState {
+            reserves: self.get_reserves()?.map(U128),
+            total_shares: self.total_lp_shares.into(),
+        })
+    }
+
+    /// Returns pool data together with data of `account_id`, as of [Contract::get_pool],
+    /// [Contract::get_lp_balance] & [Contract::get_account_deposits]
+    #[handle_result]
+    pub fn get_dashboard(&self, account_id: AccountId) -> Result<Dashboard, &'static str> {
+        let account = self
+            .get_account(&account_id)
+            .ok()
+            .map(|account| AccountView {
+                storage_balance: account.storage_balance(&account_id),
+                last_swap: self.get_last_swap(account_id.clone()),
+                swap_count: self.get_swap_count(account_id.clone()),
+            });
+
+        Ok(Dashboard {
+            pool: self.get_pool()?,
+            account,
+            user_shares: self.get_lp_balance(account_id.clone()),
+            user_deposits: self.get_account_deposits(account_id),
+        })
+    }
+
+    /// Returns Token1 & Token2 balances held by the pool for `account_id`, zeros for unregistered accounts
+    pub fn get_account_deposits(&self, account_id: AccountId) -> [U128; 2] {
+        let account = self.get_account(&account_id).ok();
+
+        [&self.token1_wallet, &self.token2_wallet].map(|token_wallet| {
+            match (&account, token_wallet) {
+                (Some(account), Some(token_wallet)) => {
+                    account.balance(&token_wallet.token_id).into()
+                }
+                _ => 0.into(),
+            }
+        })
+    }
+
+    /// Returns percentage with 2 decimal places, by which the pool fraction of an existing LP share
+    /// would decrease if `amounts` were added as liquidity
+    ///
+    /// Amounts too small to mint any LP shares, as well as a pool without LP shares, don't dilute anyone
+    pub fn estimate_dilution(&self, amounts: [U128; 2]) -> String {
+        let shares = self
+            .get_reserves()
+            .and_then(|reserves| {
+                compute_shares_to_mint(reserves, amounts.map(u128::from), self.total_lp_shares)
+            })
+            .unwrap_or_default();
+
+        if self.total_lp_shares == 0 || shares == 0 {
+            return "0.00".to_string();
+        }
+
+        // Pool fraction of a share goes from 1 / total to 1 / (total + shares), dilution never exceeds 10_000 bps
+        let shares = U256::from(shares);
+        let dilution_bps =
+            (shares * U256::from(MAX_BPS) / (U256::from(self.total_lp_shares) + shares)).as_u32();
+
+        format!("{}.{:02}", dilution_bps / 100, dilution_bps % 100)
+    }
+
+    /// Returns swap output amount for `amount_in` of `token_id_in` against current pool liquidity
+    #[handle_result]
+    pub fn get_amount_out(
+        &self,
+        token_id_in: AccountId,
+        amount_in: U128,
+    ) -> Result<U128, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        self.get_amount_out_at(
+            token_id_in,
+            amount_in,
+            [token1_wallet.liquidity, token2_wallet.liquidity],
+        )
+    }
+
+    /// Returns swap output amount for `amount_in` of `token_id_in` less the caller-provided gas cost
+    /// expressed in the output token, zero if the swap isn't worth the gas
+    #[handle_result]
+    pub fn get_amount_out_net(
+        &self,
+        token_id_in: AccountId,
+        amount_in: U128,
+        gas_cost_in_out_token: U128,
+    ) -> Result<U128, &'static str> {
+        let amount_out = self.get_amount_out(token_id_in, amount_in)?;
+
+        Ok(amount_out.0.saturating_sub(gas_cost_in_out_token.0).into())
+    }
+
+    /// Returns swap output amount for `amount_in` of `token_id_in`, exactly as the swap would execute now,
+    /// including fee & output cap by the reserve buffer
+    #[handle_result]
+    pub fn get_swap_estimate(
+        &self,
+        token_id_in: AccountId,
+        amount_in: U128,
+    ) -> Result<U128, &'static str> {
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(&token_id_in)?;
+
+        token_wallet_in
+            .quote_swap(
+                token_wallet_out,
+                amount_in.into(),
+                self.fee_bps,
+                self.fee_mode,
+            )
+            .map(|quote| quote.amount_out.into())
+    }
+
+    /// Returns swap output amount for `amount_in` of `token_id_in` against hypothetical Token1 & Token2 `reserves`
+    #[handle_result]
+    pub fn get_amount_out_at(
+        &self,
+        token_id_in: AccountId,
+        amount_in: U128,
+        reserves: [U128; 2],
+    ) -> Result<U128, &'static str> {
+        let (reserve_in, reserve_out) = match self.is_token1(&token_id_in)? {
+            true => (reserves[0], reserves[1]),
+            false => (reserves[1], reserves[0]),
+        };
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(&token_id_in)?;
+        let rounding_buffer = token_wallet_in.rounding_buffer;
+
+        compute_amount_out(
+            reserve_in.into(),
+            token_wallet_in.metadata.decimals,
+            reserve_out.into(),
+            token_wallet_out.metadata.decimals,
+            deduct_fee(
+                amount_in.0.saturating_sub(rounding_buffer.0),
+                self.fee_mode.input_fee_bps(self.fee_bps),
+            )?,
+        )
+        .and_then(|amount_out| deduct_fee(amount_out, self.fee_mode.output_fee_bps(self.fee_bps)))
+        .map(U128)
+    }
+
+    /// Returns round-trip cost in basis points of buying and selling `notional` of Token1 against current
+    /// pool liquidity, i.e. the bid-ask spread, which captures both fee and slippage
+    #[handle_result]
+    pub fn get_spread_bps(&self, notional: U128) -> Result<u16, &'static str> {
+        if notional.0 == 0 {
+            return Err("Notional must be positive");
+        }
+
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        // Token2 paid to buy `notional` of Token1, including fee & rounding buffer
+        let amount_paid = add_fee(notional.into(), self.fee_mode.output_fee_bps(self.fee_bps))
+            .and_then(|amount_out| {
+                compute_amount_in(
+                    token2_wallet.liquidity.into(),
+                    token2_wallet.metadata.decimals,
+                    token1_wallet.liquidity.into(),
+                    token1_wallet.metadata.decimals,
+                    amount_out,
+                )
+            })
+            .and_then(|amount_in| add_fee(amount_in, self.fee_mode.input_fee_bps(self.fee_bps)))?
+            .checked_add(token2_wallet.rounding_buffer.into())
+            .ok_or("Computation overflow")?;
+        // Token2 received selling `notional` of Token1
+        let amount_received = self.get_amount_out(token1_wallet.token_id.clone(), notional)?;
+
+        // Proceeds never exceed the cost, so spread never exceeds MAX_BPS
+        let spread_bps = U256::from(amount_paid.saturating_sub(amount_received.0))
+            * U256::from(MAX_BPS)
+            / U256::from(amount_paid.max(1));
+
+        Ok(spread_bps.as_u32() as u16)
+    }
+
+    /// Returns instantaneous price of `token_in` in units of the other pool token, i.e. decimals
+    /// adjusted `reserve_out / reserve_in`, unlike the execution price of [Contract::get_amount_out]
+    #[handle_result]
+    pub fn get_marginal_price(&self, token_in: AccountId) -> Result<String, &'static str> {
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(&token_in)?;
+
+        compute_price(
+            token_wallet_in.liquidity.into(),
+            token_wallet_in.price_decimals(),
+            token_wallet_out.liquidity.into(),
+            token_wallet_out.price_decimals(),
+        )
+        .map(format_price)
+    }
+
+    /// Returns instantaneous price of one `token_id` in units of the other pool token, scaled by
+    /// `10^decimals` of `token_id`, e.g. `1000000` for a balanced pool of 6 decimals stablecoins
+    #[handle_result]
+    pub fn get_spot_price(&self, token_id: AccountId) -> Result<U128, &'static str> {
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(&token_id)?;
+        if token_wallet_in.liquidity.0 == 0 || token_wallet_out.liquidity.0 == 0 {
+            return Err("Pool is empty");
+        }
+
+        // price = reserve_out * 10^decimals_in / (reserve_in * 10^decimals_out), scaled by 10^decimals_in
+        let ten = U256::from(10);
+        let numerator = ten
+            .checked_pow(U256::from(token_wallet_in.price_decimals()) * 2)
+            .and_then(|scale| U256::from(token_wallet_out.liquidity.0).checked_mul(scale))
+            .ok_or("Computation overflow")?;
+        let denominator = ten
+            .checked_pow(token_wallet_out.price_decimals().into())
+            .and_then(|scale| U256::from(token_wallet_in.liquidity.0).checked_mul(scale))
+            .ok_or("Computation overflow")?;
+
+        u128::try_from(numerator / denominator)
+            .map(U128)
+            .map_err(|_| "Computation overflow")
+    }
+
+    /// Returns amount of `token_in` to swap, so its marginal price drops to `target_price`,
+    /// in the format of [Contract::get_marginal_price]
+    #[handle_result]
+    pub fn amount_to_price(
+        &self,
+        token_in: AccountId,
+        target_price: String,
+    ) -> Result<U128, &'static str> {
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(&token_in)?;
+
+        compute_amount_to_price(
+            token_wallet_in.liquidity.into(),
+            token_wallet_in.price_decimals(),
+            token_wallet_out.liquidity.into(),
+            token_wallet_out.price_decimals(),
+            parse_price(&target_price)?,
+        )
+        .and_then(|amount_in| add_fee(amount_in, self.fee_mode.input_fee_bps(self.fee_bps)))
+        .map(U128)
+    }
+
+    /// Returns swap moving the pool to `reference_price` of Token1 in units of Token2, in the format
+    /// of [Contract::get_marginal_price], along with its profit if the output is sold at the reference price
+    #[handle_result]
+    pub fn arbitrage_opportunity(&self, reference_price: String) -> Result<ArbInfo, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        let reference_price = parse_price(&reference_price)?;
+        let pool_price = compute_price(
+            token1_wallet.liquidity.into(),
+            token1_wallet.price_decimals(),
+            token2_wallet.liquidity.into(),
+            token2_wallet.price_decimals(),
+        )?;
+
+        // Token overpriced by the pool is sold to it until its price drops to the reference one
+        let (token_wallet_in, token_wallet_out, target_price) =
+            match reference_price.cmp(&pool_price) {
+                Ordering::Less => (token1_wallet, token2_wallet, reference_price),
+                Ordering::Greater => (token2_wallet, token1_wallet, invert_price(reference_price)?),
+                Ordering::Equal => return Err("Pool is at the reference price"),
+            };
+
+        let amount_in = compute_amount_to_price(
+            token_wallet_in.liquidity.into(),
+            token_wallet_in.price_decimals(),
+            token_wallet_out.liquidity.into(),
+            token_wallet_out.price_decimals(),
+            target_price,
+        )
+        .and_then(|amount_in| add_fee(amount_in, self.fee_mode.input_fee_bps(self.fee_bps)))?
+        .checked_add(token_wallet_in.rounding_buffer.into())
+        .ok_or("Computation overflow")?;
+        let amount_out = self.get_amount_out(token_wallet_in.token_id.clone(), amount_in.into())?;
+        let value_out = compute_value_in(
+            amount_out.into(),
+            token_wallet_in.price_decimals(),
+            token_wallet_out.price_decimals(),
+            target_price,
+        )?;
+
+        Ok(ArbInfo {
+            token_in: token_wallet_in.token_id.clone(),
+            token_out: token_wallet_out.token_id.clone(),
+            amount_in: amount_in.into(),
+            amount_out,
+            expected_profit: value_out.saturating_sub(amount_in).into(),
+        })
+    }
+
+    /// Returns percentage of each token holdings provided as liquidity, i.e. `liquidity / (liquidity + deposit)`
+    #[handle_result]
+    pub fn get_utilization(&self) -> Result<[String; 2], &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok([token1_wallet.utilization(), token2_wallet.utilization()])
+    }
+
+    /// Returns cached decimals of the provided pool token
+    #[handle_result]
+    pub fn get_decimals(&self, token_id: AccountId) -> Result<u8, &'static str> {
+        Ok(self.get_token_wallet(&token_id)?.metadata.decimals)
+    }
+
+    /// Returns last successful swap of provided `account_id`
+    ///
+    /// A contract swapping via `ft_transfer_call` may read its swap output here in the resolve callback,
+    /// provided it's registered with storage balance covering the record
+    pub fn get_last_swap(&self, account_id: AccountId) -> Option<LastSwap> {
+        self.last_swaps.get(&account_id)
+    }
+
+    /// Returns number of successful swaps made by provided `account_id`, counted while it's registered
+    /// with storage balance covering the count
+    pub fn get_swap_count(&self, account_id: AccountId) -> u64 {
+        self.swap_count.get(&account_id).unwrap_or_default()
+    }
+
+    /// Checks whether provided `account_id` is registered at the output token contract for swaps of `token_in`
+    ///
+    /// Resolves to `true` if the account can receive the swap output
+    #[handle_result]
+    pub fn check_output_registration(
+        &self,
+        account_id: AccountId,
+        token_in: AccountId,
+    ) -> Result<Promise, &'static str> {
+        let (_, token_wallet_out) = self.get_swap_tokens_wallets(&token_in)?;
+        let token_out = token_wallet_out.token_id.clone();
+
+        Ok(ext_storage_management::ext(token_out)
+            .storage_balance_of(account_id)
+            .then(Self::ext(env::current_account_id()).on_output_registration_checked()))
+    }
+
+    /// Estimates total $NEAR a new user attaches to register at both pool token contracts
+    #[handle_result]
+    pub fn estimate_registration_cost(&self) -> Result<Promise, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok(ext_storage_management::ext(token1_wallet.token_id.clone())
+            .storage_balance_bounds()
+            .and(
+                ext_storage_management::ext(token2_wallet.token_id.clone())
+                    .storage_balance_bounds(),
+            )
+            .then(Self::ext(env::current_account_id()).on_registration_cost_estimated()))
+    }
+
+    /// Queries balances of the contract at both token contracts and verifies they cover tracked
+    /// liquidity & deposits, swaps are allowed only once the pool backing is verified
+    ///
+    /// A failed check doesn't un-verify the pool, as balances are transiently below the tracked amounts
+    /// while a swap output transfer awaits its callback
+    #[handle_result]
+    pub fn verify_backing(&self) -> Result<Promise, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok(ext_ft_core::ext(token1_wallet.token_id.clone())
+            .ft_balance_of(env::current_account_id())
+            .and(
+                ext_ft_core::ext(token2_wallet.token_id.clone())
+                    .ft_balance_of(env::current_account_id()),
+            )
+            .then(Self::ext(env::current_account_id()).on_backing_verified()))
+    }
+
+    #[private]
+    pub fn on_backing_verified(
+        &mut self,
+        #[callback_result] token1_balance: Result<U128, PromiseError>,
+        #[callback_result] token2_balance: Result<U128, PromiseError>,
+    ) -> bool {
+        let is_backed = |token_wallet: Option<&TokenWallet>,
+                         balance: Result<U128, PromiseError>| {
+            match (token_wallet, balance) {
+                (Some(token_wallet), Ok(balance)) => token_wallet
+                    .liquidity
+                    .0
+                    .checked_add(token_wallet.deposit.0)
+                    .and_then(|tracked| tracked.checked_add(token_wallet.accounts_deposit.0))
+                    .is_some_and(|tracked| balance.0 >= tracked),
+                _ => false,
+            }
+        };
+
+        // Observed balances are the baseline of the following deposit verifications
+        let token_ids = [&self.token1_wallet, &self.token2_wallet]
+            .map(|token_wallet| token_wallet.as_ref().map(|wallet| wallet.token_id.clone()));
+        for (token_id, balance) in token_ids.iter().zip([&token1_balance, &token2_balance]) {
+            if let (Some(token_id), Ok(balance)) = (token_id, balance) {
+                self.observe_balance(token_id, *balance);
+            }
+        }
+
+        let is_backed = is_backed(self.token1_wallet.as_ref(), token1_balance)
+            && is_backed(self.token2_wallet.as_ref(), token2_balance);
+
+        if is_backed {
+            self.backing_verified = true;
+        } else if self.backing_verified {
+            env::log_str("Pool backing check failed, the verified pool stays verified");
+        }
+
+        env::log_str(&format!("Pool backing is verified: {}", is_backed));
+
+        is_backed
+    }
+
+    /// Logs the part of the deposited `amount` of `token_id` the pool balance didn't increase by,
+    /// the deposit itself is accepted regardless
+    ///
+    /// The increase is measured from the balance observed by the previous verified deposit or backing check
+    #[private]
+    pub fn on_deposit_verified(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> U128 {
+        match balance.map(|balance| self.observe_balance(&token_id, balance)) {
+            Ok(Some(shortfall)) if shortfall > 0 => env::log_str(&format!(
+                "Deposit shortfall: {} `{}` of the deposited {} wasn't received",
+                shortfall, token_id, amount.0
+            )),
+            Ok(Some(_)) => {}
+            Ok(None) => env::log_str(&format!(
+                "Deposit of `{}` isn't verified, no previous balance is observed",
+                token_id
+            )),
+            Err(_) => env::log_str(&format!("Failed to verify deposit of `{}`", token_id)),
+        }
+
+        0.into()
+    }
+
+    /// Returns `true` if balances of the contract were verified to cover tracked liquidity & deposit
+    pub fn is_backing_verified(&self) -> bool {
+        self.backing_verified
+    }
+
+    #[private]
+    #[handle_result]
+    pub fn on_registration_cost_estimated(
+        &self,
+        #[callback_result] token1_bounds: Result<StorageBalanceBounds, PromiseError>,
+        #[callback_result] token2_bounds: Result<StorageBalanceBounds, PromiseError>,
+    ) -> Result<U128, &'static str> {
+        match (token1_bounds, token2_bounds) {
+            (Ok(token1_bounds), Ok(token2_bounds)) => token1_bounds
+                .min
+                .0
+                .checked_add(token2_bounds.min.0)
+                .map(U128)
+                .ok_or("Registration cost overflow"),
+            _ => Err("Failed to fetch storage balance bounds"),
+        }
+    }
+
+    #[private]
+    pub fn on_output_registration_checked(
+        &self,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+    ) -> bool {
+        matches!(storage_balance, Ok(Some(_)))
+    }
+
+    /// Increments swap count of `account_id`, which isn't kept if the account can't pay for its storage
+    pub(crate) fn increment_swap_count(&mut self, account_id: &AccountId) {
+        let swap_count = self.get_swap_count(account_id.clone()).saturating_add(1);
+
+        let storage_usage_before = env::storage_usage();
+        let prev_swap_count = self.swap_count.insert(account_id, &swap_count);
+        if self
+            .charge_account_storage(account_id, storage_usage_before)
+            .is_err()
+        {
+            match prev_swap_count {
+                Some(prev_swap_count) => self.swap_count.insert(account_id, &prev_swap_count),
+                None => self.swap_count.remove(account_id),
+            };
+        }
+    }
+
+    /// Returns `min_amount_out` raised to the output amount worth `min_output_usd` for swaps of `token_id_in`,
+    /// assuming a stable pair, where a whole output token is worth 1 USD
+    pub(crate) fn with_min_output_usd(
+        &self,
+        token_id_in: &AccountId,
+        min_amount_out: Option<U128>,
+        min_output_usd: Option<String>,
+    ) -> Result<Option<U128>, &'static str> {
+        let min_output_usd = match min_output_usd {
+            Some(min_output_usd) => {
+                parse_price(&min_output_usd).map_err(|_| "Invalid USD amount")?
+            }
+            None => return Ok(min_amount_out),
+        };
+
+        let (_, token_wallet_out) = self.get_swap_tokens_wallets(token_id_in)?;
+        let min_output_usd_amount = U256::from(10)
+            .checked_pow(token_wallet_out.price_decimals().into())
+            .and_then(|scale| min_output_usd.checked_mul(scale))
+            .and_then(|amount| ceil_div(amount, U256::from(10).pow(PRICE_DECIMALS.into())))
+            .and_then(|amount| u128::try_from(amount).ok())
+            .ok_or("Computation overflow")?;
+
+        Ok(Some(
+            min_amount_out
+                .map_or(0, u128::from)
+                .max(min_output_usd_amount)
+                .into(),
+        ))
+    }
+
+    /// Returns Token1 & Token2 liquidity
+    pub(crate) fn get_reserves(&self) -> Result<[u128; 2], &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not created")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not created")?;
+
+        Ok([token1_wallet.liquidity.0, token2_wallet.liquidity.0])
+    }
+
+    /// Returns token of the swap of `token_id_in` for `token_id_out` its fee is deducted from
+    pub(crate) fn fee_token_id<'a>(
+        &self,
+        token_id_in: &'a AccountId,
+        token_id_out: &'a AccountId,
+    ) -> &'a AccountId {
+        match self.fee_mode {
+            FeeMode::OnInput => token_id_in,
+            FeeMode::OnOutput => token_id_out,
+        }
+    }
+
+    /// Quotes a swap of `amount_in` of `token_id_in` & runs every check a swap is subject to,
+    /// returns its amounts & the resulting input & output liquidity without changing the state
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn prepare_swap(
+        &self,
+        sender_id: &AccountId,
+        token_id_in: &AccountId,
+        amount_in: u128,
+        min_amount_out: Option<u128>,
+        exact_amount_out: Option<u128>,
+        max_price_impact_bps: Option<u16>,
+    ) -> Result<PreparedSwap, &'static str> {
+        if !self.backing_verified {
+            return Err("Pool backing is not verified");
+        }
+
+        if self.blacklist.contains(sender_id) {
+            return Err("Account is blacklisted");
+        }
+
+        let (token_wallet_in, token_wallet_out) = self.get_swap_tokens_wallets(token_id_in)?;
+
+        if let Some(max_swap_bps) = self.max_swap_bps {
+            if U256::from(amount_in) * U256::from(MAX_BPS)
+                > U256::from(token_wallet_in.liquidity.0) * U256::from(max_swap_bps)
+            {
+                return Err("Swap input exceeds maximum share of liquidity");
+            }
+        }
+
+        let SwapQuote {
+            amount_in,
+            amount_in_priced,
+            amount_out,
+            amount_unused,
+            output_fee,
+        } = match exact_amount_out {
+            Some(amount_out) => token_wallet_in.quote_swap_exact_out(
+                token_wallet_out,
+                amount_in,
+                amount_out,
+                self.fee_bps,
+                self.fee_mode,
+            )?,
+            None => token_wallet_in.quote_swap(
+                token_wallet_out,
+                amount_in,
+                self.fee_bps,
+                self.fee_mode,
+            )?,
+        };
+
+        match min_amount_out {
+            // Explicit minimum output overrides the default slippage protection
+            Some(min_amount_out) if amount_out < min_amount_out => {
+                return Err("Swap output is below minimum");
+            }
+
+            // Output at the pre-swap marginal price, execution price may be below it only by the default slippage,
+            // the output fee isn't a slippage just as the input one
+            None if self.default_max_slippage_bps > 0 => {
+                let amount_out_at_marginal_price = U256::from(amount_in_priced)
+                    * U256::from(token_wallet_out.liquidity.0)
+                    / U256::from(token_wallet_in.liquidity.0).max(U256::one());
+
+                if U256::from(amount_out + output_fee) * U256::from(MAX_BPS)
+                    < amount_out_at_marginal_price
+                        * U256::from(MAX_BPS - self.default_max_slippage_bps)
+                {
+                    return Err("Swap slippage exceeds default maximum");
+                }
+            }
+
+            _ => (),
+        }
+
+        if amount_out < token_wallet_out.min_meaningful_output.0 {
+            return Err("Swap output is too small, try a larger trade");
+        }
+
+        // Protocol's share of the fee is rounded down in favor of the pool and kept out of liquidity
+        let fee = match self.fee_mode {
+            FeeMode::OnInput => amount_in
+                .saturating_sub(token_wallet_in.rounding_buffer.into())
+                .saturating_sub(amount_in_priced),
+            FeeMode::OnOutput => output_fee,
+        };
+        let protocol_fee = u128::try_from(
+            U256::from(fee) * U256::from(self.protocol_fee_bps) / U256::from(MAX_BPS),
+        )
+        .map_err(|_| "Computation overflow")?;
+        let (protocol_fee_in, protocol_fee_out) = match self.fee_mode {
+            FeeMode::OnInput => (protocol_fee, 0),
+            FeeMode::OnOutput => (0, protocol_fee),
+        };
+
+        let token_in_liquidity = u128::from(token_wallet_in.liquidity)
+            .checked_add(amount_in - protocol_fee_in)
+            .ok_or("Input token liquidity overflow")?;
+        let token_out_liquidity = u128::from(token_wallet_out.liquidity)
+            .checked_sub(amount_out + protocol_fee_out)
+            .ok_or("Output token liquidity overflow")?;
+
+        // Tokens ratio must never decrease, otherwise accumulated rounding slowly drains the pool
+        if !is_ratio_preserved(
+            [token_wallet_in.liquidity.0, token_wallet_out.liquidity.0],
+            [token_in_liquidity, token_out_liquidity],
+        ) {
+            return Err("Swap decreases tokens ratio");
+        }
+
+        if let Some(max_price_impact_bps) = max_price_impact_bps {
+            let price_before = compute_price(
+                token_wallet_in.liquidity.into(),
+                token_wallet_in.price_decimals(),
+                token_wallet_out.liquidity.into(),
+                token_wallet_out.price_decimals(),
+            )?;
+            let price_after = compute_price(
+                token_in_liquidity,
+                token_wallet_in.price_decimals(),
+                token_out_liquidity,
+                token_wallet_out.price_decimals(),
+            )?;
+
+            // Swap only lowers the input token price, which is bounded relative to the pre-swap price
+            if price_before.saturating_sub(price_after) * U256::from(MAX_BPS)
+                > price_before * U256::from(max_price_impact_bps)
+            {
+                return Err("Swap price impact exceeds maximum");
+            }
+        }
+
+        Ok(PreparedSwap {
+            amounts: SwapAmounts {
+                amount_in: amount_in.into(),
+                amount_out: amount_out.into(),
+                amount_unused: amount_unused.into(),
+                fee: fee.into(),
+                protocol_fee: protocol_fee.into(),
+            },
+            liquidity: [token_in_liquidity, token_out_liquidity],
+        })
+    }
+
+    /// Folds the part of accrued protocol fees proportional to the reserves into the liquidity once both fees
+    /// reach the auto-compound threshold, minting LP shares for it to the owner, who the fees belong to
+    ///
+    /// The rest stays accrued, so the price isn't moved
+    pub(crate) fn compound_protocol_fees(&mut self) {
+        let [fee1, fee2] = self.protocol_fees;
+        let [threshold1, threshold2] = self.auto_compound_threshold;
+        if fee1 == 0 || fee2 == 0 || fee1 < threshold1 || fee2 < threshold2 {
+            return;
+        }
+
+        // Shares are minted only to the existing ones, liquidity added before LP shares existed isn't claimed
+        let reserves = match self.get_reserves() {
+            Ok(reserves) if self.total_lp_shares > 0 && reserves[0] > 0 && reserves[1] > 0 => {
+                reserves
+            }
+            _ => return,
+        };
+
+        // u128 * u128 always fits into U256, proportional amount is below the other fee, so it fits into u128
+        let amounts = if U256::from(fee1) * U256::from(reserves[1])
+            <= U256::from(fee2) * U256::from(reserves[0])
+        {
+            [
+                fee1,
+                (U256::from(fee1) * U256::from(reserves[1]) / U256::from(reserves[0])).as_u128(),
+            ]
+        } else {
+            [
+                (U256::from(fee2) * U256::from(reserves[0]) / U256::from(reserves[1])).as_u128(),
+                fee2,
+            ]
+        };
+
+        // Fees too small to mint a share stay accrued
+        let Ok(shares) = compute_shares_to_mint(reserves, amounts, self.total_lp_shares) else {
+            return;
+        };
+
+        if let (Some(token1_wallet), Some(token2_wallet)) =
+            (self.token1_wallet.as_mut(), self.token2_wallet.as_mut())
+        {
+            // Reserves after the addition were checked by the shares computation not to overflow
+            token1_wallet.liquidity = (reserves[0] + amounts[0]).into();
+            token2_wallet.liquidity = (reserves[1] + amounts[1]).into();
+        }
+        self.protocol_fees[0] -= amounts[0];
+        self.protocol_fees[1] -= amounts[1];
+        self.owner_lp_shares += shares;
+        self.owner_lp_entry_value = self.owner_lp_entry_value.saturating_add(
+            compute_entry_value(
+                [reserves[0] + amounts[0], reserves[1] + amounts[1]],
+                amounts,
+            )
+            .unwrap_or_default(),
+        );
+        self.total_lp_shares += shares;
+
+        env::log_str(&format!(
+            "Protocol fees {} & {} compounded into liquidity for {} LP shares",
+            amounts[0], amounts[1], shares
+        ));
+    }
+
+    /// Debits `amount` of `token_id` from the balance of `account_id` and transfers it to the account,
+    /// the balance is restored if the transfer fails
+    pub(crate) fn transfer_account_deposit(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> Result<Promise, &'static str> {
+        self.internal_withdraw(&account_id, &token_id, amount.into())?;
+
+        let token_wallet = self.get_token_wallet_mut(&token_id)?;
+        token_wallet.accounts_deposit = token_wallet
+            .accounts_deposit
+            .0
+            .saturating_sub(amount.0)
+            .into();
+
+        Ok(ext_ft_core::ext(token_id.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(
+                account_id.clone(),
+                amount,
+                token_wallet.transfer_memo.clone(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .on_account_deposit_withdrawn(account_id, token_id, amount),
+            ))
+    }
+
+    /// Returns input & output token wallets for swaps of `token_id_in`
+    pub(crate) fn get_swap_tokens_wallets(
+        &self,
+        token_id_in: &AccountId,
+    ) -> Result<(&TokenWallet, &TokenWallet), &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not registered")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not registered")?;
+
+        if token_id_in == &token1_wallet.token_id {
+            return Ok((token1_wallet, token2_wallet));
+        }
+
+        if token_id_in == &token2_wallet.token_id {
+            return Ok((token2_wallet, token1_wallet));
+        }
+
+        Err("Token is not supported")
+    }
+
+    pub(crate) fn get_swap_tokens_wallets_mut(
+        &mut self,
+        token_id_in: &AccountId,
+    ) -> Result<(&mut TokenWallet, &mut TokenWallet, bool), &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_mut()
+            .ok_or("Token1 wallet is not registered")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_mut()
+            .ok_or("Token2 wallet is not registered")?;
+
+        if token_id_in == &token1_wallet.token_id {
+            return Ok((token1_wallet, token2_wallet, true));
+        }
+
+        if token_id_in == &token2_wallet.token_id {
+            return Ok((token2_wallet, token1_wallet, false));
+        }
+
+        Err("Token is not supported")
+    }
+
+    /// Checks if both reserves exceed their configured floors for swaps
+    pub(crate) fn assert_min_liquidity_for_swaps(&self) -> Result<(), &'static str> {
+        let token_wallets = [self.token1_wallet.as_ref(), self.token2_wallet.as_ref()];
+
+        for (token_wallet, min_liquidity) in token_wallets.iter().zip(self.min_liquidity_for_swaps)
+        {
+            if let (Some(token_wallet), Some(min_liquidity)) = (token_wallet, min_liquidity) {
+                if token_wallet.liquidity.0 <= min_liquidity.0 {
+                    return Err("Pool liquidity is below minimum for swaps");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if provided `token_id` is Token1 and `false` if it is Token2
+    pub(crate) fn is_token1(&self, token_id: &AccountId) -> Result<bool, &'static str> {
+        // ensure token is supported by the pool
+        let _ = self.get_token_wallet(token_id)?;
+
+        Ok(self
+            .token1_wallet
+            .as_ref()
+            .is_some_and(|token1_wallet| &token1_wallet.token_id == token_id))
+    }
+
+    pub(crate) fn get_token_wallet(
+        &self,
+        token_id: &AccountId,
+    ) -> Result<&TokenWallet, &'static str> {
+        let token1_wallet = self
+            .token1_wallet
+            .as_ref()
+            .ok_or("Token1 wallet is not registered")?;
+        let token2_wallet = self
+            .token2_wallet
+            .as_ref()
+            .ok_or("Token2 wallet is not registered")?;
+
+        if token_id == &token1_wallet.token_id {
+            return Ok(token1_wallet);
+        }
+
+        if token_id == &token2_wallet.token_id {
+            return Ok(token2_wallet);
         }
+
+        Err("Token is not supported")
     }
 
     pub(crate) fn get_token_wallet_mut(
@@ -439,6 +2649,44 @@ impl Contract {
 
         Err("Token is not supported")
     }
+
+    /// Returns liquidity, deposits & protocol fees of `token_id` tracked by the pool
+    fn tracked_balance(&self, token_id: &AccountId) -> Option<u128> {
+        let token_wallet = self.get_token_wallet(token_id).ok()?;
+        let protocol_fee = self.protocol_fees[if self.is_token1(token_id).ok()? { 0 } else { 1 }];
+
+        token_wallet
+            .liquidity
+            .0
+            .checked_add(token_wallet.deposit.0)?
+            .checked_add(token_wallet.accounts_deposit.0)?
+            .checked_add(protocol_fee)
+    }
+
+    /// Records observed `balance` of the pool at `token_id`, returns its shortfall from the balance expected
+    /// by the previous observation, if any
+    ///
+    /// Expected balance moves with the tracked amount, so amounts held by the pool untracked, like escrowed
+    /// swap inputs, don't hide the shortfall
+    fn observe_balance(&mut self, token_id: &AccountId, balance: U128) -> Option<u128> {
+        let tracked = self.tracked_balance(token_id)?;
+        let previous = self
+            .get_token_wallet_mut(token_id)
+            .ok()?
+            .balance_observation
+            .replace(BalanceObservation {
+                balance,
+                tracked: tracked.into(),
+            })?;
+
+        let expected_balance = previous
+            .balance
+            .0
+            .checked_add(tracked)?
+            .checked_sub(previous.tracked.0)?;
+
+        Some(expected_balance.saturating_sub(balance.0))
+    }
 }
 
 impl TokenWallet {
@@ -448,18 +2696,210 @@ impl TokenWallet {
             metadata,
             deposit: U128(0),
             liquidity: U128(0),
+            accounts_deposit: U128(0),
+            max_deposit_per_account: None,
+            transfer_memo: None,
+            reserve_buffer: U128(DEFAULT_RESERVE_BUFFER),
+            rounding_buffer: U128(0),
+            min_meaningful_output: U128(0),
+            decimals_override: None,
+            verify_deposits: false,
+            balance_observation: None,
+        }
+    }
+
+    /// Returns decimals the token prices are expressed in, the override if set or the metadata ones
+    pub(crate) fn price_decimals(&self) -> u8 {
+        self.decimals_override.unwrap_or(self.metadata.decimals)
+    }
+
+    /// Quotes swap of `amount_in` of this token for the token of `token_wallet_out` against current liquidity,
+    /// the fee is deducted from the input or the output according to `fee_mode`
+    pub(crate) fn quote_swap(
+        &self,
+        token_wallet_out: &TokenWallet,
+        amount_in: u128,
+        fee_bps: u16,
+        fee_mode: FeeMode,
+    ) -> Result<SwapQuote, &'static str> {
+        if self.liquidity.0 == 0 || token_wallet_out.liquidity.0 == 0 {
+            return Err("Pool is empty");
+        }
+
+        // Rounding buffer and input fee are retained by the pool, but not priced
+        let rounding_buffer = u128::from(self.rounding_buffer);
+        let input_fee_bps = fee_mode.input_fee_bps(fee_bps);
+
+        let mut quote = SwapQuote {
+            amount_in,
+            amount_in_priced: deduct_fee(amount_in.saturating_sub(rounding_buffer), input_fee_bps)?,
+            amount_out: 0,
+            amount_unused: 0,
+            output_fee: 0,
+        };
+
+        // Priced input must fit into the input reserve, otherwise the whole input is refunded
+        if u128::from(self.liquidity)
+            .checked_add(quote.amount_in_priced)
+            .is_none()
+        {
+            return Err("Swap input is too large");
+        }
+        quote.amount_out = compute_amount_out(
+            self.liquidity.into(),
+            self.metadata.decimals,
+            token_wallet_out.liquidity.into(),
+            token_wallet_out.metadata.decimals,
+            quote.amount_in_priced,
+        )?;
+
+        // Never drain output reserve below its buffer, cap output and keep unused input for refund
+        let max_amount_out = u128::from(token_wallet_out.liquidity)
+            .saturating_sub(token_wallet_out.reserve_buffer.into());
+
+        if quote.amount_out >= max_amount_out {
+            let amount_in_used = compute_amount_in(
+                self.liquidity.into(),
+                self.metadata.decimals,
+                token_wallet_out.liquidity.into(),
+                token_wallet_out.metadata.decimals,
+                max_amount_out,
+            )
+            .and_then(|amount_in| add_fee(amount_in, input_fee_bps))?
+            .saturating_add(rounding_buffer)
+            .min(amount_in);
+
+            quote = SwapQuote {
+                amount_in: amount_in_used,
+                amount_in_priced: deduct_fee(
+                    amount_in_used.saturating_sub(rounding_buffer),
+                    input_fee_bps,
+                )?,
+                amount_out: max_amount_out,
+                amount_unused: amount_in - amount_in_used,
+                output_fee: 0,
+            };
+        }
+
+        // Output fee is taken from the priced output, so the output buffer is kept as well
+        let amount_out = deduct_fee(quote.amount_out, fee_mode.output_fee_bps(fee_bps))?;
+        quote.output_fee = quote.amount_out - amount_out;
+        quote.amount_out = amount_out;
+
+        Ok(quote)
+    }
+
+    /// Quotes swap of this token for exact `amount_out` of the token of `token_wallet_out`, the required
+    /// input is rounded up and the rest of `amount_in` is left unused
+    ///
+    /// In [FeeMode::OnOutput] the output fee is priced on top of `amount_out`
+    pub(crate) fn quote_swap_exact_out(
+        &self,
+        token_wallet_out: &TokenWallet,
+        amount_in: u128,
+        amount_out: u128,
+        fee_bps: u16,
+        fee_mode: FeeMode,
+    ) -> Result<SwapQuote, &'static str> {
+        if self.liquidity.0 == 0 || token_wallet_out.liquidity.0 == 0 {
+            return Err("Pool is empty");
+        }
+
+        let input_fee_bps = fee_mode.input_fee_bps(fee_bps);
+        let amount_out_priced = add_fee(amount_out, fee_mode.output_fee_bps(fee_bps))?;
+        let max_amount_out = u128::from(token_wallet_out.liquidity)
+            .saturating_sub(token_wallet_out.reserve_buffer.into());
+        if amount_out_priced > max_amount_out {
+            return Err("Swap output exceeds available liquidity");
+        }
+
+        let amount_in_priced = compute_amount_in(
+            self.liquidity.into(),
+            self.metadata.decimals,
+            token_wallet_out.liquidity.into(),
+            token_wallet_out.metadata.decimals,
+            amount_out_priced,
+        )
+        .and_then(|amount_in| add_fee(amount_in, input_fee_bps))?;
+        let amount_in_used = amount_in_priced
+            .checked_add(self.rounding_buffer.into())
+            .ok_or("Computation overflow")?;
+
+        if amount_in_used > amount_in {
+            return Err("Not enough input for the exact output");
+        }
+
+        Ok(SwapQuote {
+            amount_in: amount_in_used,
+            amount_in_priced: deduct_fee(amount_in_priced, input_fee_bps)?,
+            amount_out,
+            amount_unused: amount_in - amount_in_used,
+            output_fee: amount_out_priced - amount_out,
+        })
+    }
+
+    /// Returns percentage of holdings provided as liquidity with 2 decimal places
+    pub(crate) fn utilization(&self) -> String {
+        let liquidity = U256::from(self.liquidity.0);
+        let total = liquidity + U256::from(self.deposit.0);
+
+        if total.is_zero() {
+            return "0.00".to_string();
         }
+
+        // utilization in basis points never exceeds 10_000
+        let utilization_bps = (liquidity * U256::from(10_000) / total).as_u32();
+
+        format!("{}.{:02}", utilization_bps / 100, utilization_bps % 100)
+    }
+}
+
+pub(crate) fn validate_transfer_memo(memo: &str) -> Result<(), &'static str> {
+    if memo.len() > MAX_TRANSFER_MEMO_LENGTH {
+        return Err("Transfer memo is too long");
     }
+
+    Ok(())
 }
 
+/// Command passed in `msg` of `ft_transfer_call`, empty `msg` deposits the tokens
 #[derive(Deserialize, Serialize)]
-#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+#[serde(
+    crate = "near_sdk::serde",
+    rename_all = "snake_case",
+    deny_unknown_fields
+)]
 pub struct TransferCommand {
     pub r#type: TransferType,
+    /// Minimum swap output, the transfer is refunded if the output is below it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_amount_out: Option<U128>,
+    /// Exact swap output of [TransferType::SwapExactOut]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_out: Option<U128>,
+    /// Minimum swap output value in USD as a decimal string, e.g. `"99.5"`, the transfer is refunded
+    /// if the output is worth less
+    ///
+    /// Meant for stable pairs only, as a whole output token, i.e. `10^decimals` of its units, is assumed
+    /// to be worth exactly 1 USD
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_output_usd: Option<String>,
+    /// Maximum drop of the input token price caused by the swap in basis points, the transfer is refunded
+    /// if the post-swap marginal price is lower than the pre-swap spot price by more
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_price_impact_bps: Option<u16>,
+    /// Block timestamp in nanoseconds, starting from which the transfer is refunded, so a swap delayed
+    /// in the network doesn't execute at a stale price
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ns: Option<U64>,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
 pub enum TransferType {
     Swap,
+    /// Swap for exact `amount_out`, unused input is refunded
+    SwapExactOut,
+    /// Escrow tokens for a swap executed later by [Contract::execute_swap]
+    CommitSwap,
 }